@@ -0,0 +1,287 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::blockdev;
+use crate::partition;
+
+/// The start of the disk holds the MBR, the primary GPT and boot loaders
+const HEAD_BYTES: u64 = 4 * 1024 * 1024;
+/// The end of the disk normally holds the backup GPT
+const TAIL_BYTES: u64 = 1024 * 1024;
+
+/// Describes a backup file: a single line of JSON followed by the saved
+/// regions of the device, back to back.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupManifest {
+    pub device: String,
+    pub size: u64,
+    pub serial: Option<String>,
+    /// UTC, e.g. "20261018T133501Z"
+    pub created: String,
+    pub regions: Vec<Region>,
+}
+
+/// A backup written by `backup_device`.
+#[derive(Debug)]
+pub struct Backup {
+    pub path: PathBuf,
+    /// What could not be saved, if anything
+    pub warning: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Region {
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Where backups go unless told otherwise.
+pub fn default_backup_dir() -> PathBuf {
+    let data_dir = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))
+        .or_else(|| std::env::var_os("LOCALAPPDATA").map(PathBuf::from))
+        .unwrap_or_else(|| PathBuf::from("."));
+    data_dir.join("ferrisflash").join("backups")
+}
+
+/// Save the partition tables (MBR, primary and backup GPT with their entry
+/// arrays) and the first few MiB of a device to a timestamped file in
+/// `backup_dir`. A backup GPT that the primary header puts outside the device
+/// is left out, with a warning.
+pub fn backup_device<P: AsRef<Path>>(device_path: P, backup_dir: &Path) -> io::Result<Backup> {
+    let mut device = File::open(&device_path)?;
+    let size = blockdev::device_size(&device)?;
+
+    let mut head = vec![0; HEAD_BYTES.min(size) as usize];
+    device.read_exact(&mut head)?;
+
+    let mut ranges = vec![0..head.len() as u64, size.saturating_sub(TAIL_BYTES)..size];
+    // a disk flashed with a smaller image has its backup GPT somewhere in the middle
+    let mut warning = None;
+    match partition::backup_gpt_range(&head).filter(|r| r.end <= size) {
+        Some(range) => ranges.push(range),
+        None if partition::has_gpt(&head) => {
+            warning = Some("its GPT header points past the end of the device, the backup GPT was not saved".to_string());
+        }
+        None => {}
+    }
+
+    let regions = merge(ranges);
+    let name = device_path
+        .as_ref()
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| "device".to_string());
+    let created = timestamp();
+    let manifest = BackupManifest {
+        device: device_path.as_ref().display().to_string(),
+        size,
+        serial: blockdev::device_identity(&device_path).ok().and_then(|id| id.serial),
+        created: created.clone(),
+        regions,
+    };
+
+    std::fs::create_dir_all(backup_dir)?;
+    let backup_path = backup_dir.join(format!("{}-{}.bak", name, created));
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        // the saved sectors can hold anything that was on the disk
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut backup = io::BufWriter::new(options.open(&backup_path)?);
+
+    serde_json::to_writer(&mut backup, &manifest).map_err(io::Error::other)?;
+    backup.write_all(b"\n")?;
+
+    let mut buffer = vec![0; 1024 * 1024];
+    for region in &manifest.regions {
+        device.seek(SeekFrom::Start(region.offset))?;
+        let mut remaining = region.length;
+        while remaining > 0 {
+            let len = remaining.min(buffer.len() as u64) as usize;
+            device.read_exact(&mut buffer[..len])?;
+            backup.write_all(&buffer[..len])?;
+            remaining -= len as u64;
+        }
+    }
+
+    backup.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    Ok(Backup { path: backup_path, warning })
+}
+
+/// Read the manifest at the start of a backup file, leaving the reader at
+/// the first saved region.
+pub fn read_manifest<R: Read>(reader: &mut BufReader<R>) -> io::Result<BackupManifest> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    serde_json::from_str(&line).map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("Not a ferrisflash backup: {}", e))
+    })
+}
+
+/// Write the regions saved in a backup back to `device_path`.
+pub fn restore_backup<P: AsRef<Path>, Q: AsRef<Path>>(backup_path: P, device_path: Q) -> io::Result<BackupManifest> {
+    let mut backup = BufReader::new(File::open(&backup_path)?);
+    let manifest = read_manifest(&mut backup)?;
+
+    let mut device = OpenOptions::new().write(true).open(&device_path)?;
+    let size = blockdev::device_size(&device)?;
+    if size != manifest.size {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+            "{} holds {} bytes but the backup was taken from a device of {} bytes",
+            device_path.as_ref().display(), size, manifest.size
+        )));
+    }
+
+    let mut buffer = vec![0; 1024 * 1024];
+    for region in &manifest.regions {
+        device.seek(SeekFrom::Start(region.offset))?;
+        let mut remaining = region.length;
+        while remaining > 0 {
+            let len = remaining.min(buffer.len() as u64) as usize;
+            backup.read_exact(&mut buffer[..len])?;
+            device.write_all(&buffer[..len])?;
+            remaining -= len as u64;
+        }
+    }
+
+    device.sync_all()?;
+    Ok(manifest)
+}
+
+/// Sort ranges and join the ones that overlap or touch.
+fn merge(mut ranges: Vec<Range<u64>>) -> Vec<Region> {
+    ranges.retain(|r| r.start < r.end);
+    ranges.sort_by_key(|r| r.start);
+
+    let mut merged: Vec<Range<u64>> = Vec::new();
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+
+    merged
+        .into_iter()
+        .map(|r| Region { offset: r.start, length: r.end - r.start })
+        .collect()
+}
+
+/// The current UTC time as "YYYYMMDDTHHMMSSZ".
+fn timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    format_timestamp(secs)
+}
+
+/// `secs` since the Unix epoch as "YYYYMMDDTHHMMSSZ".
+fn format_timestamp(secs: u64) -> String {
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // civil date from days since 1970-01-01, after Howard Hinnant's algorithm
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year, month, day,
+        secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Noise, TempFile};
+
+    fn regions(ranges: Vec<Range<u64>>) -> Vec<(u64, u64)> {
+        merge(ranges).into_iter().map(|r| (r.offset, r.length)).collect()
+    }
+
+    #[test]
+    fn merges_overlapping_and_touching_ranges() {
+        assert_eq!(regions(vec![100..200, 0..50, 150..300, 300..310]), vec![(0, 50), (100, 210)]);
+        assert_eq!(regions(vec![0..4096, 1024..2048]), vec![(0, 4096)]);
+        assert_eq!(regions(vec![10..20, 20..20, 40..40]), vec![(10, 10)]);
+        assert!(regions(vec![]).is_empty());
+    }
+
+    #[test]
+    fn formats_timestamps_in_utc() {
+        assert_eq!(format_timestamp(0), "19700101T000000Z");
+        assert_eq!(format_timestamp(951_782_400), "20000229T000000Z");
+        assert_eq!(format_timestamp(1_709_251_199), "20240229T235959Z");
+        assert_eq!(format_timestamp(1_792_330_501), "20261018T133501Z");
+        assert_eq!(format_timestamp(4_102_444_800), "21000101T000000Z");
+        assert_eq!(timestamp().len(), 16);
+    }
+
+    #[test]
+    fn restores_what_was_backed_up() {
+        let original = Noise(1).bytes(12 * 1024 * 1024);
+        let device = TempFile::new(&original);
+        let backup_dir = std::env::temp_dir().join(format!("ferrisflash-test-backups-{}", std::process::id()));
+
+        let backup = backup_device(&device.path, &backup_dir).unwrap();
+        assert!(backup.warning.is_none());
+        let backup_path = backup.path;
+        std::fs::write(&device.path, vec![0; original.len()]).unwrap();
+        let manifest = restore_backup(&backup_path, &device.path).unwrap();
+        let restored = std::fs::read(&device.path).unwrap();
+        std::fs::remove_dir_all(&backup_dir).unwrap();
+
+        assert_eq!(manifest.size, original.len() as u64);
+        let saved: Vec<_> = manifest.regions.iter().map(|r| (r.offset, r.length)).collect();
+        assert_eq!(saved, vec![(0, HEAD_BYTES), (original.len() as u64 - TAIL_BYTES, TAIL_BYTES)]);
+        let head = HEAD_BYTES as usize;
+        let tail = original.len() - TAIL_BYTES as usize;
+        assert!(restored[..head] == original[..head]);
+        assert!(restored[head..tail].iter().all(|&b| b == 0));
+        assert!(restored[tail..] == original[tail..]);
+
+        // a backup only goes back to a device of the same size
+        let smaller = TempFile::new(&original[..head]);
+        let backup_path = backup_device(&device.path, &backup_dir).unwrap().path;
+        let error = restore_backup(&backup_path, &smaller.path).unwrap_err();
+        std::fs::remove_dir_all(&backup_dir).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn a_backup_gpt_past_the_end_is_left_out() {
+        let mut contents = vec![0; 8 * 1024 * 1024];
+        let header = &mut contents[512..1024];
+        header[..8].copy_from_slice(b"EFI PART");
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[32..40].copy_from_slice(&u64::MAX.to_le_bytes()); // alternate LBA
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&128u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        let device = TempFile::new(&contents);
+        let backup_dir = std::env::temp_dir().join(format!("ferrisflash-test-gpt-backups-{}", std::process::id()));
+
+        let backup = backup_device(&device.path, &backup_dir);
+        std::fs::remove_dir_all(&backup_dir).unwrap();
+        let backup = backup.unwrap();
+        assert!(backup.warning.unwrap().contains("backup GPT was not saved"));
+    }
+}
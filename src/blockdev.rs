@@ -0,0 +1,767 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+
+// generic ioctl encoding as used on x86, arm and riscv
+#[cfg(target_os = "linux")]
+const fn ior(ty: u8, nr: u8, size: usize) -> libc::c_ulong {
+    (2 << 30) | ((size as libc::c_ulong) << 16) | ((ty as libc::c_ulong) << 8) | nr as libc::c_ulong
+}
+
+#[cfg(target_os = "linux")]
+const BLKGETSIZE64: libc::c_ulong = ior(0x12, 114, std::mem::size_of::<libc::size_t>());
+#[cfg(target_os = "linux")]
+const BLKROGET: libc::c_ulong = (0x12 << 8) | 94;
+#[cfg(target_os = "linux")]
+const BLKDISCARD: libc::c_ulong = (0x12 << 8) | 119;
+#[cfg(target_os = "linux")]
+const BLKSECDISCARD: libc::c_ulong = (0x12 << 8) | 125;
+#[cfg(target_os = "linux")]
+const BLKZEROOUT: libc::c_ulong = (0x12 << 8) | 127;
+
+#[cfg(target_os = "macos")]
+const DKIOCGETBLOCKSIZE: libc::c_ulong = 0x40046418;
+#[cfg(target_os = "macos")]
+const DKIOCGETBLOCKCOUNT: libc::c_ulong = 0x40086419;
+#[cfg(target_os = "macos")]
+const DKIOCISWRITABLE: libc::c_ulong = 0x4004641d;
+
+/// A zeroed byte buffer whose start is aligned to `align`, as required for
+/// uncached (O_DIRECT) I/O.
+pub struct AlignedBuffer {
+    storage: Vec<u8>,
+    offset: usize,
+    len: usize,
+}
+
+impl AlignedBuffer {
+    pub fn new(len: usize, align: usize) -> Self {
+        let storage = vec![0; len + align];
+        let offset = storage.as_ptr().align_offset(align);
+        AlignedBuffer { storage, offset, len }
+    }
+
+    /// Shorten the buffer, keeping its alignment.
+    pub fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.storage[self.offset..self.offset + self.len]
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.storage[self.offset..self.offset + self.len]
+    }
+}
+
+/// Size in bytes of a block device or regular file.
+pub fn device_size(file: &File) -> io::Result<u64> {
+    let metadata = file.metadata()?;
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        use std::os::unix::io::AsRawFd;
+
+        if metadata.file_type().is_block_device() {
+            let mut size: u64 = 0;
+            if unsafe { libc::ioctl(file.as_raw_fd(), BLKGETSIZE64 as _, &mut size) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            return Ok(size);
+        }
+    }
+    #[cfg(target_os = "macos")]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        use std::os::unix::io::AsRawFd;
+
+        let file_type = metadata.file_type();
+        if file_type.is_block_device() || file_type.is_char_device() {
+            let mut block_size: u32 = 0;
+            let mut block_count: u64 = 0;
+            unsafe {
+                if libc::ioctl(file.as_raw_fd(), DKIOCGETBLOCKSIZE, &mut block_size) != 0
+                    || libc::ioctl(file.as_raw_fd(), DKIOCGETBLOCKCOUNT, &mut block_count) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            return Ok(block_size as u64 * block_count);
+        }
+    }
+
+    Ok(metadata.len())
+}
+
+/// How many bytes a target can hold, or `None` for a regular file, which is
+/// replaced and grows as it is written.
+pub fn target_capacity<P: AsRef<Path>>(path: P) -> io::Result<Option<u64>> {
+    let metadata = match std::fs::metadata(&path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    if metadata.is_file() {
+        return Ok(None);
+    }
+
+    device_size(&File::open(&path)?).map(Some)
+}
+
+/// Have the device zero `len` bytes at `offset` itself (BLKZEROOUT), which the
+/// kernel turns into a write-zeroes command or, failing that, plain writes.
+#[cfg(target_os = "linux")]
+pub fn zero_range(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    range_ioctl(file, BLKZEROOUT, offset, len)
+}
+
+/// Discard `len` bytes at `offset` (BLKDISCARD).
+#[cfg(target_os = "linux")]
+pub fn discard_range(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    range_ioctl(file, BLKDISCARD, offset, len)
+}
+
+/// Unmap `len` bytes at `offset` and have them read back as zeros, through
+/// fallocate(PUNCH_HOLE). Unlike BLKDISCARD this guarantees the zeros, and
+/// unlike BLKZEROOUT it never falls back to writing them: devices that cannot
+/// zero the range themselves fail with EOPNOTSUPP.
+#[cfg(target_os = "linux")]
+pub fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    if unsafe { libc::fallocate(file.as_raw_fd(), mode, offset as libc::off_t, len as libc::off_t) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Discard `len` bytes at `offset` so that the old data cannot be recovered
+/// either (BLKSECDISCARD).
+#[cfg(target_os = "linux")]
+pub fn secure_discard_range(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    range_ioctl(file, BLKSECDISCARD, offset, len)
+}
+
+#[cfg(target_os = "linux")]
+fn range_ioctl(file: &File, request: libc::c_ulong, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let range: [u64; 2] = [offset, len];
+    if unsafe { libc::ioctl(file.as_raw_fd(), request as _, &range) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A request queue attribute of the disk behind a block device, such as
+/// "discard_max_bytes". Partitions share the queue of their disk.
+#[cfg(target_os = "linux")]
+pub fn queue_attribute<P: AsRef<Path>>(device_path: P, name: &str) -> Option<String> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let metadata = std::fs::metadata(&device_path).ok()?;
+    if !metadata.file_type().is_block_device() {
+        return None;
+    }
+
+    let sysfs_path = sysfs_for_dev(metadata.rdev());
+    let disk_path = if sysfs_path.join("partition").exists() {
+        sysfs_path.parent()?.to_path_buf()
+    } else {
+        sysfs_path
+    };
+    read_attribute(&disk_path.join("queue").join(name))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn queue_attribute<P: AsRef<Path>>(_device_path: P, _name: &str) -> Option<String> {
+    None
+}
+
+/// Block sizes that writes to a device should respect.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockGeometry {
+    /// Smallest unit the device addresses; uncached I/O has to be aligned to it
+    pub logical_block_size: usize,
+    /// Unit the device writes internally, anything smaller is read-modify-write
+    pub physical_block_size: usize,
+    /// Erase block size reported by SD cards and eMMC, when the target starts
+    /// on an erase block boundary
+    pub erase_size: Option<usize>,
+}
+
+impl Default for BlockGeometry {
+    fn default() -> Self {
+        BlockGeometry { logical_block_size: 512, physical_block_size: 512, erase_size: None }
+    }
+}
+
+/// Block sizes of the disk behind a block device, from sysfs.
+#[cfg(target_os = "linux")]
+pub fn block_geometry<P: AsRef<Path>>(device_path: P) -> BlockGeometry {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let size = |name: &str| {
+        queue_attribute(&device_path, name)
+            .and_then(|value| value.parse::<usize>().ok())
+            .filter(|&size| size >= 512 && size.is_power_of_two())
+    };
+    let logical_block_size = size("logical_block_size").unwrap_or(512);
+    let mut geometry = BlockGeometry {
+        logical_block_size,
+        physical_block_size: size("physical_block_size").unwrap_or(512).max(logical_block_size),
+        erase_size: None,
+    };
+
+    let Ok(metadata) = std::fs::metadata(&device_path) else {
+        return geometry;
+    };
+    if !metadata.file_type().is_block_device() {
+        return geometry;
+    }
+
+    // a partition only gets the erase size if it starts on an erase block
+    let sysfs_path = sysfs_for_dev(metadata.rdev());
+    let (disk_path, start) = match read_attribute(&sysfs_path.join("start")) {
+        Some(start) => (sysfs_path.parent().map(Path::to_path_buf), start.parse::<u64>().ok()),
+        None => (Some(sysfs_path), Some(0)),
+    };
+    geometry.erase_size = disk_path
+        .and_then(|disk| read_attribute(&disk.join("device").join("preferred_erase_size")))
+        .and_then(|value| value.parse::<usize>().ok())
+        .filter(|&erase| erase >= geometry.physical_block_size && erase.is_power_of_two())
+        .filter(|&erase| start.is_some_and(|start| start * 512 % erase as u64 == 0));
+
+    geometry
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn block_geometry<P: AsRef<Path>>(_device_path: P) -> BlockGeometry {
+    BlockGeometry::default()
+}
+
+/// Whether a device refuses writes, because of the lock switch on an SD card
+/// or a read-only flag such as an eMMC's force_ro. Unknown counts as writable.
+#[cfg(target_os = "linux")]
+pub fn is_read_only<P: AsRef<Path>>(device_path: P) -> bool {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    use std::os::unix::io::AsRawFd;
+
+    if let Ok(file) = File::open(&device_path) {
+        let mut read_only: libc::c_int = 0;
+        if unsafe { libc::ioctl(file.as_raw_fd(), BLKROGET as _, &mut read_only) } == 0 {
+            return read_only != 0;
+        }
+    }
+
+    // opening needs more privileges than reading sysfs
+    match std::fs::metadata(&device_path) {
+        Ok(metadata) if metadata.file_type().is_block_device() => {
+            read_attribute(&sysfs_for_dev(metadata.rdev()).join("ro")).is_some_and(|ro| ro == "1")
+        }
+        _ => false,
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn is_read_only<P: AsRef<Path>>(device_path: P) -> bool {
+    use std::os::unix::io::AsRawFd;
+
+    let Ok(file) = File::open(&device_path) else {
+        return false;
+    };
+    let mut writable: u32 = 1;
+    unsafe { libc::ioctl(file.as_raw_fd(), DKIOCISWRITABLE, &mut writable) == 0 && writable == 0 }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn is_read_only<P: AsRef<Path>>(device_path: P) -> bool {
+    std::fs::metadata(&device_path).is_ok_and(|m| m.permissions().readonly())
+}
+
+/// Refuse a device that is write-protected, before any write fails halfway.
+pub fn check_writable<P: AsRef<Path>>(device_path: P) -> io::Result<()> {
+    let is_file = std::fs::metadata(&device_path).map_or(true, |m| m.is_file());
+    if is_file || !is_read_only(&device_path) {
+        return Ok(());
+    }
+
+    Err(io::Error::new(io::ErrorKind::PermissionDenied, format!(
+        "{} is write-protected, check the lock switch on the card or the device's read-only flag",
+        device_path.as_ref().display()
+    )))
+}
+
+/// Make sure a target is something that may be flashed: a block device (or a
+/// raw disk on macOS), or a regular file when `allow_files` is set. Existing
+/// files are only replaced with `overwrite_files`, so a mistyped device path
+/// neither creates a stray file nor truncates one.
+pub fn check_target<P: AsRef<Path>>(path: P, allow_files: bool, overwrite_files: bool) -> io::Result<()> {
+    let display = path.as_ref().display();
+    let allow_files = allow_files || overwrite_files;
+
+    let metadata = match std::fs::metadata(&path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if allow_files {
+                return Ok(());
+            }
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!(
+                "{} does not exist, pass --allow-file to write the image to a new regular file",
+                display
+            )));
+        }
+        Err(e) => return Err(e),
+    };
+
+    if metadata.is_file() {
+        if !allow_files {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "{} is a regular file, not a device, pass --allow-file to write to files", display
+            )));
+        }
+        if !overwrite_files {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!(
+                "{} already exists, pass --overwrite-file to replace it", display
+            )));
+        }
+        return Ok(());
+    }
+
+    if is_device(&metadata) {
+        return Ok(());
+    }
+
+    Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+        "{} is neither a block device nor a regular file", display
+    )))
+}
+
+#[cfg(unix)]
+fn is_device(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    let file_type = metadata.file_type();
+    // macOS exposes the fast unbuffered path to a disk as /dev/rdiskN, a character device
+    file_type.is_block_device() || (cfg!(target_os = "macos") && file_type.is_char_device())
+}
+
+#[cfg(not(unix))]
+fn is_device(metadata: &std::fs::Metadata) -> bool {
+    !metadata.is_dir()
+}
+
+/// Open a target that passed `check_target` for writing. Regular files are
+/// created or replaced; block devices are opened exclusively where the
+/// platform supports it, so one that is mounted or held by another process
+/// is refused rather than written underneath it. `synchronous` opens it with
+/// O_SYNC, so that every write is durable once it returns.
+pub fn open_target<P: AsRef<Path>>(path: P, synchronous: bool) -> io::Result<File> {
+    open(path, synchronous, true)
+}
+
+/// Open a target that is being flashed again, after its previous handle was
+/// closed. Nothing is created or truncated, and block devices are still
+/// opened exclusively.
+pub fn reopen_target<P: AsRef<Path>>(path: P, synchronous: bool) -> io::Result<File> {
+    open(path, synchronous, false)
+}
+
+fn open<P: AsRef<Path>>(path: P, synchronous: bool, replace: bool) -> io::Result<File> {
+    let is_file = match std::fs::metadata(&path) {
+        Ok(metadata) => metadata.is_file(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => true,
+        Err(e) => return Err(e),
+    };
+
+    let mut options = OpenOptions::new();
+    options.write(true);
+
+    #[cfg(unix)]
+    if synchronous {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(libc::O_SYNC);
+    }
+    #[cfg(not(unix))]
+    let _ = synchronous;
+
+    if is_file && replace {
+        return options.create(true).truncate(true).open(&path);
+    } else if is_file {
+        return options.open(&path);
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(libc::O_EXCL | if synchronous { libc::O_SYNC } else { 0 });
+    }
+
+    options.open(&path).map_err(|e| describe_open_error(&path, e))
+}
+
+/// Explain why a device could not be opened, where the error alone says little.
+fn describe_open_error<P: AsRef<Path>>(path: P, e: io::Error) -> io::Error {
+    match e.kind() {
+        io::ErrorKind::ResourceBusy => io::Error::new(e.kind(), format!(
+            "{} is busy, it is mounted or opened exclusively by another process",
+            path.as_ref().display()
+        )),
+        io::ErrorKind::ReadOnlyFilesystem => io::Error::new(e.kind(), format!(
+            "{} is write-protected, check the lock switch on the card or the device's read-only flag",
+            path.as_ref().display()
+        )),
+        _ => e,
+    }
+}
+
+/// Open a device for reading and writing while bypassing the page cache where
+/// the platform allows it, so reads reflect what actually reached the media.
+/// Block devices are opened exclusively, like a target that is flashed.
+pub fn open_uncached<P: AsRef<Path>>(path: P) -> io::Result<File> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let is_file = std::fs::metadata(&path).map_or(true, |m| m.is_file());
+        let exclusive = if is_file { 0 } else { libc::O_EXCL };
+        // tmpfs and some filesystems reject O_DIRECT with EINVAL
+        match OpenOptions::new().read(true).write(true).custom_flags(libc::O_DIRECT | exclusive).open(&path) {
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                OpenOptions::new().read(true).write(true).custom_flags(exclusive).open(&path)
+            }
+            result => result,
+        }
+        .map_err(|e| describe_open_error(&path, e))
+    }
+    #[cfg(target_os = "macos")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        let file = OpenOptions::new().read(true).write(true).open(&path)?;
+        unsafe {
+            libc::fcntl(file.as_raw_fd(), libc::F_NOCACHE, 1);
+        }
+        Ok(file)
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    {
+        OpenOptions::new().read(true).write(true).open(&path)
+    }
+}
+
+/// Flush pending writes and evict any cached pages for the file, so that the
+/// next read has to go to the device.
+pub fn drop_cache(file: &File) -> io::Result<()> {
+    file.sync_all()?;
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        unsafe {
+            libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED);
+        }
+    }
+
+    Ok(())
+}
+
+/// Whole disks a device path ends up writing to. Regular files resolve to
+/// nothing, since writing them cannot clobber a disk.
+#[cfg(target_os = "linux")]
+pub fn disks_for_path<P: AsRef<Path>>(device_path: P) -> Vec<String> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    match std::fs::metadata(&device_path) {
+        Ok(metadata) if metadata.file_type().is_block_device() => {
+            disks_backing(&sysfs_for_dev(metadata.rdev()))
+        }
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn disks_for_path<P: AsRef<Path>>(device_path: P) -> Vec<String> {
+    let path = device_path.as_ref().to_string_lossy();
+    match path.strip_prefix("/dev/r").or_else(|| path.strip_prefix("/dev/")) {
+        Some(name) => vec![whole_macos_disk(name)],
+        None => Vec::new(),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn disks_for_path<P: AsRef<Path>>(_device_path: P) -> Vec<String> {
+    Vec::new()
+}
+
+/// Kernel names of the partitions on the disk at `device_path`, e.g. "sdb1".
+#[cfg(target_os = "linux")]
+pub fn partitions<P: AsRef<Path>>(device_path: P) -> Vec<String> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let sysfs_path = match std::fs::metadata(&device_path) {
+        Ok(metadata) if metadata.file_type().is_block_device() => sysfs_for_dev(metadata.rdev()),
+        _ => return Vec::new(),
+    };
+
+    let mut partitions: Vec<String> = std::fs::read_dir(&sysfs_path)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter(|e| e.path().join("partition").exists())
+                .map(|e| e.file_name().to_string_lossy().to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    partitions.sort();
+    partitions
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn partitions<P: AsRef<Path>>(_device_path: P) -> Vec<String> {
+    Vec::new()
+}
+
+/// "disk0s2" -> "disk0"
+#[cfg(target_os = "macos")]
+pub fn whole_macos_disk(name: &str) -> String {
+    let name = name.trim_start_matches("/dev/");
+    match name[4.min(name.len())..].find('s') {
+        Some(i) => name[..4 + i].to_string(),
+        None => name.to_string(),
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub fn major(dev: u64) -> u64 {
+    ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff)
+}
+
+#[cfg(target_os = "linux")]
+fn minor(dev: u64) -> u64 {
+    (dev & 0xff) | ((dev >> 12) & !0xff)
+}
+
+#[cfg(target_os = "linux")]
+pub fn parse_dev(major_minor: &str) -> Option<u64> {
+    let (major, minor) = major_minor.split_once(':')?;
+    let (major, minor): (u64, u64) = (major.parse().ok()?, minor.parse().ok()?);
+    Some(((major & 0xfff) << 8) | ((major & !0xfff) << 32) | (minor & 0xff) | ((minor & !0xff) << 12))
+}
+
+#[cfg(target_os = "linux")]
+pub fn sysfs_for_dev(dev: u64) -> std::path::PathBuf {
+    let link = format!("/sys/dev/block/{}:{}", major(dev), minor(dev));
+    std::fs::canonicalize(&link).unwrap_or_else(|_| link.into())
+}
+
+/// Follow a sysfs block device down through `slaves/` (dm, md) and up from
+/// partitions to the whole disks underneath it.
+#[cfg(target_os = "linux")]
+pub fn disks_backing(sysfs_path: &Path) -> Vec<String> {
+    let slaves: Vec<_> = std::fs::read_dir(sysfs_path.join("slaves"))
+        .map(|entries| entries.filter_map(|e| e.ok()).collect())
+        .unwrap_or_default();
+
+    if !slaves.is_empty() {
+        return slaves
+            .iter()
+            .flat_map(|slave| {
+                let path = std::fs::canonicalize(slave.path()).unwrap_or_else(|_| slave.path());
+                disks_backing(&path)
+            })
+            .collect();
+    }
+
+    let disk_path = if sysfs_path.join("partition").exists() {
+        sysfs_path.parent().unwrap_or(sysfs_path)
+    } else {
+        sysfs_path
+    };
+
+    disk_path
+        .file_name()
+        .map(|name| vec![name.to_string_lossy().to_string()])
+        .unwrap_or_default()
+}
+
+/// What a target looked like when it was selected, to tell whether the same
+/// path still leads to the same physical device right before writing.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DeviceIdentity {
+    pub vendor: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    pub wwn: Option<String>,
+    /// How the device is attached, e.g. "usb", "mmc", "nvme" or "ata"
+    pub bus: Option<String>,
+    pub size: u64,
+}
+
+impl DeviceIdentity {
+    /// "SanDisk Ultra, serial 4C53..., 15376318464 bytes"
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        let name: Vec<&str> = [&self.vendor, &self.model].into_iter().flatten().map(|s| s.as_str()).collect();
+        if !name.is_empty() {
+            parts.push(name.join(" "));
+        }
+        if let Some(ref serial) = self.serial {
+            parts.push(format!("serial {}", serial));
+        }
+        if let Some(ref wwn) = self.wwn {
+            parts.push(format!("WWN {}", wwn));
+        }
+        parts.push(format!("{} bytes", self.size));
+        parts.join(", ")
+    }
+}
+
+/// Identify the disk behind a device path by model, serial, WWN and size, as
+/// recorded by udev or the kernel in sysfs.
+#[cfg(target_os = "linux")]
+pub fn device_identity<P: AsRef<Path>>(device_path: P) -> io::Result<DeviceIdentity> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let metadata = std::fs::metadata(&device_path)?;
+    if !metadata.file_type().is_block_device() {
+        return Ok(DeviceIdentity { size: metadata.len(), ..Default::default() });
+    }
+
+    let sysfs_path = sysfs_for_dev(metadata.rdev());
+    let size = read_attribute(&sysfs_path.join("size"))
+        .and_then(|sectors| sectors.parse::<u64>().ok())
+        .map_or(0, |sectors| sectors * 512);
+
+    // model and serial belong to the whole disk rather than a partition
+    let disk_path = if sysfs_path.join("partition").exists() {
+        sysfs_path.parent().unwrap_or(&sysfs_path).to_path_buf()
+    } else {
+        sysfs_path
+    };
+
+    let udev: Vec<(String, String)> = read_attribute(&disk_path.join("dev"))
+        .and_then(|dev| std::fs::read_to_string(format!("/run/udev/data/b{}", dev)).ok())
+        .map(|data| {
+            data.lines()
+                .filter_map(|line| line.strip_prefix("E:")?.split_once('='))
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        })
+        .unwrap_or_default();
+    let udev_value = |key: &str| udev.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone());
+
+    let device_dir = std::fs::canonicalize(disk_path.join("device")).ok();
+
+    // USB bridges put the serial on the USB device a few levels up; stop there
+    // rather than picking up the serial of the hub it is plugged into
+    let serial = udev_value("ID_SERIAL_SHORT")
+        .or_else(|| read_attribute(&disk_path.join("serial")))
+        .or_else(|| {
+            let device_dir = device_dir.as_ref()?;
+            device_dir
+                .ancestors()
+                .take_while(|dir| dir.starts_with("/sys/devices"))
+                .find_map(|dir| {
+                    let serial = read_attribute(&dir.join("serial")).or_else(|| read_attribute(&dir.join("cid")));
+                    (serial.is_some() || dir.join("idVendor").exists()).then_some(serial)
+                })
+                .flatten()
+        });
+
+    let wwn = udev_value("ID_WWN")
+        .or_else(|| read_attribute(&disk_path.join("wwid")))
+        .or_else(|| read_attribute(&disk_path.join("device/wwid")));
+
+    let vendor = udev_value("ID_VENDOR").or_else(|| read_attribute(&disk_path.join("device/vendor")));
+    let model = udev_value("ID_MODEL")
+        .or_else(|| read_attribute(&disk_path.join("device/model")))
+        .or_else(|| read_attribute(&disk_path.join("device/name")));
+
+    // USB sticks show up as SCSI disks, so the path to the device tells more than the name
+    let device_dir = device_dir.map(|dir| dir.to_string_lossy().to_string()).unwrap_or_default();
+    let disk_name = disk_path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let bus = udev_value("ID_BUS").or_else(|| {
+        let bus = if device_dir.contains("/usb") {
+            "usb"
+        } else if disk_name.starts_with("mmcblk") {
+            "mmc"
+        } else if disk_name.starts_with("nvme") {
+            "nvme"
+        } else if disk_name.starts_with("vd") {
+            "virtio"
+        } else if device_dir.contains("/ata") {
+            "ata"
+        } else if disk_name.starts_with("sd") {
+            "scsi"
+        } else {
+            return None;
+        };
+        Some(bus.to_string())
+    });
+
+    Ok(DeviceIdentity { vendor, model, serial, wwn, bus, size })
+}
+
+#[cfg(target_os = "macos")]
+pub fn device_identity<P: AsRef<Path>>(device_path: P) -> io::Result<DeviceIdentity> {
+    let output = std::process::Command::new("diskutil")
+        .arg("info")
+        .arg(device_path.as_ref())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!(
+            "{} is not a disk", device_path.as_ref().display()
+        )));
+    }
+
+    let mut identity = DeviceIdentity::default();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let Some((key, value)) = line.trim().split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+
+        match key {
+            "Device / Media Name" => identity.model = Some(value.to_string()),
+            "Protocol" => identity.bus = Some(value.to_lowercase()),
+            "Disk / Partition UUID" | "Media UUID" => identity.serial = Some(value.to_string()),
+            // "16.0 GB (16008609792 Bytes) (exactly 31266816 512-Byte-Units)"
+            "Disk Size" => {
+                identity.size = value
+                    .split_once('(')
+                    .and_then(|(_, bytes)| bytes.split_whitespace().next()?.parse().ok())
+                    .unwrap_or(0);
+            }
+            _ => {}
+        }
+    }
+
+    Ok(identity)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn device_identity<P: AsRef<Path>>(device_path: P) -> io::Result<DeviceIdentity> {
+    let size = std::fs::metadata(&device_path)?.len();
+    Ok(DeviceIdentity { size, ..Default::default() })
+}
+
+/// A sysfs attribute with surrounding whitespace removed, if it is non-empty.
+#[cfg(target_os = "linux")]
+fn read_attribute(path: &Path) -> Option<String> {
+    let value = std::fs::read_to_string(path).ok()?;
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
@@ -0,0 +1,273 @@
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::blockdev::{self, AlignedBuffer, BlockGeometry};
+
+/// How a device is written: the size of each write, the block size it is
+/// aligned to, and whether it bypasses the page cache.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WriteLayout {
+    pub write_size: usize,
+    pub block_size: usize,
+    pub direct: bool,
+}
+
+impl WriteLayout {
+    /// Writes of `write_size` through the page cache.
+    pub fn buffered(write_size: usize) -> Self {
+        WriteLayout { write_size: write_size.max(512), block_size: 512, direct: false }
+    }
+
+    /// Uncached writes to a block device, aligned to its physical block size
+    /// and, for SD cards and eMMC, rounded up to whole erase blocks.
+    pub fn direct<P: AsRef<Path>>(device_path: P, write_size: usize) -> Self {
+        let BlockGeometry { physical_block_size, erase_size, .. } = blockdev::block_geometry(device_path);
+        let unit = erase_size.unwrap_or(physical_block_size);
+        WriteLayout {
+            write_size: write_size.max(1).div_ceil(unit) * unit,
+            block_size: physical_block_size,
+            direct: true,
+        }
+    }
+}
+
+/// Buffers writes to a device and hands them over in `write_size` pieces.
+/// With O_DIRECT, whole blocks at block-aligned offsets go straight to the
+/// device, while anything else (the unaligned tail of the image, the sectors
+/// of a relocated GPT on a 4Kn disk) goes through the page cache. A device
+/// that rejects O_DIRECT is written through the page cache altogether.
+pub struct AlignedWriter {
+    file: File,
+    buffer: AlignedBuffer,
+    filled: usize,
+    /// Device offset of the start of `buffer`
+    position: u64,
+    block_size: usize,
+    direct: bool,
+}
+
+impl AlignedWriter {
+    pub fn new(mut file: File, layout: WriteLayout) -> io::Result<Self> {
+        let position = file.stream_position()?;
+        let align = layout.block_size.max(4096);
+        let mut writer = AlignedWriter {
+            file,
+            buffer: AlignedBuffer::new(layout.write_size, align),
+            filled: 0,
+            position,
+            block_size: layout.block_size,
+            direct: false,
+        };
+        if layout.direct {
+            // filesystems such as tmpfs refuse the flag
+            writer.direct = set_direct(&writer.file, true).is_ok();
+        }
+        Ok(writer)
+    }
+
+    pub fn get_ref(&self) -> &File {
+        &self.file
+    }
+
+    pub fn get_mut(&mut self) -> &mut File {
+        &mut self.file
+    }
+
+    /// Whether writes still bypass the page cache.
+    pub fn is_direct(&self) -> bool {
+        self.direct
+    }
+
+    /// Drop the writer without writing out what is still buffered.
+    pub fn discard(mut self) {
+        self.filled = 0;
+    }
+
+    fn write_buffer(&mut self) -> io::Result<()> {
+        let mut written = 0;
+
+        if self.direct && self.position.is_multiple_of(self.block_size as u64) {
+            let aligned = self.filled / self.block_size * self.block_size;
+            if aligned > 0 {
+                match self.file.write_all(&self.buffer[..aligned]) {
+                    Ok(()) => written = aligned,
+                    Err(e) if e.raw_os_error() == Some(EINVAL) => {
+                        // the device takes the flag but not the writes
+                        self.direct = false;
+                        let _ = set_direct(&self.file, false);
+                        self.file.seek(SeekFrom::Start(self.position))?;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        if written < self.filled {
+            if self.direct {
+                set_direct(&self.file, false)?;
+            }
+            let result = self.file.write_all(&self.buffer[written..self.filled]);
+            if self.direct {
+                set_direct(&self.file, true)?;
+            }
+            result?;
+        }
+
+        self.position += self.filled as u64;
+        self.filled = 0;
+        Ok(())
+    }
+}
+
+impl Write for AlignedWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let len = data.len().min(self.buffer.len() - self.filled);
+        self.buffer[self.filled..self.filled + len].copy_from_slice(&data[..len]);
+        self.filled += len;
+
+        if self.filled == self.buffer.len() {
+            self.write_buffer()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.filled > 0 {
+            self.write_buffer()?;
+        }
+        self.file.flush()
+    }
+}
+
+impl Seek for AlignedWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.flush()?;
+        self.position = self.file.seek(pos)?;
+        Ok(self.position)
+    }
+}
+
+impl Drop for AlignedWriter {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(unix)]
+const EINVAL: i32 = libc::EINVAL;
+#[cfg(not(unix))]
+const EINVAL: i32 = 22;
+
+/// Switch the page cache off or back on for an open file.
+#[cfg(target_os = "linux")]
+fn set_direct(file: &File, direct: bool) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let flags = if direct { flags | libc::O_DIRECT } else { flags & !libc::O_DIRECT };
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn set_direct(file: &File, direct: bool) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_NOCACHE, direct as libc::c_int) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn set_direct(_file: &File, direct: bool) -> io::Result<()> {
+    if direct {
+        return Err(io::Error::from(io::ErrorKind::Unsupported));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Noise, TempFile};
+    use std::fs::OpenOptions;
+
+    fn open_writer(path: &Path, block_size: usize, write_size: usize) -> AlignedWriter {
+        let file = OpenOptions::new().write(true).open(path).unwrap();
+        AlignedWriter::new(file, WriteLayout { write_size, block_size, direct: true }).unwrap()
+    }
+
+    fn write_in_pieces(writer: &mut AlignedWriter, data: &[u8]) {
+        for piece in data.chunks(7777) {
+            writer.write_all(piece).unwrap();
+        }
+        writer.flush().unwrap();
+    }
+
+    #[test]
+    fn buffers_suit_direct_writes() {
+        let target = TempFile::new(b"");
+        for block_size in [512, 4096, 16384] {
+            let writer = open_writer(&target.path, block_size, 64 * 1024);
+            assert_eq!(writer.buffer.len(), 64 * 1024);
+            assert_eq!(writer.buffer.as_ptr() as usize % block_size.max(4096), 0);
+        }
+    }
+
+    #[test]
+    fn an_unaligned_tail_is_written_as_is() {
+        let data = Noise(1).bytes(3 * 64 * 1024 + 1000);
+        let target = TempFile::new(b"");
+        let mut writer = open_writer(&target.path, 512, 64 * 1024);
+        // whether the filesystem takes O_DIRECT at all is up to it
+        let direct = writer.is_direct();
+
+        write_in_pieces(&mut writer, &data);
+        // the tail went through the page cache without giving up on O_DIRECT
+        assert_eq!(writer.is_direct(), direct);
+        drop(writer);
+        // and was not padded out to a whole block
+        assert!(std::fs::read(&target.path).unwrap() == data);
+    }
+
+    #[test]
+    fn writes_from_an_unaligned_offset_go_through_the_page_cache() {
+        let before = Noise(2).bytes(8192);
+        let data = Noise(3).bytes(100_000);
+        let target = TempFile::new(&before);
+        let mut writer = open_writer(&target.path, 512, 16 * 1024);
+        assert_eq!(writer.seek(SeekFrom::Start(100)).unwrap(), 100);
+        write_in_pieces(&mut writer, &data);
+        drop(writer);
+
+        let written = std::fs::read(&target.path).unwrap();
+        assert!(written[..100] == before[..100]);
+        assert!(written[100..] == data);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn targets_refusing_o_direct_are_written_through_the_page_cache() {
+        // the flag itself is refused
+        let mut writer = open_writer(Path::new("/dev/null"), 512, 4096);
+        assert!(!writer.is_direct());
+        write_in_pieces(&mut writer, &Noise(4).bytes(10_000));
+
+        // the flag is taken, but blocks smaller than the filesystem's are not
+        // (ext4 refuses them, newer tmpfs takes anything)
+        let data = Noise(5).bytes(50_000);
+        let target = TempFile::new(b"");
+        let mut writer = open_writer(&target.path, 256, 4096);
+        write_in_pieces(&mut writer, &data);
+        drop(writer);
+        assert!(std::fs::read(&target.path).unwrap() == data);
+    }
+}
@@ -1,15 +1,46 @@
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 use std::time::{Duration, Instant};
 use std::process::Command;
-use flate2::read::GzDecoder;
+use flate2::read::MultiGzDecoder;
+use sha2::{Digest, Sha256};
+use liblzma::read::XzDecoder;
 use zstd::stream::read::Decoder as ZstdDecoder;
 
+use crate::backup;
+use crate::blockdev::{self, DeviceIdentity};
+use crate::direct::{AlignedWriter, WriteLayout};
+use crate::gzip::{self, ParallelGzDecoder};
+use crate::mounts::{self, UnmountPolicy};
+use crate::parallel::{self, SegmentDecoder};
+use crate::partition;
+use crate::pipeline::{self, Chunk, IoBackend, PipelineLimits};
+use crate::policy;
+use crate::probe;
+use crate::protect;
+use crate::ratelimit::{RateLimits, Throttle};
+#[cfg(target_os = "linux")]
+use crate::uring;
+use crate::recovery::{self, RetryPolicy, SyncWindow};
+use crate::writeback::{self, SyncPolicy, Writeback};
+use crate::sparse::{ImageRead, SparseFile};
+use crate::xz::{self, XzBlocks};
+use crate::zeroing::{self, DiscardMode, DiscardOutcome, ZeroStrategy};
+use crate::zst::{self, ZstdFrames};
+
 pub struct Progress {
     pub bytes_written: u64,
     pub total_bytes: u64,
+    pub devices: Vec<DeviceStatus>,
+    /// SHA-256 of the decoded image, once all of it has been read
+    pub image_sha256: Option<String>,
+    /// The device being discarded, while the discard pass runs
+    pub discarding: Option<String>,
     start_time: Instant,
 }
 
@@ -18,6 +49,9 @@ impl Progress {
         Progress {
             bytes_written: 0,
             total_bytes,
+            devices: Vec::new(),
+            image_sha256: None,
+            discarding: None,
             start_time: Instant::now(),
         }
     }
@@ -43,6 +77,435 @@ impl Progress {
 
         self.bytes_written as f32 / elapsed
     }
+
+    /// "path: reason" for every device that was dropped from the job
+    pub fn failed_devices(&self) -> Vec<String> {
+        self.devices
+            .iter()
+            .filter_map(|d| d.error.as_ref().map(|e| format!("{}: {}", d.path, e)))
+            .collect()
+    }
+
+    /// Average write speed of one device in bytes per second.
+    pub fn device_speed_bytes(&self, index: usize) -> f64 {
+        let elapsed = self.start_time.elapsed().as_secs_f64();
+        if elapsed <= 0.0 {
+            return 0.0;
+        }
+        self.devices[index].bytes_written as f64 / elapsed
+    }
+
+    /// Record how far a device has got. Overall progress is that of the
+    /// slowest device still being written.
+    pub fn device_progressed(&mut self, index: usize, bytes_written: u64) {
+        self.devices[index].bytes_written = bytes_written;
+        if let Some(slowest) = self.devices.iter().filter(|d| d.error.is_none()).map(|d| d.bytes_written).min() {
+            self.bytes_written = slowest;
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceStatus {
+    pub path: String,
+    /// Bytes of the image handed to the device so far
+    pub bytes_written: u64,
+    /// Writes submitted to the device that have not completed yet
+    pub in_flight: usize,
+    /// LBA ranges (512-byte sectors) that could not be written
+    pub error_map: Vec<Range<u64>>,
+    /// Set once the device has been dropped from the job
+    pub error: Option<String>,
+    /// Where the image was cut off because the device is smaller than it
+    pub truncated_at: Option<u64>,
+    /// File holding the device's partition tables from before it was flashed
+    pub backup: Option<PathBuf>,
+    /// What the backup had to leave out
+    pub backup_warning: Option<String>,
+    /// How the image's zero blocks reached the device
+    pub zero_strategy: Option<ZeroStrategy>,
+    /// What the discard pass before flashing did
+    pub discard: Option<DiscardOutcome>,
+    /// How the device's writes were pushed to stable storage
+    pub sync_policy: Option<SyncPolicy>,
+    /// Syncs and writebacks waited for so far, and how long they took
+    pub syncs: u64,
+    pub sync_time: Duration,
+}
+
+impl DeviceStatus {
+    pub fn new(path: String) -> Self {
+        DeviceStatus {
+            path,
+            bytes_written: 0,
+            in_flight: 0,
+            error_map: Vec::new(),
+            error: None,
+            truncated_at: None,
+            backup: None,
+            backup_warning: None,
+            zero_strategy: None,
+            discard: None,
+            sync_policy: None,
+            syncs: 0,
+            sync_time: Duration::ZERO,
+        }
+    }
+
+    pub fn failed_sectors(&self) -> u64 {
+        self.error_map.iter().map(|r| r.end - r.start).sum()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FlashOptions {
+    /// Probe every target for fake capacity before writing to it
+    pub probe_capacity: bool,
+    pub retry: RetryPolicy,
+    /// Write even to disks holding the running system, swap or LVM/md members
+    pub allow_system_disk: bool,
+    /// What to do about mounted partitions on a target
+    pub unmount: UnmountPolicy,
+    /// Identity of each target at the time it was selected, keyed by path
+    pub pinned: HashMap<String, DeviceIdentity>,
+    /// Accept regular files as targets, not just block devices
+    pub allow_files: bool,
+    /// Replace regular files that already exist
+    pub overwrite_files: bool,
+    /// Go through every check and decode the whole image, but write nothing
+    pub dry_run: bool,
+    /// Save each device's partition tables here before overwriting them
+    pub backup_dir: Option<PathBuf>,
+    /// How runs of zeros in the image are written
+    pub zero_strategy: ZeroStrategy,
+    /// Size of the blocks the image is checked for zeros in
+    pub zero_granularity: usize,
+    /// Discard every target in full before writing to it
+    pub discard: DiscardMode,
+    /// How far reading the image may run ahead of the devices
+    pub pipeline: PipelineLimits,
+    /// Bypass the page cache when writing to block devices
+    pub direct_io: bool,
+    /// Bytes handed to a device in each write
+    pub write_size: usize,
+    /// How the devices are written
+    pub io_backend: IoBackend,
+    /// Writes kept in flight on each device with io_uring
+    pub uring_depth: usize,
+    /// How the devices are synced while they are written
+    pub sync_policy: SyncPolicy,
+    /// Sync policies that differ from `sync_policy`, keyed by path
+    pub device_sync_policies: HashMap<String, SyncPolicy>,
+    /// Threads decoding a compressed image, 0 for one per core
+    pub threads: usize,
+    /// Bandwidth limits, which may be changed while the devices are written
+    pub rate_limits: Arc<RateLimits>,
+}
+
+impl FlashOptions {
+    pub fn sync_policy_for(&self, path: &str) -> SyncPolicy {
+        self.device_sync_policies.get(path).copied().unwrap_or(self.sync_policy)
+    }
+}
+
+/// A target being flashed. `writer` is taken away once the device has failed,
+/// so the remaining devices can carry on without it.
+pub struct DeviceWriter {
+    pub path: PathBuf,
+    pub writer: Option<AlignedWriter>,
+    /// Bytes the device can hold, if it has a fixed size
+    pub capacity: Option<u64>,
+    /// Whether everything past `capacity` lies outside the image's partitions
+    /// and may be dropped
+    pub truncate: bool,
+    pub zero_strategy: ZeroStrategy,
+    pub layout: WriteLayout,
+    pub writeback: Writeback,
+    pub throttle: Throttle,
+}
+
+impl DeviceWriter {
+    /// Drop the device from the job, recording why in its status.
+    pub fn fail(&mut self, index: usize, reason: String, progress: &Arc<Mutex<Progress>>) {
+        if let Some(writer) = self.writer.take() {
+            // discard whatever is still buffered rather than flushing it on drop
+            writer.discard();
+        }
+        progress.lock().unwrap().devices[index].error = Some(reason);
+    }
+
+    /// Put `len` zeros at `offset`, the writer's current position, the way the
+    /// device's zero strategy says.
+    fn write_zeros(&mut self, offset: u64, len: u64, dry_run: bool) -> io::Result<()> {
+        let offloaded = self.offloads_zeros(len);
+        let Some(writer) = self.writer.as_mut() else {
+            return Ok(());
+        };
+        if !offloaded {
+            return write_zero_bytes(writer, len);
+        }
+
+        match self.zero_strategy {
+            ZeroStrategy::Skip => {}
+            // the null device of a dry run has nothing to zero
+            ZeroStrategy::ZeroOut | ZeroStrategy::Discard if dry_run => {}
+            ZeroStrategy::ZeroOut | ZeroStrategy::Discard => {
+                writer.flush()?;
+                match zeroing::clear_range(writer.get_ref(), self.zero_strategy, offset, len) {
+                    Ok(()) => {}
+                    // ranges off the device's block boundaries are written out
+                    Err(e) if e.kind() == io::ErrorKind::InvalidInput => return write_zero_bytes(writer, len),
+                    Err(e) => return Err(e),
+                }
+            }
+            ZeroStrategy::Write | ZeroStrategy::Auto => return write_zero_bytes(writer, len),
+        }
+
+        writer.seek(SeekFrom::Current(len as i64)).and_then(|_| writer.flush())
+    }
+
+    /// Whether a run of `len` zeros reaches the device some other way than
+    /// being written. Short runs are written even where the device could zero
+    /// them itself, see `zeroing::MIN_OFFLOAD`.
+    pub fn offloads_zeros(&self, len: u64) -> bool {
+        match self.zero_strategy {
+            ZeroStrategy::Skip => true,
+            ZeroStrategy::ZeroOut | ZeroStrategy::Discard => len >= zeroing::MIN_OFFLOAD,
+            ZeroStrategy::Write | ZeroStrategy::Auto => false,
+        }
+    }
+
+    /// Bytes of a hole of `len` that are actually sent to the device, and
+    /// count against its rate limit.
+    pub fn hole_traffic(&self, len: u64) -> u64 {
+        if self.offloads_zeros(len) {
+            0
+        } else {
+            len
+        }
+    }
+
+    /// Write the next chunk of the image, rewriting the sync window if the
+    /// device reports an error.
+    pub fn write_chunk(
+        &mut self,
+        index: usize,
+        chunk: Chunk,
+        window: &mut SyncWindow,
+        progress: &Arc<Mutex<Progress>>,
+        options: &FlashOptions,
+    ) {
+        let offset = window.end();
+        window.push(chunk.clone());
+
+        self.check_capacity(index, window.end(), progress);
+
+        // a truncated device only takes the part of the image that fits
+        let fits = self.capacity.map_or(chunk.len(), |capacity| {
+            capacity.saturating_sub(offset).min(chunk.len() as u64) as usize
+        });
+        if fits == 0 || self.writer.is_none() {
+            return;
+        }
+
+        if let Err(e) = self.write_data(offset, &chunk[..fits], options) {
+            recovery::recover_device(self, index, e, window, &options.retry, progress);
+        }
+    }
+
+    /// Write a piece of the image at `offset`, the writer's current position.
+    /// Unless zeros are written like any other data, runs of zero blocks long
+    /// enough to be worth it go the way of the zero strategy instead.
+    fn write_data(&mut self, offset: u64, data: &[u8], options: &FlashOptions) -> io::Result<()> {
+        if matches!(self.zero_strategy, ZeroStrategy::Write | ZeroStrategy::Auto) {
+            return self.writer.as_mut().map_or(Ok(()), |writer| writer.write_all(data));
+        }
+
+        for extent in zeroing::zero_extents(data, offset, options.zero_granularity) {
+            let start = offset + extent.range.start as u64;
+            if extent.zero && self.offloads_zeros(extent.range.len() as u64) {
+                self.write_zeros(start, extent.range.len() as u64, options.dry_run)?;
+            } else if let Some(writer) = self.writer.as_mut() {
+                writer.write_all(&data[extent.range])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Put a hole of `len` zeros that the image does not store on the device,
+    /// the way its zero strategy says.
+    pub fn write_hole(
+        &mut self,
+        index: usize,
+        len: u64,
+        window: &mut SyncWindow,
+        progress: &Arc<Mutex<Progress>>,
+        options: &FlashOptions,
+    ) {
+        let offset = window.end();
+        window.push_hole(len);
+
+        self.check_capacity(index, window.end(), progress);
+
+        let fits = self.capacity.map_or(len, |capacity| capacity.saturating_sub(offset).min(len));
+        if fits == 0 || self.writer.is_none() {
+            return;
+        }
+
+        if let Err(e) = self.write_zeros(offset, fits, options.dry_run) {
+            recovery::recover_device(self, index, e, window, &options.retry, progress);
+        }
+    }
+
+    /// Sync or write back the device the way its sync policy says, if it is
+    /// due.
+    pub fn write_back(
+        &mut self,
+        index: usize,
+        window: &mut SyncWindow,
+        progress: &Arc<Mutex<Progress>>,
+        options: &FlashOptions,
+    ) {
+        if !self.writeback.due(window, options.pipeline.sync_bytes()) {
+            return;
+        }
+        match self.writeback.policy {
+            // sync_file_range leaves the data in the device's cache, so only a
+            // real sync lets the window go, once it is full
+            SyncPolicy::Dirty(_) if window.len() < options.pipeline.sync_bytes() => {
+                self.write_back_dirty(index, window, progress, options)
+            }
+            _ => self.sync(index, window, progress, options),
+        }
+    }
+
+    /// Start writeback of what the device was given since the last time,
+    /// then wait for what was started before that and drop it from the page
+    /// cache, so little of the sync window is ever dirty. The window is kept,
+    /// as the device may still fail to flush its cache.
+    fn write_back_dirty(
+        &mut self,
+        index: usize,
+        window: &mut SyncWindow,
+        progress: &Arc<Mutex<Progress>>,
+        options: &FlashOptions,
+    ) {
+        let (finished, started, end) = (self.writeback.finished, self.writeback.started, window.end());
+        if let Some(writer) = self.writer.as_mut() {
+            let timer = Instant::now();
+            let result = writer.flush().and_then(|_| {
+                if options.dry_run {
+                    return Ok(());
+                }
+                writeback::start(writer.get_ref(), started, end - started)?;
+                writeback::finish(writer.get_ref(), finished, started - finished)
+            });
+            self.record_sync(index, timer, progress);
+
+            if let Err(e) = result {
+                recovery::recover_device(self, index, e, window, &options.retry, progress);
+            }
+        }
+        self.writeback.finished = started;
+        self.writeback.started = end;
+    }
+
+    /// Flush the device to stable storage, then drop the sync window.
+    pub fn sync(
+        &mut self,
+        index: usize,
+        window: &mut SyncWindow,
+        progress: &Arc<Mutex<Progress>>,
+        options: &FlashOptions,
+    ) {
+        if let Some(writer) = self.writer.as_mut() {
+            let timer = Instant::now();
+            // the null device of a dry run cannot be synced
+            let result = writer.flush().and_then(|_| {
+                if options.dry_run {
+                    return Ok(());
+                }
+                // a file whose zeros were skipped ends short of them
+                if self.capacity.is_none() && self.zero_strategy == ZeroStrategy::Skip {
+                    writer.get_ref().set_len(window.end())?;
+                }
+                // writes through O_SYNC are on stable storage already
+                if self.writeback.policy == SyncPolicy::OSync {
+                    return Ok(());
+                }
+                writer.get_mut().sync_data()
+            });
+            self.record_sync(index, timer, progress);
+
+            if let Err(e) = result {
+                recovery::recover_device(self, index, e, window, &options.retry, progress);
+            }
+        }
+        window.advance();
+        self.writeback.synced(window.end());
+    }
+
+    fn record_sync(&self, index: usize, timer: Instant, progress: &Arc<Mutex<Progress>>) {
+        let device = &mut progress.lock().unwrap().devices[index];
+        device.syncs += 1;
+        device.sync_time += timer.elapsed();
+    }
+
+    /// Put the backup GPT back at the real end of a device the image was cut
+    /// short on, and record where it was cut.
+    pub fn finish_truncated(
+        &mut self,
+        index: usize,
+        image_header: &[u8],
+        image_size: u64,
+        progress: &Arc<Mutex<Progress>>,
+    ) {
+        let capacity = match self.capacity {
+            Some(capacity) if self.truncate && capacity < image_size => capacity,
+            _ => return,
+        };
+        let Some(writer) = self.writer.as_mut() else {
+            return;
+        };
+
+        let result = partition::relocate_gpt(image_header, capacity)
+            .unwrap_or_default()
+            .iter()
+            .try_for_each(|(offset, data)| {
+                writer.seek(SeekFrom::Start(*offset))?;
+                writer.write_all(data)
+            });
+
+        match result {
+            Ok(()) => progress.lock().unwrap().devices[index].truncated_at = Some(capacity),
+            Err(e) => self.fail(index, format!("Could not rewrite the backup GPT header: {}", e), progress),
+        }
+    }
+
+    /// Fail the device if it cannot hold `image_size` bytes.
+    pub fn check_capacity(&mut self, index: usize, image_size: u64, progress: &Arc<Mutex<Progress>>) {
+        if let Some(capacity) = self.capacity {
+            if self.writer.is_some() && image_size > capacity && !self.truncate {
+                self.fail(index, format!(
+                    "image needs at least {} bytes but the device only holds {}",
+                    image_size, capacity
+                ), progress);
+            }
+        }
+    }
+}
+
+/// Write `len` zeros a chunk at a time, since a hole can be far bigger than
+/// is worth holding in memory.
+fn write_zero_bytes(writer: &mut AlignedWriter, len: u64) -> io::Result<()> {
+    let zeros = vec![0; len.min(pipeline::CHUNK_SIZE as u64) as usize];
+    let mut left = len;
+    while left > 0 {
+        let n = left.min(zeros.len() as u64) as usize;
+        writer.write_all(&zeros[..n])?;
+        left -= n as u64;
+    }
+    Ok(())
 }
 
 fn is_gzipped<P: AsRef<Path>>(path: P) -> io::Result<bool> {
@@ -61,8 +524,14 @@ fn is_zstd<P: AsRef<Path>>(path: P) -> io::Result<bool> {
     Ok(magic[0] == 0x28 && magic[1] == 0xB5 && magic[2] == 0x2F && magic[3] == 0xFD)
 }
 
+fn is_xz<P: AsRef<Path>>(path: P) -> io::Result<bool> {
+    let mut magic = [0; 6];
+    let bytes_read = File::open(path)?.read(&mut magic)?;
+    Ok(xz::is_xz(&magic[..bytes_read]))
+}
+
 
-fn get_img_size_from_header(header_buffer: &[u8]) -> u64 {
+pub fn get_img_size_from_header(header_buffer: &[u8]) -> u64 {
     if header_buffer.len() < 512 {
         return 0;
     }
@@ -139,8 +608,8 @@ fn get_img_size_from_header(header_buffer: &[u8]) -> u64 {
     0
 }
 
-fn get_file_info<P: AsRef<Path>>(path: P) -> io::Result<(u64, bool)> {
-    if is_gzipped(&path)? || is_zstd(&path)? {
+pub fn get_file_info<P: AsRef<Path>>(path: P) -> io::Result<(u64, bool)> {
+    if is_gzipped(&path)? || is_zstd(&path)? || is_xz(&path)? {
         // determine size during decompression
         return Ok((0, true));
     }
@@ -154,175 +623,423 @@ fn get_file_info<P: AsRef<Path>>(path: P) -> io::Result<(u64, bool)> {
 pub fn flash_images<P: AsRef<Path>, Q: AsRef<Path>>(
     image_path: P,
     device_paths: Vec<Q>,
-    progress: Arc<Mutex<Progress>>
+    progress: Arc<Mutex<Progress>>,
+    options: &FlashOptions,
 ) -> io::Result<()> {
     if device_paths.is_empty() {
         return Err(io::Error::new(io::ErrorKind::InvalidInput, "No device paths provided"));
     }
 
-    // Create writers for all devices
-    let mut writers: Vec<BufWriter<File>> = Vec::new();
     for device_path in &device_paths {
-        let device_file = File::create(device_path)?;
-        writers.push(BufWriter::with_capacity(1024 * 8192, device_file));
+        blockdev::check_target(device_path, options.allow_files, options.overwrite_files)?;
+        blockdev::check_writable(device_path)?;
+        policy::check_device(device_path)?;
+    }
+
+    // a regular file is replaced, so it reads as zeros wherever nothing is written
+    let is_file: Vec<bool> = device_paths
+        .iter()
+        .map(|p| std::fs::metadata(p).map_or(true, |m| m.is_file()))
+        .collect();
+    for (device_path, &is_file) in device_paths.iter().zip(&is_file) {
+        let zeroed = options.discard == DiscardMode::Discard && zeroing::discard_zeroes(device_path);
+        zeroing::resolve(options.zero_strategy, device_path, is_file || zeroed)?;
+    }
+
+    if !options.allow_system_disk {
+        for device_path in &device_paths {
+            protect::check_not_system_disk(device_path)?;
+        }
     }
 
     let (total_size, is_compressed) = get_file_info(&image_path)?;
+    let known_size = if is_compressed { compressed_size_hint(&image_path)? } else { total_size };
+    let image_header = if is_compressed { Vec::new() } else { read_header(&image_path)? };
+    let required_size = partition::required_size(&image_header);
+
+    // Query sizes before anything is opened for writing, which would truncate files
+    let mut capacities = Vec::new();
+    for device_path in &device_paths {
+        let capacity = blockdev::target_capacity(device_path)?;
+        if let Some(capacity) = capacity {
+            if known_size > capacity && required_size.is_none_or(|required| required > capacity) {
+                let trimmed = required_size
+                    .map(|required| format!(", {} without the space past its last partition", required))
+                    .unwrap_or_default();
+                return Err(io::Error::new(io::ErrorKind::StorageFull, format!(
+                    "{} holds {} bytes but the image is {} bytes{}",
+                    device_path.as_ref().display(), capacity, known_size, trimmed
+                )));
+            }
+        }
+        capacities.push(capacity);
+    }
+
+    // the name may have moved to another stick since it was selected, which
+    // has to be caught before anything on it is unmounted or written
+    for device_path in &device_paths {
+        ensure_identity(device_path, &options.pinned)?;
+    }
+
+    // a dry run never unmounts, it only reports mounts that would stop a real flash
+    if !options.dry_run || options.unmount == UnmountPolicy::Refuse {
+        for device_path in &device_paths {
+            mounts::ensure_unmounted(device_path, options.unmount)?;
+        }
+    }
+
+    let mut backups = Vec::new();
+    for (device_path, &is_file) in device_paths.iter().zip(&is_file) {
+        let backup = match &options.backup_dir {
+            Some(backup_dir) if !is_file && !options.dry_run => {
+                Some(backup::backup_device(device_path, backup_dir).map_err(|e| io::Error::new(e.kind(), format!(
+                    "Could not back up the partition table of {}: {}", device_path.as_ref().display(), e
+                )))?)
+            }
+            _ => None,
+        };
+        backups.push(backup);
+    }
+
+    if options.probe_capacity && !options.dry_run {
+        for device_path in &device_paths {
+            ensure_identity(device_path, &options.pinned)?;
+            let report = probe::probe_capacity(device_path, progress.clone())?;
+            if !report.is_genuine() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "{} reports {} bytes but only {} bytes are usable (counterfeit capacity)",
+                    device_path.as_ref().display(), report.advertised_bytes, report.usable_bytes
+                )));
+            }
+        }
+    }
+
+    // a dry run only assumes the discard would zero the device where it is supported
+    let mut discards = Vec::new();
+    let mut zero_strategies = Vec::new();
+    for (device_path, &is_file) in device_paths.iter().zip(&is_file) {
+        let discard = match options.discard {
+            DiscardMode::Off => None,
+            _ if is_file => None,
+            _ if options.dry_run => None,
+            mode => {
+                ensure_identity(device_path, &options.pinned)?;
+                Some(zeroing::discard_device(device_path, mode, &progress).map_err(|e| io::Error::new(e.kind(), format!(
+                    "Could not discard {}: {}", device_path.as_ref().display(), e
+                )))?)
+            }
+        };
+        let zeroed = discard.map_or(
+            options.dry_run && options.discard == DiscardMode::Discard && zeroing::discard_zeroes(device_path),
+            DiscardOutcome::zeroed,
+        );
+        zero_strategies.push(zeroing::resolve(options.zero_strategy, device_path, is_file || zeroed)?);
+        discards.push(discard);
+    }
 
     {
         let mut progress = progress.lock().unwrap();
-        progress.total_bytes = total_size;
+        *progress = Progress::new(total_size);
+        progress.devices = device_paths
+            .iter()
+            .map(|p| DeviceStatus::new(p.as_ref().display().to_string()))
+            .collect();
+        for (((device, backup), zero_strategy), discard) in progress.devices.iter_mut()
+            .zip(backups)
+            .zip(&zero_strategies)
+            .zip(discards)
+        {
+            if let Some(backup) = backup {
+                device.backup = Some(backup.path);
+                device.backup_warning = backup.warning;
+            }
+            device.zero_strategy = Some(*zero_strategy);
+            device.discard = discard;
+            device.sync_policy = Some(options.sync_policy_for(&device.path));
+        }
     }
 
+    // Create writers for all devices
+    let mut writers: Vec<DeviceWriter> = Vec::new();
+    for (index, ((device_path, capacity), zero_strategy)) in device_paths.iter().zip(capacities).zip(zero_strategies).enumerate() {
+        let is_file = std::fs::metadata(device_path).map_or(true, |m| m.is_file());
+        let layout = if options.direct_io && !is_file && !options.dry_run {
+            WriteLayout::direct(device_path, options.write_size)
+        } else {
+            WriteLayout::buffered(options.write_size)
+        };
+        let sync_policy = options.sync_policy_for(&device_path.as_ref().display().to_string());
+        let mut device = DeviceWriter {
+            path: device_path.as_ref().to_path_buf(),
+            writer: None,
+            capacity,
+            truncate: false,
+            zero_strategy,
+            layout,
+            writeback: Writeback::new(sync_policy),
+            throttle: Throttle::new(device_path.as_ref().display().to_string()),
+        };
+
+        // the name may have moved to another stick since it was selected
+        let opened = check_identity(device_path, &options.pinned)
+            .and_then(|()| {
+                open_sink(device_path, options.dry_run, sync_policy == SyncPolicy::OSync)
+                    .and_then(|file| AlignedWriter::new(file, layout))
+                    .map_err(|e| e.to_string())
+            });
+        match opened {
+            Ok(writer) => device.writer = Some(writer),
+            Err(reason) => device.fail(index, reason, &progress),
+        }
+        writers.push(device);
+    }
+    ensure_devices_remain(&writers, &progress)?;
+    allow_truncation(&mut writers, &image_header);
+
     let file = File::open(&image_path)?;
-    let mut reader: Box<dyn Read> = create_reader(&image_path, file)?;
+    let mut reader = HashingReader::new(create_reader(&image_path, file, options.threads)?);
+
+    // one thread reads and decodes the image while every device is written
+    // from its own, so a slow device only holds the others back once its
+    // queue is full
+    thread::scope(|scope| {
+        let mut senders = Vec::new();
+        let mut devices = Vec::new();
+        for (index, device) in writers.into_iter().enumerate() {
+            if device.writer.is_none() {
+                senders.push(None);
+                continue;
+            }
+            let (sender, receiver) = mpsc::sync_channel(options.pipeline.queue_depth());
+            devices.push((index, device, receiver));
+            senders.push(Some(sender));
+        }
 
-    if is_compressed {
-        flash_data_with_header_detection_multi(&mut reader, &mut writers, progress)?;
-    } else {
-        flash_data_multi(&mut reader, &mut writers, progress)?;
-    }
+        let progress = &progress;
+        if options.io_backend == IoBackend::IoUring && uring_available() {
+            #[cfg(target_os = "linux")]
+            scope.spawn(move || uring::run_devices(devices, options.uring_depth, progress, options));
+        } else {
+            for (index, device, receiver) in devices {
+                scope.spawn(move || pipeline::run_writer(device, index, receiver, progress, options));
+            }
+        }
 
-    // Flush and sync all writers
-    for writer in &mut writers {
-        writer.flush()?;
-        writer.get_mut().sync_all()?;
+        pipeline::produce(&mut reader, &mut senders, &image_header, is_compressed, progress)
+    })?;
+
+    progress.lock().unwrap().image_sha256 = Some(reader.hex_digest());
+
+    let failed = progress.lock().unwrap().failed_devices();
+    if !failed.is_empty() {
+        return Err(io::Error::other(format!("Flashing failed for {}", failed.join("; "))));
     }
 
     Ok(())
 }
 
-fn create_reader<P: AsRef<Path>>(image_path: P, file: File) -> io::Result<Box<dyn Read>> {
-    if is_gzipped(&image_path)? {
-        Ok(Box::new(BufReader::with_capacity(1024 * 8192, GzDecoder::new(file))))
-    } else if is_zstd(&image_path)? {
-        let decoder = ZstdDecoder::new(file)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-        Ok(Box::new(BufReader::with_capacity(1024 * 8192, decoder)))
-    } else {
-        Ok(Box::new(BufReader::with_capacity(1024 * 8192, file)))
+#[cfg(target_os = "linux")]
+fn uring_available() -> bool {
+    uring::available()
+}
+
+/// io_uring is Linux only, everything else takes the threaded path.
+#[cfg(not(target_os = "linux"))]
+fn uring_available() -> bool {
+    false
+}
+
+/// Where a target's data goes: the target itself, or a null device on a dry
+/// run. A dry run still opens block devices once, so that a busy one is
+/// reported just like a real flash would.
+fn open_sink<P: AsRef<Path>>(device_path: P, dry_run: bool, synchronous: bool) -> io::Result<File> {
+    if !dry_run {
+        return blockdev::open_target(device_path, synchronous);
+    }
+
+    let is_file = std::fs::metadata(&device_path).map_or(true, |m| m.is_file());
+    if !is_file {
+        drop(blockdev::open_target(&device_path, false)?);
     }
+
+    #[cfg(windows)]
+    let null = "NUL";
+    #[cfg(not(windows))]
+    let null = "/dev/null";
+    std::fs::OpenOptions::new().write(true).open(null)
 }
 
-fn write_buffer_chunk_multi(writers: &mut [BufWriter<File>], chunk: &[u8]) -> io::Result<()> {
-    let is_all_zeros = chunk.iter().all(|&b| b == 0);
+/// Make sure a target is still the device it was when it was selected.
+pub fn check_identity<P: AsRef<Path>>(
+    device_path: P,
+    pinned: &HashMap<String, DeviceIdentity>,
+) -> Result<(), String> {
+    let path = device_path.as_ref().display().to_string();
+    let Some(expected) = pinned.get(&path) else {
+        return Ok(());
+    };
 
-    for writer in writers.iter_mut() {
-        if is_all_zeros {
-            // For all-zero blocks, seek forward instead of writing
-            writer.seek(SeekFrom::Current(chunk.len() as i64))?;
-            writer.flush()?;
-        } else {
-            writer.write_all(chunk)?;
-        }
+    match blockdev::device_identity(&device_path) {
+        Ok(identity) if &identity == expected => Ok(()),
+        Ok(identity) => Err(format!(
+            "device changed since it was selected (was {}, now {})",
+            expected.describe(), identity.describe()
+        )),
+        Err(e) => Err(format!("device changed since it was selected ({}: {})", expected.describe(), e)),
     }
-    Ok(())
 }
 
-fn flash_data_multi(
-    reader: &mut Box<dyn Read>,
-    writers: &mut [BufWriter<File>],
-    progress: Arc<Mutex<Progress>>,
-) -> io::Result<()> {
-    let mut buffer = vec![0; 1024 * 1024]; // 1MB buffer
-    let mut sync_data = 0u64;
+/// `check_identity` for a step that cannot just drop the device, failing the
+/// whole flash instead.
+fn ensure_identity<P: AsRef<Path>>(device_path: P, pinned: &HashMap<String, DeviceIdentity>) -> io::Result<()> {
+    check_identity(&device_path, pinned).map_err(|reason| io::Error::new(io::ErrorKind::InvalidInput, format!(
+        "{}: {}", device_path.as_ref().display(), reason
+    )))
+}
+
+/// The start of a raw image, enough to hold its partition table.
+fn read_header<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
+    let mut header = Vec::new();
+    File::open(&path)?.take(65536).read_to_end(&mut header)?;
+    Ok(header)
+}
 
-    loop {
-        let bytes_read = reader.read(&mut buffer)?;
-        if bytes_read == 0 {
-            break;
+/// Let devices that are too small for the whole image take it anyway when
+/// only trailing space past the last partition would be lost.
+fn allow_truncation(writers: &mut [DeviceWriter], image_header: &[u8]) {
+    if let Some(required) = partition::required_size(image_header) {
+        for device in writers.iter_mut() {
+            device.truncate = device.capacity.is_some_and(|capacity| capacity >= required);
         }
+    }
+}
 
-        write_buffer_chunk_multi(writers, &buffer[..bytes_read])?;
+/// A lower bound on the decoded size of a compressed image that is known
+/// without decompressing it: the content sizes recorded in the zstd frame
+/// headers, when the encoder wrote them, or the sizes in the xz index.
+fn compressed_size_hint<P: AsRef<Path>>(path: P) -> io::Result<u64> {
+    if is_zstd(&path)? {
+        Ok(ZstdFrames::read(File::open(&path)?).map_or(0, |frames| frames.content_size()))
+    } else if is_xz(&path)? {
+        Ok(XzBlocks::read(File::open(&path)?).map_or(0, |blocks| blocks.uncompressed_size()))
+    } else {
+        Ok(0)
+    }
+}
 
-        {
-            let mut progress = progress.lock().unwrap();
-            progress.bytes_written += bytes_read as u64;
-        }
+/// Passes the decoded image through while computing its SHA-256.
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    /// Zeros of skipped holes not hashed yet. They are hashed on the next
+    /// read, once the hole is on its way to the devices.
+    pending_zeros: u64,
+}
 
-        sync_data += bytes_read as u64;
-        if sync_data >= 1024 * 1024 * 32 {
-            for writer in writers.iter_mut() {
-                writer.flush()?;
-                writer.get_mut().sync_data()?;
-            }
-            sync_data = 0;
-        }
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        HashingReader { inner, hasher: Sha256::new(), pending_zeros: 0 }
     }
 
-    Ok(())
+    pub fn hex_digest(&self) -> String {
+        let mut hasher = self.hasher.clone();
+        hash_zeros(&mut hasher, self.pending_zeros);
+        hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
 }
 
-fn flash_data_with_header_detection_multi(
-    reader: &mut Box<dyn Read>,
-    writers: &mut [BufWriter<File>],
-    progress: Arc<Mutex<Progress>>,
-) -> io::Result<()> {
-    let mut buffer = vec![0; 1024 * 1024]; // 1MB buffer
-    let mut sync_data = 0u64;
-    let mut total_written = 0u64;
-    let mut header_buffer = Vec::new();
-    let mut size_determined = false;
-
-    loop {
-        let bytes_read = reader.read(&mut buffer)?;
-        if bytes_read == 0 {
-            break;
-        }
-
-        // Accumulate header data until we can determine the size or reach 64KB
-        if !size_determined && header_buffer.len() < 65536 {
-            let bytes_to_add = (65536 - header_buffer.len()).min(bytes_read);
-            header_buffer.extend_from_slice(&buffer[..bytes_to_add]);
-
-            // 1024 bytes should be enough to check for MBR/GPT
-            if header_buffer.len() >= 1024 {
-                let img_size = get_img_size_from_header(&header_buffer);
-                if img_size > 0 {
-                    {
-                        let mut progress = progress.lock().unwrap();
-                        progress.total_bytes = img_size;
-                    }
-                    size_determined = true;
-                }
-            }
-        }
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        hash_zeros(&mut self.hasher, std::mem::take(&mut self.pending_zeros));
+        let bytes_read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..bytes_read]);
+        Ok(bytes_read)
+    }
+}
 
-        write_buffer_chunk_multi(writers, &buffer[..bytes_read])?;
-        total_written += bytes_read as u64;
+impl<R: ImageRead> ImageRead for HashingReader<R> {
+    fn skip_hole(&mut self) -> io::Result<u64> {
+        let skipped = self.inner.skip_hole()?;
+        self.pending_zeros += skipped;
+        Ok(skipped)
+    }
 
-        {
-            let mut progress = progress.lock().unwrap();
-            progress.bytes_written = total_written;
+    fn data_ahead(&mut self) -> io::Result<u64> {
+        self.inner.data_ahead()
+    }
+}
 
-            // If we haven't determined the size yet, use streaming-style progress
-            if !size_determined {
-                progress.total_bytes = total_written + (total_written / 4).max(1024 * 1024);
-            }
-        }
+fn hash_zeros(hasher: &mut Sha256, mut len: u64) {
+    static ZEROS: [u8; 65536] = [0; 65536];
+    while len > 0 {
+        let n = len.min(ZEROS.len() as u64) as usize;
+        hasher.update(&ZEROS[..n]);
+        len -= n as u64;
+    }
+}
 
-        sync_data += bytes_read as u64;
-        if sync_data >= 1024 * 1024 * 32 { // 32MB
-            for writer in writers.iter_mut() {
-                writer.flush()?;
-                writer.get_mut().sync_data()?;
+/// Open the image for decoding on `threads` threads, or one per core for 0.
+/// Gzip images large enough to split up are decoded on several threads, the
+/// rest by flate2. Zstd frames and xz blocks are decoded on several threads
+/// when the image has more than one; where the frames or blocks cannot be told apart, the
+/// image is decoded sequentially. Raw images in regular files have their holes
+/// skipped, anything else is read straight through.
+pub fn create_reader<P: AsRef<Path>>(image_path: P, file: File, threads: usize) -> io::Result<Box<dyn ImageRead>> {
+    let threads = parallel::thread_count(threads);
+    if is_gzipped(&image_path)? {
+        if threads > 1 && gzip::worth_splitting(&file)? {
+            return Ok(Box::new(ParallelGzDecoder::new(file, threads)?));
+        }
+        Ok(Box::new(BufReader::with_capacity(1024 * 8192, MultiGzDecoder::new(file))))
+    } else if is_zstd(&image_path)? {
+        if threads > 1 {
+            if let Some(frames) = ZstdFrames::read(file.try_clone()?).ok().filter(|frames| frames.len() > 1) {
+                return Ok(Box::new(SegmentDecoder::new(frames, threads)));
             }
-            sync_data = 0;
         }
-    }
-
-    {
-        let mut progress = progress.lock().unwrap();
-        if !size_determined {
-            progress.total_bytes = total_written;
+        let mut decoder = ZstdDecoder::new(file)
+            .map_err(io::Error::other)?;
+        decoder.window_log_max(zst::WINDOW_LOG_MAX)?;
+        Ok(Box::new(BufReader::with_capacity(1024 * 8192, decoder)))
+    } else if is_xz(&image_path)? {
+        if threads > 1 {
+            if let Some(blocks) = XzBlocks::read(file.try_clone()?).ok().filter(|blocks| blocks.len() > 1) {
+                return Ok(Box::new(SegmentDecoder::new(blocks, threads)));
+            }
         }
-        progress.bytes_written = total_written;
+        let decoder = XzDecoder::new_multi_decoder(BufReader::with_capacity(1024 * 1024, file));
+        Ok(Box::new(BufReader::with_capacity(1024 * 8192, decoder)))
+    } else if file.metadata()?.is_file() {
+        Ok(Box::new(SparseFile::new(file)?))
+    } else {
+        // a block device or pipe has no length or holes to go by
+        Ok(Box::new(BufReader::with_capacity(1024 * 8192, file)))
     }
+}
 
-    Ok(())
+/// Whether a piece of the image is all zeros. It is checked 64 bytes at a
+/// time, which the compiler turns into vector instructions.
+pub fn is_zero_chunk(chunk: &[u8]) -> bool {
+    let mut blocks = chunk.chunks_exact(64);
+    blocks.all(|block| block.iter().fold(0, |acc, &b| acc | b) == 0)
+        && blocks.remainder().iter().all(|&b| b == 0)
 }
 
+fn ensure_devices_remain(writers: &[DeviceWriter], progress: &Arc<Mutex<Progress>>) -> io::Result<()> {
+    if writers.iter().any(|d| d.writer.is_some()) {
+        return Ok(());
+    }
+    Err(all_devices_failed(progress))
+}
 
+pub fn all_devices_failed(progress: &Arc<Mutex<Progress>>) -> io::Error {
+    let failed = progress.lock().unwrap().failed_devices();
+    io::Error::other(format!("All devices failed: {}", failed.join("; ")))
+}
 
 #[derive(Debug, Clone)]
 pub struct DeviceInfo {
@@ -330,13 +1047,22 @@ pub struct DeviceInfo {
     pub name: String,
     pub size: String,
     pub device_type: String,
+    pub removable: bool,
+    /// Write-protected by a lock switch or read-only flag
+    pub read_only: bool,
+    /// Why the device is protected from being overwritten, if it is
+    pub protected: Option<String>,
+    /// Why the device policy forbids writing the device, if it does
+    pub disallowed: Option<String>,
+    /// Serial, WWN and exact size, for pinning the device once it is selected
+    pub identity: Option<DeviceIdentity>,
 }
 
 impl DeviceInfo {
     pub fn display_name(&self) -> String {
         if self.name.is_empty() || self.name == "Unknown Device" {
             if self.size == "Unknown" {
-                format!("{}", self.path)
+                self.path.to_string()
             } else {
                 format!("{} ({})", self.path, self.size)
             }
@@ -352,17 +1078,44 @@ impl DeviceInfo {
 
 pub fn enumerate_devices() -> Vec<DeviceInfo> {
     #[cfg(target_os = "linux")]
-    {
-        enumerate_linux_devices()
-    }
+    let mut devices = enumerate_linux_devices();
     #[cfg(target_os = "macos")]
-    {
-        enumerate_macos_devices()
-    }
+    let mut devices = enumerate_macos_devices();
     #[cfg(not(any(target_os = "linux", target_os = "macos")))]
-    {
-        Vec::new()
+    let mut devices = Vec::new();
+
+    policy::apply(&mut devices);
+    devices
+}
+
+/// Details of any target, taken from the device list when it is on it.
+pub fn device_info(path: &str) -> DeviceInfo {
+    if let Some(device) = enumerate_devices().into_iter().find(|d| d.path == path) {
+        return device;
     }
+
+    let identity = blockdev::device_identity(path).ok();
+    let mut device = DeviceInfo {
+        path: path.to_string(),
+        name: identity
+            .as_ref()
+            .and_then(|id| id.model.clone())
+            .unwrap_or_else(|| "Unknown Device".to_string()),
+        size: identity
+            .as_ref()
+            .map_or("Unknown".to_string(), |id| format_bytes_to_human_readable(id.size)),
+        device_type: "Disk".to_string(),
+        removable: false,
+        read_only: blockdev::is_read_only(path),
+        protected: protect::protected_disks()
+            .into_iter()
+            .find(|(disk, _)| blockdev::disks_for_path(path).contains(disk))
+            .map(|(_, reason)| reason),
+        disallowed: None,
+        identity,
+    };
+    policy::apply(std::slice::from_mut(&mut device));
+    device
 }
 
 #[cfg(target_os = "linux")]
@@ -378,7 +1131,7 @@ fn enumerate_linux_devices() -> Vec<DeviceInfo> {
 #[cfg(target_os = "linux")]
 fn try_enumerate_with_lsblk() -> Option<Vec<DeviceInfo>> {
     let output = Command::new("lsblk")
-        .args(&["-J", "-o", "NAME,SIZE,TYPE,MODEL,MOUNTPOINT,VENDOR,SERIAL,HOTPLUG,RM"])
+        .args(["-J", "-o", "NAME,SIZE,TYPE,MODEL,MOUNTPOINT,VENDOR,SERIAL,HOTPLUG,RM"])
         .output()
         .ok()?;
 
@@ -390,9 +1143,10 @@ fn try_enumerate_with_lsblk() -> Option<Vec<DeviceInfo>> {
     let json: serde_json::Value = serde_json::from_str(&json_str).ok()?;
     let blockdevices = json["blockdevices"].as_array()?;
 
+    let protected = protect::protected_disks();
     let mut devices = Vec::new();
     for device in blockdevices {
-        if let Some(device_info) = parse_lsblk_device(device) {
+        if let Some(device_info) = parse_lsblk_device(device, &protected) {
             devices.push(device_info);
         }
     }
@@ -401,7 +1155,10 @@ fn try_enumerate_with_lsblk() -> Option<Vec<DeviceInfo>> {
 }
 
 #[cfg(target_os = "linux")]
-fn parse_lsblk_device(device: &serde_json::Value) -> Option<DeviceInfo> {
+fn parse_lsblk_device(
+    device: &serde_json::Value,
+    protected: &std::collections::HashMap<String, String>,
+) -> Option<DeviceInfo> {
     let name = device["name"].as_str()?;
     let size = device["size"].as_str()?;
     let device_type = device["type"].as_str()?;
@@ -439,6 +1196,11 @@ fn parse_lsblk_device(device: &serde_json::Value) -> Option<DeviceInfo> {
         name: device_name,
         size: format_size(size),
         device_type: if is_removable { "Removable" } else { "Disk" }.to_string(),
+        removable: is_removable,
+        read_only: blockdev::is_read_only(format!("/dev/{}", name)),
+        protected: protected.get(name).cloned(),
+        disallowed: None,
+        identity: blockdev::device_identity(format!("/dev/{}", name)).ok(),
     })
 }
 
@@ -481,6 +1243,7 @@ fn format_size(size: &str) -> String {
 
 #[cfg(target_os = "linux")]
 fn enumerate_fallback_devices() -> Vec<DeviceInfo> {
+    let protected = protect::protected_disks();
     let dev_dir = std::fs::read_dir("/dev").unwrap_or_else(|_| std::fs::read_dir(".").unwrap());
 
     dev_dir
@@ -504,6 +1267,13 @@ fn enumerate_fallback_devices() -> Vec<DeviceInfo> {
                     name: device_name.to_string(),
                     size,
                     device_type: "Removable".to_string(),
+                    removable: std::fs::read_to_string(format!("/sys/block/{}/removable", name))
+                        .map(|s| s.trim() == "1")
+                        .unwrap_or(false),
+                    read_only: blockdev::is_read_only(format!("/dev/{}", name)),
+                    protected: protected.get(&name).cloned(),
+                    disallowed: None,
+                    identity: blockdev::device_identity(format!("/dev/{}", name)).ok(),
                 })
             } else {
                 None
@@ -526,8 +1296,7 @@ fn get_device_size_from_sys(device_name: &str) -> String {
     "Unknown".to_string()
 }
 
-#[cfg(target_os = "linux")]
-fn format_bytes_to_human_readable(bytes: u64) -> String {
+pub fn format_bytes_to_human_readable(bytes: u64) -> String {
     const UNITS: &[&str] = &["B", "KB", "MB", "GB", "TB"];
     const THRESHOLD: u64 = 1024;
 
@@ -586,6 +1355,7 @@ fn get_macos_device_info(disk_name: &str) -> Option<DeviceInfo> {
     let mut size = "Unknown".to_string();
     let mut is_removable = false;
     let mut is_external = false;
+    let mut read_only = false;
 
     for info_line in info_str.lines() {
         let line = info_line.trim();
@@ -607,6 +1377,8 @@ fn get_macos_device_info(disk_name: &str) -> Option<DeviceInfo> {
                     size = format!("{} {}", size_parts[0], size_parts[1]);
                 }
             }
+        } else if line.starts_with("Media Read-Only:") || line.starts_with("Read-Only Media:") {
+            read_only = read_only || line.contains("Yes");
         } else if line.starts_with("Removable Media:") {
             is_removable = line.contains("Yes");
         } else if line.starts_with("Protocol:") {
@@ -628,11 +1400,18 @@ fn get_macos_device_info(disk_name: &str) -> Option<DeviceInfo> {
         build_macos_device_name(disk_name, is_external)
     };
 
+    let protected = protect::protected_disks();
+
     Some(DeviceInfo {
         path: disk_name.to_string(),
         name: final_name,
         size,
         device_type: if is_removable { "Removable" } else { "External" }.to_string(),
+        removable: is_removable,
+        read_only,
+        protected: protected.get(disk_name.trim_start_matches("/dev/")).cloned(),
+        disallowed: None,
+        identity: blockdev::device_identity(disk_name).ok(),
     })
 }
 
@@ -648,3 +1427,101 @@ fn build_macos_device_name(disk_name: &str, is_external: bool) -> String {
         "Unknown Device".to_string()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Noise, TempFile};
+
+    fn file_options() -> FlashOptions {
+        FlashOptions { allow_files: true, overwrite_files: true, write_size: 1024 * 1024, ..Default::default() }
+    }
+
+    #[test]
+    fn a_changed_device_is_refused_before_anything_is_touched() {
+        let image = TempFile::new(&Noise(1).bytes(64 * 1024));
+        let target = TempFile::new(b"previous contents");
+        let identity = blockdev::device_identity(&target.path).unwrap();
+        let options = FlashOptions {
+            discard: DiscardMode::Discard,
+            pinned: HashMap::from([(
+                target.path.display().to_string(),
+                DeviceIdentity { serial: Some("4C530001".to_string()), ..identity },
+            )]),
+            ..file_options()
+        };
+
+        let progress = Arc::new(Mutex::new(Progress::new(0)));
+        let error = flash_images(&image.path, vec![&target.path], progress.clone(), &options).unwrap_err();
+        assert!(error.to_string().contains("device changed since it was selected"), "{}", error);
+        // refused before any step that goes near the device, not once it is opened
+        assert!(progress.lock().unwrap().devices.is_empty());
+        assert_eq!(std::fs::read(&target.path).unwrap(), b"previous contents");
+    }
+
+    #[test]
+    fn a_missing_target_is_only_created_when_files_are_allowed() {
+        let image = TempFile::new(&Noise(3).bytes(64 * 1024));
+        let missing = image.path.with_extension("missing");
+
+        let error = blockdev::check_target(&missing, false, false).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(error.to_string().contains("--allow-file"), "{}", error);
+        assert!(blockdev::check_target(&missing, true, false).is_ok());
+
+        // a mistyped device path must not leave a stray file behind
+        let options = FlashOptions { allow_files: false, overwrite_files: false, ..file_options() };
+        let progress = Arc::new(Mutex::new(Progress::new(0)));
+        assert!(flash_images(&image.path, vec![&missing], progress, &options).is_err());
+        assert!(!missing.exists());
+    }
+
+    #[test]
+    fn an_existing_file_is_only_replaced_when_asked_to() {
+        let target = TempFile::new(b"previous contents");
+
+        let error = blockdev::check_target(&target.path, false, false).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(error.to_string().contains("--allow-file"), "{}", error);
+
+        let error = blockdev::check_target(&target.path, true, false).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert!(error.to_string().contains("--overwrite-file"), "{}", error);
+
+        assert!(blockdev::check_target(&target.path, true, true).is_ok());
+        // overwriting implies files are allowed
+        assert!(blockdev::check_target(&target.path, false, true).is_ok());
+        assert_eq!(std::fs::read(&target.path).unwrap(), b"previous contents");
+    }
+
+    #[test]
+    fn targets_that_are_neither_devices_nor_files_are_refused() {
+        let mut paths = vec![std::env::temp_dir()];
+        // macOS flashes raw disks through character devices, so only a directory is refused there
+        if cfg!(target_os = "linux") {
+            paths.push(PathBuf::from("/dev/null"));
+        }
+        for path in paths {
+            let error = blockdev::check_target(&path, true, true).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+            assert!(error.to_string().contains("neither a block device nor a regular file"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn raw_images_that_are_not_files_are_read_through() {
+        // a device or pipe reports no length, so it cannot be read by extent
+        let mut reader = create_reader("/dev/zero", File::open("/dev/zero").unwrap(), 1).unwrap();
+        assert_eq!(reader.skip_hole().unwrap(), 0);
+        let mut image = Vec::new();
+        reader.take(3 * 1024 * 1024).read_to_end(&mut image).unwrap();
+        assert_eq!(image.len(), 3 * 1024 * 1024);
+        assert!(is_zero_chunk(&image));
+
+        let contents = Noise(2).bytes(100_000);
+        let file = TempFile::new(&contents);
+        let mut image = Vec::new();
+        create_reader(&file.path, file.open(), 1).unwrap().read_to_end(&mut image).unwrap();
+        assert!(image == contents);
+    }
+}
@@ -1,7 +1,18 @@
+use clap::ValueEnum;
 use eframe::egui;
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
+use crate::blockdev::DeviceIdentity;
 use crate::fs::{DeviceInfo, Progress};
+use crate::mounts::{self, Mount, UnmountPolicy};
+use crate::pipeline::{IoBackend, PipelineLimits};
+use crate::ratelimit::{self, RateLimits};
+use crate::recovery::{BadSectorPolicy, RetryPolicy};
+use crate::writeback::SyncPolicy;
+use crate::zeroing::{DiscardMode, ZeroStrategy};
 use crate::{Args, fs};
 
 // Ferris SVG asset, curtosy of https://rustacean.net/
@@ -20,19 +31,57 @@ struct State {
     device_paths: Vec<String>,
     flashing_state: FlashingState,
     progress: Arc<Mutex<Progress>>,
-    error_message: Option<&'static str>,
+    flash_result: Arc<Mutex<Option<Result<(), String>>>>,
+    error_message: Option<String>,
     success_message: Option<String>,
     available_devices: Vec<DeviceInfo>,
     selected_device_indices: Vec<usize>,
+    /// What each selected device was when it was picked from the list, so a
+    /// stick swapped in under the same name is not overwritten
+    pinned: HashMap<String, DeviceIdentity>,
     refresh_devices: bool,
     completed_time: Option<u64>,
+    probe_capacity: bool,
+    retry: RetryPolicy,
+    allow_system_disk: bool,
+    allow_files: bool,
+    overwrite_files: bool,
+    dry_run: bool,
+    backup_dir: Option<PathBuf>,
+    zero_strategy: ZeroStrategy,
+    discard: DiscardMode,
+    pipeline: PipelineLimits,
+    direct_io: bool,
+    write_size: usize,
+    zero_granularity: usize,
+    io_backend: IoBackend,
+    uring_depth: usize,
+    threads: usize,
+    sync_policy: SyncPolicy,
+    device_sync_policies: HashMap<String, SyncPolicy>,
+    /// Shared with the writers, so changing them takes effect mid-flash
+    rate_limits: Arc<RateLimits>,
+    unmount: UnmountPolicy,
+    mounted: Vec<Mount>,
+    last_mount_check: Option<Instant>,
 }
 
 impl State {
     fn new(args: Args) -> Self {
         let available_devices = fs::enumerate_devices();
+        let retry = args.retry_policy();
+        let unmount = args.unmount_policy();
+        let backup_dir = args.backup_dir();
+        let discard = args.discard_mode();
+        let pipeline = args.pipeline_limits();
+        let device_sync_policies = args.device_sync_policies();
+        let rate_limits = Arc::new(args.rate_limits());
+        let mut pinned = HashMap::new();
         let (device_paths, selected_device_indices) = if !args.device_path.is_empty() {
             if let Some(index) = available_devices.iter().position(|d| d.path == args.device_path) {
+                if let Some(ref identity) = available_devices[index].identity {
+                    pinned.insert(args.device_path.clone(), identity.clone());
+                }
                 (vec![args.device_path], vec![index])
             } else {
                 (vec![args.device_path], vec![])
@@ -46,18 +95,42 @@ impl State {
             device_paths,
             flashing_state: FlashingState::Idle,
             progress: Arc::new(Mutex::new(Progress::new(0))),
+            flash_result: Arc::new(Mutex::new(None)),
             error_message: None,
             success_message: None,
             available_devices,
             selected_device_indices,
+            pinned,
             refresh_devices: false,
             completed_time: None,
+            probe_capacity: args.probe_capacity,
+            retry,
+            allow_system_disk: args.allow_system_disk,
+            allow_files: args.allow_file,
+            overwrite_files: args.overwrite_file,
+            dry_run: args.dry_run,
+            backup_dir,
+            zero_strategy: args.zero_blocks,
+            discard,
+            pipeline,
+            direct_io: !args.no_direct_io,
+            write_size: args.write_size * 1024,
+            zero_granularity: args.zero_granularity.max(1) * 1024,
+            io_backend: args.io_backend,
+            uring_depth: args.uring_depth,
+            threads: args.threads,
+            sync_policy: args.sync_policy,
+            device_sync_policies,
+            rate_limits,
+            unmount,
+            mounted: Vec::new(),
+            last_mount_check: None,
         }
     }
 
     fn start_flashing(&mut self) {
         if self.image_path.is_empty() || self.device_paths.is_empty() {
-            self.error_message = Some("Please select both image and device paths");
+            self.error_message = Some("Please select both image and device paths".to_string());
             return;
         }
 
@@ -69,15 +142,42 @@ impl State {
         let image_path = self.image_path.clone();
         let device_paths = self.device_paths.clone();
         let progress = Arc::clone(&self.progress);
+        let flash_result = Arc::clone(&self.flash_result);
+        let options = fs::FlashOptions {
+            probe_capacity: self.probe_capacity,
+            retry: self.retry.clone(),
+            allow_system_disk: self.allow_system_disk,
+            unmount: self.unmount,
+            pinned: self.pinned.clone(),
+            allow_files: self.allow_files,
+            overwrite_files: self.overwrite_files,
+            dry_run: self.dry_run,
+            backup_dir: self.backup_dir.clone(),
+            zero_strategy: self.zero_strategy,
+            discard: self.discard,
+            pipeline: self.pipeline,
+            direct_io: self.direct_io,
+            write_size: self.write_size,
+            zero_granularity: self.zero_granularity,
+            io_backend: self.io_backend,
+            uring_depth: self.uring_depth,
+            threads: self.threads,
+            sync_policy: self.sync_policy,
+            device_sync_policies: self.device_sync_policies.clone(),
+            rate_limits: Arc::clone(&self.rate_limits),
+        };
 
         thread::spawn(move || {
             // Flash to all devices simultaneously
-            let result = fs::flash_images(&image_path, device_paths, progress.clone());
+            let result = fs::flash_images(&image_path, device_paths, progress.clone(), &options);
             if result.is_err() {
                 if let Ok(mut progress_guard) = progress.lock() {
                     *progress_guard = Progress::new(0);
                 }
             }
+            if let Ok(mut flash_result) = flash_result.lock() {
+                *flash_result = Some(result.map_err(|e| e.to_string()));
+            }
         });
     }
 }
@@ -86,20 +186,71 @@ impl eframe::App for State {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         // Check flashing progress
         if self.flashing_state == FlashingState::InProgress {
-            if let Ok(progress) = self.progress.lock() {
-                if progress.get_progress() >= 1.0 {
+            let result = self.flash_result.lock().ok().and_then(|mut r| r.take());
+            match result {
+                Some(Ok(())) => {
                     self.flashing_state = FlashingState::Completed;
-                    let elapsed = progress.get_elapsed_time().as_secs();
+                    let (elapsed, failed_sectors) = self.progress.lock()
+                        .map(|p| (
+                            p.get_elapsed_time().as_secs(),
+                            p.devices.iter().map(|d| d.failed_sectors()).sum::<u64>(),
+                        ))
+                        .unwrap_or((0, 0));
                     self.completed_time = Some(elapsed); // Store the completion time
-                    self.success_message = Some(format!(
-                        "Flashing completed in {:.1}s!",
-                        elapsed as f32
-                    ));
+                    self.success_message = Some(if self.dry_run {
+                        format!("Dry run completed in {:.1}s, nothing was written.", elapsed as f32)
+                    } else if failed_sectors > 0 {
+                        format!(
+                            "Flashing completed in {:.1}s, but {} sector(s) could not be written!",
+                            elapsed as f32, failed_sectors
+                        )
+                    } else {
+                        format!("Flashing completed in {:.1}s!", elapsed as f32)
+                    });
+                    if let (Some(ref backup_dir), false) = (&self.backup_dir, self.dry_run) {
+                        if let Some(ref mut message) = self.success_message {
+                            message.push_str(&format!("\nPrevious partition tables saved in {}", backup_dir.display()));
+                        }
+                    }
+                    if let (Ok(progress), Some(message)) = (self.progress.lock(), self.success_message.as_mut()) {
+                        for device in &progress.devices {
+                            if let Some(discard) = device.discard {
+                                message.push_str(&format!("\n{} was {}", device.path, discard));
+                            }
+                            if let Some(zero_strategy) = device.zero_strategy {
+                                message.push_str(&format!("\nZero blocks on {} were {}", device.path, zero_strategy));
+                            }
+                            if let Some(sync_policy) = device.sync_policy {
+                                message.push_str(&format!(
+                                    "\n{} was {}, {} sync(s) took {:.1}s",
+                                    device.path, sync_policy, device.syncs, device.sync_time.as_secs_f32()
+                                ));
+                            }
+                        }
+                    }
                 }
+                Some(Err(e)) => {
+                    self.flashing_state = FlashingState::Error;
+                    self.error_message = Some(e);
+                }
+                None => {}
             }
             ctx.request_repaint_after(std::time::Duration::from_millis(100));
         }
 
+        // Desktops auto-mount sticks shortly after they are plugged in, so keep checking
+        if self.flashing_state == FlashingState::Idle && !self.device_paths.is_empty() {
+            if self.last_mount_check.is_none_or(|t| t.elapsed() >= Duration::from_secs(1)) {
+                self.mounted = self.device_paths
+                    .iter()
+                    .filter(|p| !p.is_empty())
+                    .flat_map(mounts::mounted_partitions)
+                    .collect();
+                self.last_mount_check = Some(Instant::now());
+            }
+            ctx.request_repaint_after(Duration::from_secs(1));
+        }
+
         egui::CentralPanel::default().show(ctx, |ui| {
             // Refresh devices if needed
             if self.refresh_devices {
@@ -178,7 +329,7 @@ impl eframe::App for State {
 
                             if ui.add_sized([75.0, 25.0], egui::Button::new("Browse")).clicked() {
                                 if let Some(path) = rfd::FileDialog::new()
-                                    .add_filter("Image files", &["img", "iso", "gz", "zst"])
+                                    .add_filter("Image files", &["img", "iso", "gz", "xz", "zst"])
                                     .pick_file()
                                 {
                                     self.image_path = path.display().to_string();
@@ -251,10 +402,19 @@ impl eframe::App for State {
                                 });
 
                             if let Some(i) = to_remove {
-                                self.device_paths.remove(i);
+                                let removed = self.device_paths.remove(i);
+                                self.pinned.remove(&removed);
                                 if i < self.selected_device_indices.len() {
                                     self.selected_device_indices.remove(i);
                                 }
+                                self.last_mount_check = None;
+                            }
+
+                            for mount in &self.mounted {
+                                ui.colored_label(
+                                    egui::Color32::YELLOW,
+                                    format!("⚠ {} is mounted on {}", mount.source, mount.mount_point),
+                                );
                             }
                             ui.add_space(5.0);
                         }
@@ -268,26 +428,47 @@ impl eframe::App for State {
                                 .show_ui(ui, |ui| {
                                     for (i, device) in self.available_devices.iter().enumerate() {
                                         let display_name = device.display_name();
-                                        let hover_text = format!(
+                                        let mut hover_text = format!(
                                             "Device: {}\nPath: {}\nSize: {}\nType: {}",
                                             device.name,
                                             device.path,
                                             device.size,
                                             device.device_type
                                         );
+                                        if let Some(serial) = device.identity.as_ref().and_then(|id| id.serial.as_ref()) {
+                                            hover_text.push_str(&format!("\nSerial: {}", serial));
+                                        }
+                                        if let Some(ref reason) = device.protected {
+                                            hover_text.push_str(&format!("\nSystem disk: {}", reason));
+                                        }
+                                        if device.read_only {
+                                            hover_text.push_str("\nWrite-protected: check the lock switch or read-only flag");
+                                        }
+                                        if let Some(ref reason) = device.disallowed {
+                                            hover_text.push_str(&format!("\nPolicy: {}", reason));
+                                        }
+                                        let selectable = (device.protected.is_none() || self.allow_system_disk)
+                                            && !device.read_only
+                                            && device.disallowed.is_none();
 
                                         // Check if already selected
                                         let already_selected = self.device_paths.contains(&device.path);
                                         let label = if already_selected {
                                             format!("✓ {}", display_name)
+                                        } else if device.protected.is_some() || device.disallowed.is_some() {
+                                            format!("🔒 {}", display_name)
+                                        } else if device.read_only {
+                                            format!("{} (write-protected)", display_name)
                                         } else {
                                             display_name
                                         };
 
-                                        if ui.selectable_label(false, &label).on_hover_text(hover_text).clicked() {
-                                            if !already_selected {
-                                                self.device_paths.push(device.path.clone());
-                                                self.selected_device_indices.push(i);
+                                        let response = ui.add_enabled(selectable, egui::Button::selectable(false, &label));
+                                        if response.on_hover_text(hover_text.as_str()).on_disabled_hover_text(hover_text.as_str()).clicked() && !already_selected {
+                                            self.device_paths.push(device.path.clone());
+                                            self.selected_device_indices.push(i);
+                                            if let Some(ref identity) = device.identity {
+                                                self.pinned.insert(device.path.clone(), identity.clone());
                                             }
                                         }
                                     }
@@ -300,7 +481,7 @@ impl eframe::App for State {
                         });
 
                         // Show custom path input if last device is empty
-                        let should_show_custom = self.device_paths.last().map_or(false, |p| p.is_empty());
+                        let should_show_custom = self.device_paths.last().is_some_and(|p| p.is_empty());
                         if should_show_custom {
                             ui.add_space(3.0);
                             let mut remove_last = false;
@@ -330,7 +511,84 @@ impl eframe::App for State {
                     });
                 });
 
-                ui.add_space(15.0);
+                ui.add_space(5.0);
+
+                ui.checkbox(&mut self.dry_run, "Dry run")
+                    .on_hover_text("Run every check and decode the whole image, but write nothing to the devices");
+
+                ui.checkbox(&mut self.direct_io, "Bypass the page cache")
+                    .on_hover_text("Write block devices with O_DIRECT in aligned pieces, so flashing does not fill RAM with the image");
+
+                ui.checkbox(&mut self.probe_capacity, "Check for fake capacity before flashing")
+                    .on_hover_text("Writes and reads back test blocks across each device to detect counterfeit flash");
+
+                let mut unmount = self.unmount != UnmountPolicy::Refuse;
+                if ui.checkbox(&mut unmount, "Unmount mounted partitions before flashing").changed() {
+                    self.unmount = if unmount { UnmountPolicy::Unmount } else { UnmountPolicy::Refuse };
+                }
+
+                let mut skip_bad_sectors = self.retry.on_bad_sector == BadSectorPolicy::Continue;
+                if ui.checkbox(&mut skip_bad_sectors, "Skip sectors that cannot be written")
+                    .on_hover_text("Keep flashing past bad sectors and report them, instead of failing the device")
+                    .changed()
+                {
+                    self.retry.on_bad_sector = if skip_bad_sectors {
+                        BadSectorPolicy::Continue
+                    } else {
+                        BadSectorPolicy::Fail
+                    };
+                }
+
+                egui::ComboBox::from_label("Zero blocks")
+                    .selected_text(zero_strategy_name(self.zero_strategy))
+                    .show_ui(ui, |ui| {
+                        for &strategy in ZeroStrategy::value_variants() {
+                            ui.selectable_value(&mut self.zero_strategy, strategy, zero_strategy_name(strategy));
+                        }
+                    })
+                    .response
+                    .on_hover_text("How runs of zeros in the image reach the devices. Auto always leaves real zeros behind");
+
+                ui.horizontal(|ui| {
+                    let mut discard = self.discard != DiscardMode::Off;
+                    if ui.checkbox(&mut discard, "Discard devices before flashing")
+                        .on_hover_text("Trim the whole device first, which speeds up writes to flash media")
+                        .changed()
+                    {
+                        self.discard = if discard { DiscardMode::Discard } else { DiscardMode::Off };
+                    }
+
+                    let mut secure = self.discard == DiscardMode::Secure;
+                    if ui.add_enabled(discard, egui::Checkbox::new(&mut secure, "Securely"))
+                        .on_hover_text("Ask the device to make the old data unrecoverable, falling back to a plain discard")
+                        .changed()
+                    {
+                        self.discard = if secure { DiscardMode::Secure } else { DiscardMode::Discard };
+                    }
+                });
+
+                // limits can be changed while flashing, the writers pick them up right away
+                ui.horizontal(|ui| {
+                    ui.label("Limit speed:");
+                    let mut device = ratelimit::to_mib(self.rate_limits.device_default());
+                    if rate_drag_value(ui, &mut device)
+                        .on_hover_text("Most MB/s written to each device, 0 for no limit")
+                        .changed()
+                    {
+                        self.rate_limits.set_device_default(ratelimit::from_mib(device));
+                    }
+                    ui.label("per device,");
+                    let mut total = ratelimit::to_mib(self.rate_limits.total());
+                    if rate_drag_value(ui, &mut total)
+                        .on_hover_text("Most MB/s written to all devices together, 0 for no limit")
+                        .changed()
+                    {
+                        self.rate_limits.set_total(ratelimit::from_mib(total));
+                    }
+                    ui.label("in total");
+                });
+
+                ui.add_space(10.0);
 
                 // Progress bar - Always displayed
                 ui.group(|ui| {
@@ -339,16 +597,18 @@ impl eframe::App for State {
                         ui.label(egui::RichText::new("⚡ Flashing Progress").size(16.0).strong());
                         ui.add_space(5.0);
 
+                        if let Some(device) = self.progress.lock().ok().and_then(|p| p.discarding.clone()) {
+                            ui.label(format!("Discarding {}...", device));
+                        }
+
                         let (progress_val, speed, elapsed) = if let Ok(progress_guard) = self.progress.lock() {
                             let progress_val = progress_guard.get_progress();
                             let speed = progress_guard.get_speed_bytes() / 1_048_576.0;
                             // Show current elapsed time during flashing, or stored time when completed
                             let elapsed = if self.flashing_state == FlashingState::InProgress {
                                 progress_guard.get_elapsed_time().as_secs()
-                            } else if let Some(completed) = self.completed_time {
-                                completed
                             } else {
-                                0
+                                self.completed_time.unwrap_or_default()
                             };
                             (progress_val, speed, elapsed)
                         } else {
@@ -366,6 +626,32 @@ impl eframe::App for State {
                         ui.add(egui::ProgressBar::new(progress_val).show_percentage().desired_height(20.0));
                         ui.add_space(3.0);
                         ui.label(format!("Elapsed: {}s", elapsed));
+
+                        // with several devices, show which one is holding the others back
+                        if let Ok(progress) = self.progress.lock() {
+                            if self.flashing_state == FlashingState::InProgress && progress.devices.len() > 1 {
+                                for (index, device) in progress.devices.iter().enumerate() {
+                                    let mut line = format!(
+                                        "{}: {:.1} MB/s",
+                                        device.path,
+                                        progress.device_speed_bytes(index) / 1_048_576.0
+                                    );
+                                    if device.in_flight > 0 {
+                                        line.push_str(&format!(", {} writes in flight", device.in_flight));
+                                    }
+                                    ui.horizontal(|ui| {
+                                        ui.small(line);
+                                        let mut rate = ratelimit::to_mib(self.rate_limits.device(&device.path));
+                                        if rate_drag_value(ui, &mut rate)
+                                            .on_hover_text("Most MB/s written to this device, 0 for no limit")
+                                            .changed()
+                                        {
+                                            self.rate_limits.set_device(&device.path, ratelimit::from_mib(rate));
+                                        }
+                                    });
+                                }
+                            }
+                        }
                     });
                 });
                 ui.add_space(10.0);
@@ -395,7 +681,7 @@ impl eframe::App for State {
                 ui.add_space(10.0);
 
                 // Messages
-                if let Some(error) = self.error_message {
+                if let Some(ref error) = self.error_message {
                     ui.colored_label(egui::Color32::RED, format!("❌ {}", error));
                 }
 
@@ -419,6 +705,16 @@ impl eframe::App for State {
     }
 }
 
+/// The name the strategy goes by on the command line.
+fn zero_strategy_name(strategy: ZeroStrategy) -> String {
+    strategy.to_possible_value().map_or_else(String::new, |v| v.get_name().to_string())
+}
+
+/// An editor for a rate limit in MB/s, where 0 means none.
+fn rate_drag_value(ui: &mut egui::Ui, rate: &mut f64) -> egui::Response {
+    ui.add(egui::DragValue::new(rate).range(0.0..=f64::MAX).speed(0.5).max_decimals(1).suffix(" MB/s"))
+}
+
 pub fn run_gui(args: Args) -> Result<(), Box<dyn std::error::Error>> {
     let state = State::new(args);
     let native_options = eframe::NativeOptions {
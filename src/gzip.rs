@@ -0,0 +1,1158 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::sync::{Arc, OnceLock};
+
+use crate::parallel::{self, OrderedPool};
+use crate::sparse::ImageRead;
+
+/// The compressed file is split into pieces of this size, each decoded by a
+/// worker from the first deflate block that starts in it
+const CHUNK_SIZE: u64 = 1024 * 1024;
+/// Output a worker decodes before handing the rest of its chunk back
+const WORKER_LIMIT: usize = 8 * 1024 * 1024;
+/// Output decoded at a time once decoding has to go on sequentially
+const PIECE_SIZE: usize = 4 * 1024 * 1024;
+/// How far back a deflate stream may refer
+const WINDOW: usize = 32 * 1024;
+/// Gzip member headers are searched for this far before a chunk, since the
+/// block they lead up to may still start in it
+const HEADER_LOOKBACK: u64 = 1024;
+/// Bytes read from the compressed file at a time
+const READ_SIZE: usize = 256 * 1024;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+/// Order the code length code lengths are stored in
+const PRECODE_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// Decodes a gzip file on several threads, rapidgzip style. The file is cut
+/// into chunks and each worker looks for the first deflate block (or gzip
+/// member, as written by bgzip or by concatenating files) that starts in its
+/// chunk, then decodes from there without knowing the 32 KiB of output the
+/// block may refer back to. Those references are kept as markers and filled
+/// in once the chunk before is done. Chunks are handed out in order, and a
+/// chunk whose worker started anywhere but where the chunk before actually
+/// ended, or found no block at all, is decoded sequentially instead. Every
+/// member is checked against its CRC and size, and all of them are decoded,
+/// not only the first. With a single thread, or a file too small to be worth
+/// splitting up, it is all decoded sequentially, though images like that are
+/// better left to flate2.
+pub struct ParallelGzDecoder {
+    source: Arc<File>,
+    /// Decodes the chunks ahead, unless it is all decoded sequentially
+    pool: Option<OrderedPool<Option<Decoded>>>,
+    chunks: usize,
+    /// Next chunk to take from the workers
+    next: usize,
+    /// Bit offset of the block boundary decoding has reached
+    position: u64,
+    /// Decoding that goes on sequentially, up to `stop`
+    current: Option<Inflater>,
+    stop: u64,
+    finished: bool,
+    /// The last 32 KiB of output
+    window: Vec<u8>,
+    ready: Vec<u8>,
+    ready_pos: usize,
+    crc: crc32fast::Hasher,
+    member_size: u64,
+}
+
+/// What a worker made of its chunk.
+struct Decoded {
+    /// Bit offset decoding started at
+    start: u64,
+    /// Decoding started at the length of a stored block, whose header lies
+    /// somewhere in the bits before
+    stored: bool,
+    /// Output, after `WINDOW` markers standing for what came before the chunk
+    /// (none for the first chunk)
+    data: Vec<u16>,
+    prefix: usize,
+    members: Vec<MemberEnd>,
+    stop: Stop,
+    /// Picks up where the worker left off when it stopped at its limit
+    inflater: Inflater,
+}
+
+/// Whether a gzip file is large enough for decoding it in parallel to pay off.
+pub fn worth_splitting(file: &File) -> io::Result<bool> {
+    Ok(file.metadata()?.len() > 2 * CHUNK_SIZE)
+}
+
+impl ParallelGzDecoder {
+    pub fn new(file: File, threads: usize) -> io::Result<Self> {
+        let length = file.metadata()?.len();
+        let threads = if worth_splitting(&file)? { threads } else { 1 };
+        let source = Arc::new(file);
+        let chunks = if threads > 1 { length.div_ceil(CHUNK_SIZE) as usize } else { 1 };
+        let pool = (threads > 1).then(|| {
+            let source = source.clone();
+            OrderedPool::new(chunks, threads, move |chunk| decode_chunk(&source, length, chunk, chunks))
+        });
+
+        Ok(ParallelGzDecoder {
+            source,
+            pool,
+            chunks,
+            next: 0,
+            position: 0,
+            current: None,
+            stop: 0,
+            finished: false,
+            window: Vec::new(),
+            ready: Vec::new(),
+            ready_pos: 0,
+            crc: crc32fast::Hasher::new(),
+            member_size: 0,
+        })
+    }
+
+    /// Decode the next piece of output into `ready`, returning false at the
+    /// end of the stream.
+    fn fill(&mut self) -> io::Result<bool> {
+        if let Some(mut inflater) = self.current.take() {
+            let mut out = self.window.clone();
+            let prefix = out.len();
+            let stop = inflater.run(&mut out, prefix + PIECE_SIZE, self.stop)?;
+            let mut members = std::mem::take(&mut inflater.members);
+            for member in members.iter_mut() {
+                member.offset -= prefix;
+            }
+            self.emit(out, prefix, &members)?;
+            self.advance(stop, inflater);
+            return Ok(true);
+        }
+        if self.finished {
+            return Ok(false);
+        }
+
+        while self.next < self.chunks {
+            let chunk = self.next;
+            self.next += 1;
+            // a worker that tripped over a bug leaves its chunk to this thread
+            let decoded = self.pool.as_ref().and_then(|pool| pool.take(chunk).flatten());
+            // a block may have run past this chunk altogether
+            let stop = chunk_stop(chunk, self.chunks);
+            if self.position >= stop {
+                continue;
+            }
+
+            self.stop = stop;
+            match decoded {
+                Some(decoded) if self.starts_here(&decoded)? => {
+                    let out = self.resolve(&decoded.data[decoded.prefix..]);
+                    let prefix = self.window.len();
+                    self.emit(out, prefix, &decoded.members)?;
+                    self.advance(decoded.stop, decoded.inflater);
+                }
+                _ => self.current = Some(Inflater::at(&self.source, self.position)?),
+            }
+            return Ok(true);
+        }
+
+        Err(io::Error::new(io::ErrorKind::UnexpectedEof, "gzip stream ends early"))
+    }
+
+    /// Whether a worker started decoding right where the chunk before ended.
+    fn starts_here(&self, decoded: &Decoded) -> io::Result<bool> {
+        if !decoded.stored {
+            return Ok(decoded.start == self.position);
+        }
+        Ok(Inflater::stored_data(&self.source, self.position)? == Some(decoded.start))
+    }
+
+    fn advance(&mut self, stop: Stop, inflater: Inflater) {
+        match stop {
+            Stop::Boundary(position) => self.position = position,
+            Stop::End => self.finished = true,
+            Stop::Limit => self.current = Some(inflater),
+        }
+    }
+
+    /// Fill in the markers of a worker's output from the window, returning
+    /// the window followed by the output.
+    fn resolve(&self, data: &[u16]) -> Vec<u8> {
+        let mut window = [0; WINDOW];
+        window[WINDOW - self.window.len()..].copy_from_slice(&self.window);
+
+        let mut out = Vec::with_capacity(self.window.len() + data.len());
+        out.extend_from_slice(&self.window);
+        out.extend(data.iter().map(|&symbol| match symbol {
+            0..=255 => symbol as u8,
+            marker => window[marker as usize - 256],
+        }));
+        out
+    }
+
+    /// Hand out what `out` holds past `prefix`, checking the members that end
+    /// in it.
+    fn emit(&mut self, out: Vec<u8>, prefix: usize, members: &[MemberEnd]) -> io::Result<()> {
+        let mut cursor = prefix;
+        for member in members {
+            let end = prefix + member.offset;
+            self.crc.update(&out[cursor..end]);
+            self.member_size += (end - cursor) as u64;
+            let crc = std::mem::replace(&mut self.crc, crc32fast::Hasher::new()).finalize();
+            if crc != member.crc || self.member_size as u32 != member.size {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "gzip member fails its CRC or size check"));
+            }
+            self.member_size = 0;
+            cursor = end;
+        }
+        self.crc.update(&out[cursor..]);
+        self.member_size += (out.len() - cursor) as u64;
+
+        self.window = out[out.len().saturating_sub(WINDOW)..].to_vec();
+        self.ready = out;
+        self.ready_pos = prefix;
+        Ok(())
+    }
+}
+
+impl ImageRead for ParallelGzDecoder {}
+
+impl Read for ParallelGzDecoder {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.ready_pos == self.ready.len() {
+            if !self.fill()? {
+                return Ok(0);
+            }
+        }
+        let len = buf.len().min(self.ready.len() - self.ready_pos);
+        buf[..len].copy_from_slice(&self.ready[self.ready_pos..self.ready_pos + len]);
+        self.ready_pos += len;
+        Ok(len)
+    }
+}
+
+/// Bit offset the block boundary ending a chunk lies at or after.
+fn chunk_stop(chunk: usize, chunks: usize) -> u64 {
+    if chunk + 1 == chunks {
+        u64::MAX
+    } else {
+        (chunk as u64 + 1) * CHUNK_SIZE * 8
+    }
+}
+
+/// Decode a chunk from the first block or member that starts in it and
+/// decodes cleanly, up to the first block boundary past its end.
+fn decode_chunk(source: &Arc<File>, length: u64, chunk: usize, chunks: usize) -> Option<Decoded> {
+    let stop = chunk_stop(chunk, chunks);
+    if chunk == 0 {
+        return try_decode(Inflater::at(source, 0).ok()?, 0, stop, 0);
+    }
+
+    let begin = chunk as u64 * CHUNK_SIZE;
+    let end = (begin + CHUNK_SIZE).min(length);
+    let lookback = begin.saturating_sub(HEADER_LOOKBACK);
+    // room past the end for a block header starting just before it
+    let mut data = vec![0; ((end + 1024).min(length) - lookback) as usize];
+    parallel::read_exact_at(source, &mut data, lookback).ok()?;
+
+    let try_member = |offset: u64| -> Option<Decoded> {
+        let mut inflater = Inflater::member(source, offset).ok()?;
+        let Ok(Stop::Boundary(start)) = inflater.run::<u16>(&mut Vec::new(), usize::MAX, 0) else {
+            return None;
+        };
+        if start < begin * 8 || start >= stop {
+            return None;
+        }
+        try_decode(inflater, start, stop, WINDOW)
+    };
+
+    for offset in lookback..begin {
+        if is_member_header(&data[(offset - lookback) as usize..]) {
+            if let Some(decoded) = try_member(offset) {
+                return Some(decoded);
+            }
+        }
+    }
+
+    for offset in begin..end {
+        let local = (offset - lookback) as usize;
+        if is_member_header(&data[local..]) {
+            if let Some(decoded) = try_member(offset) {
+                return Some(decoded);
+            }
+        }
+        // the header of a stored block comes before its length, so one whose
+        // length is right at the start of the chunk began in the chunk before
+        if offset > begin && is_stored_length(&data[local..]) {
+            if let Some(decoded) = Inflater::stored(source, offset).ok().and_then(|i| try_decode(i, offset * 8, stop, WINDOW)) {
+                return Some(Decoded { stored: true, ..decoded });
+            }
+        }
+
+        let word = load_word(&data[local..]);
+        for shift in 0..8 {
+            let bit = offset * 8 + shift;
+            if dynamic_header_plausible(word >> shift) && read_tables(&mut SliceBits { data: &data, pos: local * 8 + shift as usize + 3 }).is_some() {
+                if let Some(decoded) = Inflater::block(source, bit).ok().and_then(|i| try_decode(i, bit, stop, WINDOW)) {
+                    return Some(decoded);
+                }
+            }
+        }
+    }
+    None
+}
+
+fn try_decode(mut inflater: Inflater, start: u64, stop: u64, prefix: usize) -> Option<Decoded> {
+    let mut data: Vec<u16> = (0..prefix as u16).map(|i| 256 + i).collect();
+    let stop = inflater.run(&mut data, prefix + WORKER_LIMIT, stop).ok()?;
+    let mut members = std::mem::take(&mut inflater.members);
+    for member in members.iter_mut() {
+        member.offset -= prefix;
+    }
+    Some(Decoded { start, stored: false, data, prefix, members, stop, inflater })
+}
+
+fn is_member_header(data: &[u8]) -> bool {
+    // magic, deflate, and none of the reserved flags
+    data.len() >= 10 && data[0] == 0x1f && data[1] == 0x8b && data[2] == 8 && data[3] & 0xe0 == 0
+}
+
+/// Whether the bits starting with `bits` could be a dynamic Huffman block
+/// header: its sizes are in range and its code length code is complete.
+/// Nearly every offset that is not a block fails this, and the rest are
+/// checked the way zlib would check them with `read_tables`.
+fn dynamic_header_plausible(bits: u128) -> bool {
+    let header = bits as u32;
+    if (header >> 1) & 3 != 2 || (header >> 3) & 31 > 29 || (header >> 8) & 31 > 29 {
+        return false;
+    }
+
+    let mut left = 128;
+    for i in 0..((header >> 13) & 15) + 4 {
+        let len = (bits >> (17 + 3 * i)) as u32 & 7;
+        if len != 0 {
+            left -= 1 << (7 - len);
+        }
+    }
+    left == 0
+}
+
+/// Whether `data` starts with the length of a stored block and its complement.
+fn is_stored_length(data: &[u8]) -> bool {
+    data.len() >= 4 && data[0] == !data[2] && data[1] == !data[3]
+}
+
+/// The next 128 bits of `data`, padded with zeros past its end.
+fn load_word(data: &[u8]) -> u128 {
+    let mut word = [0; 16];
+    let len = data.len().min(16);
+    word[..len].copy_from_slice(&data[..len]);
+    u128::from_le_bytes(word)
+}
+
+/// Where a worker's or the reading thread's decoding stopped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stop {
+    /// At the start of a block at or past the bit offset it was to stop at
+    Boundary(u64),
+    /// At the end of the last member
+    End,
+    /// Once it had decoded as much as it was allowed to
+    Limit,
+}
+
+/// Where a gzip member ends in the output, and what its trailer says about it.
+#[derive(Debug, Clone, Copy)]
+struct MemberEnd {
+    offset: usize,
+    crc: u32,
+    size: u32,
+}
+
+/// Output of the inflater: bytes, or bytes and markers when the window the
+/// stream refers back to is not known yet.
+trait Symbol: Copy {
+    fn byte(byte: u8) -> Self;
+}
+
+impl Symbol for u8 {
+    fn byte(byte: u8) -> Self {
+        byte
+    }
+}
+
+impl Symbol for u16 {
+    fn byte(byte: u8) -> Self {
+        byte as u16
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Header,
+    Block,
+    Stored(usize),
+    Codes,
+    Trailer,
+}
+
+/// A deflate decoder over gzip members that can start at any block boundary
+/// and be stopped at any point, so workers and the reading thread can take
+/// turns with it.
+struct Inflater {
+    bits: BitReader,
+    state: State,
+    /// The current block is the last of its member
+    last: bool,
+    first_member: bool,
+    literals: Huffman,
+    distances: Huffman,
+    members: Vec<MemberEnd>,
+}
+
+impl Inflater {
+    /// Decode from the start of a gzip member at `offset`.
+    fn member(source: &Arc<File>, offset: u64) -> io::Result<Self> {
+        Self::new(source, offset * 8, State::Header)
+    }
+
+    /// Decode from the deflate block at bit offset `bit`.
+    fn block(source: &Arc<File>, bit: u64) -> io::Result<Self> {
+        Self::new(source, bit, State::Block)
+    }
+
+    /// Decode a stored block that is not the last of its member from its
+    /// length at `offset`.
+    fn stored(source: &Arc<File>, offset: u64) -> io::Result<Self> {
+        let mut inflater = Self::new(source, offset * 8, State::Block)?;
+        inflater.read_stored_length()?;
+        Ok(inflater)
+    }
+
+    /// Bit offset of the length of the block at `bit`, if it is a stored
+    /// block that is not the last of its member.
+    fn stored_data(source: &Arc<File>, bit: u64) -> io::Result<Option<u64>> {
+        let mut bits = BitReader::new(source.clone(), bit)?;
+        if bits.read(3)? != 0 {
+            return Ok(None);
+        }
+        bits.align();
+        Ok(Some(bits.position()))
+    }
+
+    /// Decode from a block boundary, or from the start of the file.
+    fn at(source: &Arc<File>, bit: u64) -> io::Result<Self> {
+        if bit == 0 {
+            Self::member(source, 0)
+        } else {
+            Self::block(source, bit)
+        }
+    }
+
+    fn new(source: &Arc<File>, bit: u64, state: State) -> io::Result<Self> {
+        Ok(Inflater {
+            bits: BitReader::new(source.clone(), bit)?,
+            state,
+            last: false,
+            first_member: bit == 0,
+            literals: Huffman::default(),
+            distances: Huffman::default(),
+            members: Vec::new(),
+        })
+    }
+
+    /// Decode into `out`, whose contents are the history the stream refers
+    /// back to, until it holds about `limit` symbols, a block starts at or
+    /// past bit offset `stop`, or the stream ends.
+    fn run<T: Symbol>(&mut self, out: &mut Vec<T>, limit: usize, stop: u64) -> io::Result<Stop> {
+        loop {
+            if out.len() >= limit {
+                return Ok(Stop::Limit);
+            }
+            match self.state {
+                State::Header => {
+                    if !self.read_member_header()? {
+                        return Ok(Stop::End);
+                    }
+                    self.state = State::Block;
+                }
+                State::Block => {
+                    let position = self.bits.position();
+                    if position >= stop {
+                        return Ok(Stop::Boundary(position));
+                    }
+                    self.read_block_header()?;
+                }
+                State::Stored(remaining) => {
+                    let len = remaining.min(limit - out.len());
+                    self.bits.copy_bytes(out, len)?;
+                    self.state = match remaining - len {
+                        0 => self.block_ended(),
+                        remaining => State::Stored(remaining),
+                    };
+                }
+                State::Codes => {
+                    if self.decode_codes(out, limit)? {
+                        self.state = self.block_ended();
+                    }
+                }
+                State::Trailer => {
+                    self.bits.align();
+                    let crc = self.bits.read_u32()?;
+                    let size = self.bits.read_u32()?;
+                    self.members.push(MemberEnd { offset: out.len(), crc, size });
+                    self.first_member = false;
+                    self.state = State::Header;
+                }
+            }
+        }
+    }
+
+    fn block_ended(&self) -> State {
+        if self.last {
+            State::Trailer
+        } else {
+            State::Block
+        }
+    }
+
+    /// Skip a gzip member header, returning false if there is no further
+    /// member. Anything after the last member is ignored, as gzip does.
+    fn read_member_header(&mut self) -> io::Result<bool> {
+        self.bits.align();
+        self.bits.refill()?;
+        if !self.first_member && (self.bits.count < 16 || self.bits.peek(16) != 0x8b1f) {
+            return Ok(false);
+        }
+
+        let mut header = [0; 10];
+        for byte in header.iter_mut() {
+            *byte = self.bits.read_byte()?;
+        }
+        if !is_member_header(&header) {
+            return Err(corrupt("not a gzip member"));
+        }
+
+        let flags = header[3];
+        if flags & 4 != 0 {
+            let len = self.bits.read_byte()? as usize | (self.bits.read_byte()? as usize) << 8;
+            for _ in 0..len {
+                self.bits.read_byte()?;
+            }
+        }
+        // file name, then comment, each ending in a zero byte
+        for flag in [8, 16] {
+            if flags & flag != 0 {
+                while self.bits.read_byte()? != 0 {}
+            }
+        }
+        if flags & 2 != 0 {
+            self.bits.read_byte()?;
+            self.bits.read_byte()?;
+        }
+        Ok(true)
+    }
+
+    fn read_block_header(&mut self) -> io::Result<()> {
+        self.bits.refill()?;
+        let header = self.bits.read(3)?;
+        self.last = header & 1 != 0;
+        match header >> 1 {
+            0 => {
+                self.bits.align();
+                self.read_stored_length()?;
+            }
+            1 => {
+                let (literals, distances) = fixed_tables();
+                self.literals = literals.clone();
+                self.distances = distances.clone();
+                self.state = State::Codes;
+            }
+            2 => {
+                let tables = read_tables(&mut self.bits);
+                if let Some(error) = self.bits.error.take() {
+                    return Err(error);
+                }
+                (self.literals, self.distances) = tables.ok_or_else(|| corrupt("invalid Huffman tables"))?;
+                self.state = State::Codes;
+            }
+            _ => return Err(corrupt("invalid block type")),
+        }
+        Ok(())
+    }
+
+    fn read_stored_length(&mut self) -> io::Result<()> {
+        let len = self.bits.read(16)?;
+        if self.bits.read(16)? != !len & 0xffff {
+            return Err(corrupt("stored block length does not match its complement"));
+        }
+        self.state = State::Stored(len as usize);
+        Ok(())
+    }
+
+    /// Decode symbols until the end of the block, returning true, or until
+    /// `out` holds `limit` symbols.
+    fn decode_codes<T: Symbol>(&mut self, out: &mut Vec<T>, limit: usize) -> io::Result<bool> {
+        let bits = &mut self.bits;
+        while out.len() < limit {
+            // enough for a length and a distance with their extra bits
+            bits.refill()?;
+            let symbol = bits.decode(&self.literals)?;
+            if symbol < 256 {
+                out.push(T::byte(symbol as u8));
+                continue;
+            }
+            if symbol == 256 {
+                return Ok(true);
+            }
+
+            let index = symbol as usize - 257;
+            if index >= LENGTH_BASE.len() {
+                return Err(corrupt("invalid length code"));
+            }
+            let length = LENGTH_BASE[index] as usize + bits.read(LENGTH_EXTRA[index] as u32)? as usize;
+            let index = bits.decode(&self.distances)? as usize;
+            if index >= DISTANCE_BASE.len() {
+                return Err(corrupt("invalid distance code"));
+            }
+            let distance = DISTANCE_BASE[index] as usize + bits.read(DISTANCE_EXTRA[index] as u32)? as usize;
+            if distance > out.len() {
+                return Err(corrupt("distance too far back"));
+            }
+            copy_match(out, distance, length);
+        }
+        Ok(false)
+    }
+}
+
+fn copy_match<T: Symbol>(out: &mut Vec<T>, distance: usize, length: usize) {
+    let start = out.len() - distance;
+    if distance >= length {
+        out.extend_from_within(start..start + length);
+    } else if distance == 1 {
+        let symbol = out[start];
+        out.resize(out.len() + length, symbol);
+    } else {
+        for i in start..start + length {
+            let symbol = out[i];
+            out.push(symbol);
+        }
+    }
+}
+
+fn corrupt(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("corrupt gzip data: {}", what))
+}
+
+fn fixed_tables() -> &'static (Huffman, Huffman) {
+    static TABLES: OnceLock<(Huffman, Huffman)> = OnceLock::new();
+    TABLES.get_or_init(|| {
+        let mut lengths = [8; 288];
+        lengths[144..256].fill(9);
+        lengths[256..280].fill(7);
+        let literals = Huffman::new(&lengths, false).unwrap();
+        let distances = Huffman::new(&[5; 32], false).unwrap();
+        (literals, distances)
+    })
+}
+
+/// Read the code lengths of a dynamic block, following the 3 header bits,
+/// and build its tables. Fails on anything zlib would reject.
+fn read_tables<B: Bits>(bits: &mut B) -> Option<(Huffman, Huffman)> {
+    let literals = bits.bits(5)? as usize + 257;
+    let distances = bits.bits(5)? as usize + 1;
+    let precode_len = bits.bits(4)? as usize + 4;
+    if literals > 286 || distances > 30 {
+        return None;
+    }
+
+    let mut precode = [0; 19];
+    for &symbol in &PRECODE_ORDER[..precode_len] {
+        precode[symbol] = bits.bits(3)? as u8;
+    }
+    let precode = Huffman::new(&precode, true)?;
+
+    let mut lengths = [0; 286 + 30];
+    let total = literals + distances;
+    let mut filled = 0;
+    while filled < total {
+        let symbol = bits.symbol(&precode)?;
+        let (length, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => (*lengths[..filled].last()?, 3 + bits.bits(2)? as usize),
+            17 => (0, 3 + bits.bits(3)? as usize),
+            _ => (0, 11 + bits.bits(7)? as usize),
+        };
+        if filled + repeat > total {
+            return None;
+        }
+        lengths[filled..filled + repeat].fill(length);
+        filled += repeat;
+    }
+
+    // a block without an end of block code could never end
+    if lengths[256] == 0 {
+        return None;
+    }
+    Some((Huffman::new(&lengths[..literals], false)?, Huffman::new(&lengths[literals..total], false)?))
+}
+
+/// Codes of up to this many bits are looked up in one go
+const FAST_BITS: u32 = 10;
+
+/// A canonical Huffman code, decoded through a lookup table for short codes
+/// and code by code for the rest.
+#[derive(Clone, Default)]
+struct Huffman {
+    /// Symbol and length of the code each `FAST_BITS` bits start with, or 0
+    fast: Vec<u16>,
+    counts: [u16; 16],
+    /// Symbols ordered by code
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    /// Build the code for `lengths`, which has to be complete except that a
+    /// literal or distance code may consist of a single 1 bit code.
+    fn new(lengths: &[u8], precode: bool) -> Option<Self> {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return None;
+            }
+        }
+        let max = (1..16).rev().find(|&len| counts[len] > 0).unwrap_or(0);
+        if left > 0 && max != 0 && (precode || max != 1) {
+            return None;
+        }
+
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+
+        let mut fast = vec![0; 1 << FAST_BITS];
+        let (mut code, mut index) = (0u32, 0);
+        for len in 1..=FAST_BITS {
+            for _ in 0..counts[len as usize] {
+                let entry = symbols[index] << 4 | len as u16;
+                let mut slot = (code.reverse_bits() >> (32 - len)) as usize;
+                while slot < fast.len() {
+                    fast[slot] = entry;
+                    slot += 1 << len;
+                }
+                code += 1;
+                index += 1;
+            }
+            code <<= 1;
+        }
+
+        Some(Huffman { fast, counts, symbols })
+    }
+
+    /// Symbol and code length for the next 15 bits of the stream.
+    fn lookup(&self, bits: u32) -> Option<(u16, u32)> {
+        let entry = self.fast[bits as usize & ((1 << FAST_BITS) - 1)];
+        if entry != 0 {
+            return Some((entry >> 4, entry as u32 & 15));
+        }
+
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..16 {
+            code |= (bits >> (len - 1)) as i32 & 1;
+            let count = self.counts[len as usize] as i32;
+            if code - first < count {
+                return Some((self.symbols[(index + code - first) as usize], len));
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        None
+    }
+}
+
+/// Reading bits off a deflate stream, for the parts shared by the inflater
+/// and the search for blocks.
+trait Bits {
+    /// The next `n` bits, padded with zeros past the end of the data
+    fn peek_bits(&mut self, n: u32) -> u32;
+    fn consume(&mut self, n: u32) -> bool;
+
+    fn bits(&mut self, n: u32) -> Option<u32> {
+        let value = self.peek_bits(n);
+        self.consume(n).then_some(value)
+    }
+
+    fn symbol(&mut self, table: &Huffman) -> Option<u16> {
+        let (symbol, len) = table.lookup(self.peek_bits(15))?;
+        self.consume(len).then_some(symbol)
+    }
+}
+
+/// Bits of the chunk a worker searches for blocks.
+struct SliceBits<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Bits for SliceBits<'_> {
+    fn peek_bits(&mut self, n: u32) -> u32 {
+        let byte = self.pos / 8;
+        let mut word = [0; 4];
+        let available = self.data.len().saturating_sub(byte).min(4);
+        word[..available].copy_from_slice(&self.data[byte..byte + available]);
+        (u32::from_le_bytes(word) >> (self.pos % 8)) & ((1 << n) - 1)
+    }
+
+    fn consume(&mut self, n: u32) -> bool {
+        self.pos += n as usize;
+        self.pos <= self.data.len() * 8
+    }
+}
+
+/// Reads the compressed file bit by bit from any offset, independently of
+/// every other reader of it.
+struct BitReader {
+    source: Arc<File>,
+    data: Vec<u8>,
+    /// File offset of `data[0]`
+    start: u64,
+    pos: usize,
+    bits: u64,
+    count: u32,
+    eof: bool,
+    /// Error while refilling on behalf of `Bits`
+    error: Option<io::Error>,
+}
+
+impl BitReader {
+    fn new(source: Arc<File>, bit: u64) -> io::Result<Self> {
+        let mut reader = BitReader {
+            source,
+            data: Vec::new(),
+            start: bit / 8,
+            pos: 0,
+            bits: 0,
+            count: 0,
+            eof: false,
+            error: None,
+        };
+        reader.refill()?;
+        reader.read((bit % 8) as u32)?;
+        Ok(reader)
+    }
+
+    /// Bit offset in the file of the next bit.
+    fn position(&self) -> u64 {
+        (self.start + self.pos as u64) * 8 - self.count as u64
+    }
+
+    /// Read on from the file, keeping what has not been used yet.
+    fn fill(&mut self) -> io::Result<()> {
+        self.data.drain(..self.pos);
+        self.start += self.pos as u64;
+        self.pos = 0;
+
+        let mut filled = self.data.len();
+        self.data.resize(READ_SIZE.max(filled), 0);
+        while filled < self.data.len() {
+            match parallel::read_at(&self.source, &mut self.data[filled..], self.start + filled as u64) {
+                Ok(0) => {
+                    self.eof = true;
+                    break;
+                }
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.data.truncate(filled);
+        Ok(())
+    }
+
+    /// Make at least 48 bits available, unless the file ends first.
+    fn refill(&mut self) -> io::Result<()> {
+        if self.count >= 48 {
+            return Ok(());
+        }
+        if self.pos + 8 > self.data.len() && !self.eof {
+            self.fill()?;
+        }
+        if self.pos + 8 <= self.data.len() {
+            // load a whole word; the bytes past `count` are loaded again later
+            let word = u64::from_le_bytes(self.data[self.pos..self.pos + 8].try_into().unwrap());
+            self.bits |= word << self.count;
+            self.pos += (63 - self.count) as usize / 8;
+            self.count |= 56;
+        } else {
+            while self.count <= 56 && self.pos < self.data.len() {
+                self.bits |= (self.data[self.pos] as u64) << self.count;
+                self.pos += 1;
+                self.count += 8;
+            }
+        }
+        Ok(())
+    }
+
+    fn peek(&self, n: u32) -> u32 {
+        (self.bits & ((1 << n) - 1)) as u32
+    }
+
+    fn skip(&mut self, n: u32) -> io::Result<()> {
+        if n > self.count {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "gzip stream ends early"));
+        }
+        self.bits >>= n;
+        self.count -= n;
+        Ok(())
+    }
+
+    /// Up to 32 bits that have already been refilled.
+    fn read(&mut self, n: u32) -> io::Result<u32> {
+        let value = self.peek(n);
+        self.skip(n)?;
+        Ok(value)
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        self.refill()?;
+        Ok(self.read(8)? as u8)
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        self.refill()?;
+        let low = self.read(16)?;
+        Ok(low | self.read(16)? << 16)
+    }
+
+    fn decode(&mut self, table: &Huffman) -> io::Result<u16> {
+        let (symbol, len) = table.lookup(self.peek(15)).ok_or_else(|| corrupt("invalid Huffman code"))?;
+        self.skip(len)?;
+        Ok(symbol)
+    }
+
+    /// Skip to the next byte boundary.
+    fn align(&mut self) {
+        let n = self.count % 8;
+        self.bits >>= n;
+        self.count -= n;
+    }
+
+    /// Copy `len` bytes of a stored block; the reader is at a byte boundary.
+    fn copy_bytes<T: Symbol>(&mut self, out: &mut Vec<T>, mut len: usize) -> io::Result<()> {
+        while len > 0 && self.count >= 8 {
+            out.push(T::byte(self.read(8)? as u8));
+            len -= 1;
+        }
+        if len == 0 {
+            return Ok(());
+        }
+
+        // whatever was loaded past `count` is skipped over now
+        self.bits = 0;
+        while len > 0 {
+            if self.pos == self.data.len() {
+                self.fill()?;
+                if self.data.is_empty() {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "gzip stream ends early"));
+                }
+            }
+            let n = len.min(self.data.len() - self.pos);
+            out.extend(self.data[self.pos..self.pos + n].iter().map(|&b| T::byte(b)));
+            self.pos += n;
+            len -= n;
+        }
+        Ok(())
+    }
+}
+
+impl Bits for BitReader {
+    fn peek_bits(&mut self, n: u32) -> u32 {
+        if self.count < n {
+            if let Err(e) = self.refill() {
+                self.error.get_or_insert(e);
+            }
+        }
+        self.peek(n)
+    }
+
+    fn consume(&mut self, n: u32) -> bool {
+        self.skip(n).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use crate::testing::{Noise, TempFile};
+    use flate2::{Compression, GzBuilder};
+    use std::io::Write;
+
+    const MIB: usize = 1024 * 1024;
+
+    /// Data that compresses to about half its size with back-references of
+    /// every distance up to the window, so deflate picks dynamic blocks.
+    fn image_like(len: usize, seed: u64) -> Vec<u8> {
+        let mut noise = Noise(seed);
+        let mut out = Vec::with_capacity(len + 64);
+        while out.len() < len {
+            let r = noise.next();
+            if r.is_multiple_of(8) && out.len() > WINDOW {
+                let distance = (r >> 8) as usize % (WINDOW - 64) + 64;
+                let start = out.len() - distance;
+                out.extend_from_within(start..start + 4 + (r >> 40) as usize % 12);
+            } else {
+                out.push(b'a' + (r >> 16) as u8 % 16);
+            }
+        }
+        out.truncate(len);
+        out
+    }
+
+    fn gzip(data: &[u8], level: Compression) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), level);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// Type of the first deflate block of a member written without optional
+    /// header fields.
+    fn first_block_type(compressed: &[u8]) -> u8 {
+        (compressed[10] >> 1) & 3
+    }
+
+    fn decode(compressed: &[u8], threads: usize) -> io::Result<Vec<u8>> {
+        let file = TempFile::new(compressed);
+        let mut out = Vec::new();
+        ParallelGzDecoder::new(file.open(), threads)?.read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    fn assert_round_trip(data: &[u8], compressed: &[u8]) {
+        for threads in [1, 4] {
+            let out = decode(compressed, threads).unwrap();
+            assert!(out == data, "{} threads decoded {} bytes of {}", threads, out.len(), data.len());
+        }
+    }
+
+    #[test]
+    fn decodes_stored_blocks() {
+        let data = Noise(1).bytes(3 * MIB);
+        let compressed = gzip(&data, Compression::none());
+        assert_eq!(first_block_type(&compressed), 0);
+        assert_round_trip(&data, &compressed);
+    }
+
+    #[test]
+    fn decodes_fixed_blocks() {
+        let data = b"ferrisflash".to_vec();
+        let compressed = gzip(&data, Compression::fast());
+        assert_eq!(first_block_type(&compressed), 1);
+        assert_round_trip(&data, &compressed);
+    }
+
+    #[test]
+    fn decodes_dynamic_blocks() {
+        let data = image_like(8 * MIB, 2);
+        let compressed = gzip(&data, Compression::default());
+        assert_eq!(first_block_type(&compressed), 2);
+        assert!(compressed.len() as u64 > 2 * CHUNK_SIZE);
+        assert_round_trip(&data, &compressed);
+    }
+
+    #[test]
+    fn skips_optional_header_fields() {
+        let data = image_like(64 * 1024, 3);
+        let mut encoder = GzBuilder::new()
+            .filename("image.img")
+            .comment("flashed by ferrisflash")
+            .extra(vec![1, 2, 3, 4])
+            .write(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        assert_round_trip(&data, &encoder.finish().unwrap());
+    }
+
+    #[test]
+    fn decodes_every_member() {
+        let (first, second) = (image_like(3 * MIB, 4), Noise(5).bytes(2 * MIB));
+        let mut compressed = gzip(&first, Compression::default());
+        compressed.extend(gzip(&second, Compression::none()));
+        assert_round_trip(&[first, second].concat(), &compressed);
+
+        // bgzip style, many small members
+        let data = image_like(6 * MIB, 6);
+        let compressed: Vec<u8> = data.chunks(64 * 1024).flat_map(|piece| gzip(piece, Compression::default())).collect();
+        assert!(compressed.len() as u64 > 2 * CHUNK_SIZE);
+        assert_round_trip(&data, &compressed);
+    }
+
+    #[test]
+    fn resolves_references_across_chunks() {
+        let data = image_like(8 * MIB, 7);
+        let compressed = gzip(&data, Compression::default());
+        let chunks = (compressed.len() as u64).div_ceil(CHUNK_SIZE) as usize;
+
+        let file = TempFile::new(&compressed);
+        let decoded = decode_chunk(&Arc::new(file.open()), compressed.len() as u64, 1, chunks);
+
+        // the second chunk refers back into the output of the first
+        let decoded = decoded.expect("no block found in the second chunk");
+        assert!(decoded.start > CHUNK_SIZE * 8);
+        assert!(decoded.data[decoded.prefix..].iter().any(|&symbol| symbol >= 256));
+        assert_round_trip(&data, &compressed);
+    }
+
+    #[test]
+    fn ignores_false_block_candidates() {
+        // stored data full of what looks like member headers, stored block
+        // lengths and, being noise, dynamic block headers
+        let mut noise = Noise(8);
+        let mut data = Vec::new();
+        while data.len() < 3 * MIB {
+            data.extend_from_slice(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 3]);
+            let len = noise.next() as u16;
+            data.extend_from_slice(&len.to_le_bytes());
+            data.extend_from_slice(&(!len).to_le_bytes());
+            let filler = noise.next() as usize % 4096;
+            data.extend(noise.bytes(filler));
+        }
+        assert!(is_member_header(&data) && is_stored_length(&data[10..]));
+
+        let compressed = gzip(&data, Compression::none());
+        assert_round_trip(&data, &compressed);
+    }
+
+    #[test]
+    fn rejects_truncated_and_corrupt_members() {
+        for data in [image_like(256 * 1024, 9), image_like(5 * MIB, 10)] {
+            let compressed = gzip(&data, Compression::default());
+            for threads in [1, 4] {
+                assert!(decode(&compressed[..compressed.len() - 100], threads).is_err());
+
+                let mut corrupt = compressed.clone();
+                let crc = corrupt.len() - 8;
+                corrupt[crc] ^= 1;
+                let error = decode(&corrupt, threads).unwrap_err();
+                assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            }
+        }
+    }
+}
@@ -1,14 +1,40 @@
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
+mod backup;
+mod blockdev;
+mod direct;
 mod fs;
 mod gui;
+mod gzip;
+mod mounts;
+mod parallel;
+mod partition;
+mod pipeline;
+mod policy;
+mod probe;
+mod protect;
+mod ratelimit;
+mod recovery;
+mod sparse;
+#[cfg(test)]
+mod testing;
+#[cfg(target_os = "linux")]
+mod uring;
+mod verify;
+mod writeback;
+mod xz;
+mod zeroing;
+mod zst;
 
 #[derive(Debug, Parser)]
 #[clap(version)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
     #[clap(short, long)]
     verbose: bool,
     #[clap(short, long, default_value = "")]
@@ -17,11 +43,209 @@ struct Args {
     device_path: String,
     #[clap(short, long, default_value = "false")]
     gui: bool,
+    /// Check every target for fake capacity before flashing
+    #[clap(long)]
+    probe_capacity: bool,
+    /// How many times to retry a failed write before giving up on a block
+    #[clap(long, default_value = "3")]
+    retries: u32,
+    /// Delay before the first retry in milliseconds, doubled on every attempt
+    #[clap(long, default_value = "100")]
+    retry_backoff_ms: u64,
+    /// Block size used when rewriting a region that failed to write
+    #[clap(long, default_value = "4096")]
+    retry_block_size: usize,
+    /// What to do with a device once a sector cannot be written
+    #[clap(long, value_enum, default_value = "fail")]
+    on_bad_sector: recovery::BadSectorPolicy,
+    /// Allow writing to disks that hold the running system, swap or LVM/md members
+    #[clap(long)]
+    allow_system_disk: bool,
+    /// Unmount any mounted partitions of the target before flashing
+    #[clap(long)]
+    unmount: bool,
+    /// Unmount lazily, detaching filesystems that are still busy
+    #[clap(long)]
+    lazy_unmount: bool,
+    /// Allow writing the image to a new regular file instead of a device
+    #[clap(long)]
+    allow_file: bool,
+    /// Replace an existing regular file (implies --allow-file)
+    #[clap(long)]
+    overwrite_file: bool,
+    /// Start without asking for confirmation, unless a target is not removable
+    #[clap(short, long)]
+    yes: bool,
+    /// Run every check and decode the image, but write nothing to the targets
+    #[clap(long)]
+    dry_run: bool,
+    /// Directory for backups of each target's partition table [default: ~/.local/share/ferrisflash/backups]
+    #[clap(long)]
+    backup_dir: Option<String>,
+    /// Do not back up the partition table of the targets before flashing
+    #[clap(long)]
+    no_backup: bool,
+    /// How to write the image's runs of zeros to the targets
+    #[clap(long, value_enum, default_value = "auto")]
+    zero_blocks: zeroing::ZeroStrategy,
+    /// Size in KiB of the blocks checked for zeros, runs of zero blocks get the zero strategy
+    #[clap(long, default_value = "4")]
+    zero_granularity: usize,
+    /// Discard the whole target before flashing, where it supports discard
+    #[clap(long)]
+    discard: bool,
+    /// Discard the whole target securely before flashing (implies --discard)
+    #[clap(long)]
+    secure_discard: bool,
+    /// Chunks of 1 MiB the image may be read ahead of each target
+    #[clap(long, default_value = "16")]
+    queue_depth: usize,
+    /// Ceiling in MiB on the image data held in memory while flashing
+    #[clap(long, default_value = "256")]
+    memory_limit: u64,
+    /// Write to block devices through the page cache instead of with O_DIRECT
+    #[clap(long)]
+    no_direct_io: bool,
+    /// Size in KiB of each write, rounded up to whole erase blocks on SD cards
+    #[clap(long, default_value = "1024")]
+    write_size: usize,
+    /// How to write the targets, io-uring falls back to threads where it is unavailable
+    #[clap(long, value_enum, default_value = "threads")]
+    io_backend: pipeline::IoBackend,
+    /// Writes kept in flight on each target with the io-uring backend
+    #[clap(long, default_value = "8")]
+    uring_depth: usize,
+    /// Threads decompressing the image, 0 for one per core
+    #[clap(long, default_value = "0")]
+    threads: usize,
+    /// When to sync the targets: bytes[:MiB], interval[:ms], dirty[:MiB] (sync_file_range) or o-sync
+    #[clap(long, default_value = "bytes:32")]
+    sync_policy: writeback::SyncPolicy,
+    /// Sync policy for a single target, overriding --sync-policy; may be repeated
+    #[clap(long, value_name = "PATH=POLICY")]
+    device_sync_policy: Vec<writeback::DeviceSyncPolicy>,
+    /// Most MiB/s written to each target, 0 for no limit
+    #[clap(long, default_value = "0")]
+    rate_limit: f64,
+    /// Most MiB/s written to all targets together, 0 for no limit
+    #[clap(long, default_value = "0")]
+    total_rate_limit: f64,
+    /// Rate limit in MiB/s for a single target, overriding --rate-limit; may be repeated
+    #[clap(long, value_name = "PATH=MiB/s")]
+    device_rate_limit: Vec<ratelimit::DeviceRateLimit>,
+}
+
+impl Args {
+    fn retry_policy(&self) -> recovery::RetryPolicy {
+        recovery::RetryPolicy {
+            max_retries: self.retries,
+            backoff: Duration::from_millis(self.retry_backoff_ms),
+            block_size: self.retry_block_size.max(512),
+            on_bad_sector: self.on_bad_sector,
+        }
+    }
+
+    fn backup_dir(&self) -> Option<std::path::PathBuf> {
+        if self.no_backup {
+            return None;
+        }
+        Some(self.backup_dir.as_ref().map_or_else(backup::default_backup_dir, Into::into))
+    }
+
+    fn pipeline_limits(&self) -> pipeline::PipelineLimits {
+        pipeline::PipelineLimits {
+            queue_depth: self.queue_depth,
+            memory_limit: self.memory_limit * 1024 * 1024,
+        }
+    }
+
+    fn discard_mode(&self) -> zeroing::DiscardMode {
+        if self.secure_discard {
+            zeroing::DiscardMode::Secure
+        } else if self.discard {
+            zeroing::DiscardMode::Discard
+        } else {
+            zeroing::DiscardMode::Off
+        }
+    }
+
+    fn device_sync_policies(&self) -> HashMap<String, writeback::SyncPolicy> {
+        self.device_sync_policy
+            .iter()
+            .map(|device| (device.path.clone(), device.policy))
+            .collect()
+    }
+
+    fn rate_limits(&self) -> ratelimit::RateLimits {
+        ratelimit::RateLimits::new(
+            ratelimit::from_mib(self.total_rate_limit),
+            ratelimit::from_mib(self.rate_limit),
+            self.device_rate_limit.iter().map(|device| (device.path.clone(), device.rate)).collect(),
+        )
+    }
+
+    fn unmount_policy(&self) -> mounts::UnmountPolicy {
+        mounts::UnmountPolicy::from_flags(self.unmount, self.lazy_unmount)
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Compare an already-flashed device against an image, up to the end of a
+    /// device the image was cut short for
+    Verify {
+        #[clap(short, long)]
+        image: String,
+        #[clap(short, long)]
+        device: String,
+        /// Treat all-zero regions of the image as unwritten holes and skip them
+        #[clap(long)]
+        ignore_holes: bool,
+        /// Threads decompressing the image, 0 for one per core
+        #[clap(long, default_value = "0")]
+        threads: usize,
+    },
+    /// Detect counterfeit devices that store less than they advertise
+    Probe {
+        #[clap(short, long)]
+        device: String,
+        /// Allow probing disks that hold the running system, swap or LVM/md members
+        #[clap(long)]
+        allow_system_disk: bool,
+        /// Unmount any mounted partitions of the device before probing
+        #[clap(long)]
+        unmount: bool,
+        /// Unmount lazily, detaching filesystems that are still busy
+        #[clap(long)]
+        lazy_unmount: bool,
+        /// Start without asking for confirmation, unless the device is not removable
+        #[clap(short, long)]
+        yes: bool,
+    },
+    /// Write a partition table backup taken before flashing back to its device
+    RestoreHeader {
+        /// Backup file, as printed after flashing
+        #[clap(short, long)]
+        backup: String,
+        /// Device to restore to, defaults to the one the backup was taken from
+        #[clap(short, long)]
+        device: Option<String>,
+        /// Start without asking for confirmation, unless the device is not removable
+        #[clap(short, long)]
+        yes: bool,
+        /// Allow restoring to disks that hold the running system, swap or LVM/md members
+        #[clap(long)]
+        allow_system_disk: bool,
+    },
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
 
+    if let Some(command) = args.command {
+        return run_command(command);
+    }
+
     if args.gui {
         gui::run_gui(args)?;
         return Ok(());
@@ -33,6 +257,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         std::process::exit(1);
     }
 
+    let pinned = confirm_targets(&[&args.device_path], args.yes, args.dry_run)?;
+
     let progress = Arc::new(Mutex::new(fs::Progress::new(0)));
     let progress_clone = Arc::clone(&progress);
 
@@ -40,15 +266,280 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         update_progress_bar(progress_clone);
     });
 
-    fs::flash_images(&args.image_path, vec![&args.device_path], progress.clone())?;
+    let options = fs::FlashOptions {
+        probe_capacity: args.probe_capacity,
+        retry: args.retry_policy(),
+        allow_system_disk: args.allow_system_disk,
+        unmount: args.unmount_policy(),
+        allow_files: args.allow_file,
+        overwrite_files: args.overwrite_file,
+        pinned,
+        dry_run: args.dry_run,
+        backup_dir: args.backup_dir(),
+        zero_strategy: args.zero_blocks,
+        zero_granularity: args.zero_granularity.max(1) * 1024,
+        discard: args.discard_mode(),
+        pipeline: args.pipeline_limits(),
+        direct_io: !args.no_direct_io,
+        write_size: args.write_size * 1024,
+        io_backend: args.io_backend,
+        uring_depth: args.uring_depth,
+        threads: args.threads,
+        sync_policy: args.sync_policy,
+        device_sync_policies: args.device_sync_policies(),
+        rate_limits: Arc::new(args.rate_limits()),
+    };
+
+    let result = fs::flash_images(&args.image_path, vec![&args.device_path], progress.clone(), &options);
 
     println!();
 
-    println!("Completed in {:?}", progress.lock().unwrap().get_elapsed_time());
+    print_error_map(&progress.lock().unwrap());
+    result?;
+
+    let progress = progress.lock().unwrap();
+    if let Some(ref sha256) = progress.image_sha256 {
+        println!("Image SHA-256: {}", sha256);
+    }
+    if args.dry_run {
+        println!("Dry run: decoded {} bytes at {:.2} MB/s, nothing was written",
+                 progress.bytes_written, progress.get_speed_bytes() / 1_048_576.0);
+    }
+    println!("Completed in {:?}", progress.get_elapsed_time());
+
+    Ok(())
+}
+
+fn run_command(command: Command) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::Verify { image, device, ignore_holes, threads } => {
+            let progress = Arc::new(Mutex::new(fs::Progress::new(0)));
+            let progress_clone = Arc::clone(&progress);
+
+            thread::spawn(move || {
+                update_progress_bar(progress_clone);
+            });
+
+            let report = verify::verify_image(&image, &device, ignore_holes, threads, progress.clone())?;
+
+            println!();
+
+            println!("Compared {} bytes, skipped {} bytes of holes",
+                     report.bytes_compared, report.bytes_skipped);
+
+            if let Some(truncated_at) = report.truncated_at {
+                println!("{} is smaller than the image, it was compared up to its end at {} bytes \
+                          with the backup GPT moved there", device, truncated_at);
+            }
+
+            if report.device_too_small {
+                eprintln!("Error: {} is smaller than the image", device);
+            }
+
+            if report.mismatched_chunks > 0 {
+                eprintln!("Error: {} mismatched chunk(s), first difference at byte {}",
+                          report.mismatched_chunks, report.first_mismatch.unwrap_or(0));
+            }
+
+            if !report.is_match() {
+                eprintln!("Verification failed");
+                std::process::exit(1);
+            }
+
+            println!("Verification passed in {:?}", progress.lock().unwrap().get_elapsed_time());
+        }
+        Command::Probe { device, allow_system_disk, unmount, lazy_unmount, yes } => {
+            // probing overwrites blocks all over the device, so it gets the
+            // checks a flash does; an existing file may be probed, but is never created
+            blockdev::check_target(&device, true, true)?;
+            blockdev::check_writable(&device)?;
+            policy::check_device(&device)?;
+            if !allow_system_disk {
+                protect::check_not_system_disk(&device)?;
+            }
+            let pinned = confirm_targets(&[&device], yes, false)?;
+            fs::check_identity(&device, &pinned)?;
+            mounts::ensure_unmounted(&device, mounts::UnmountPolicy::from_flags(unmount, lazy_unmount))?;
+
+            let progress = Arc::new(Mutex::new(fs::Progress::new(0)));
+            let progress_clone = Arc::clone(&progress);
+
+            thread::spawn(move || {
+                update_progress_bar(progress_clone);
+            });
+
+            let report = probe::probe_capacity(&device, progress)?;
+
+            println!();
+
+            println!("Advertised capacity: {} bytes", report.advertised_bytes);
+            println!("Usable capacity:     {} bytes", report.usable_bytes);
+
+            if !report.is_genuine() {
+                eprintln!("Error: {} is counterfeit, only the first {} bytes can be written safely",
+                          device, report.usable_bytes);
+                std::process::exit(1);
+            }
+
+            println!("{} stores its full advertised capacity", device);
+        }
+        Command::RestoreHeader { backup, device, yes, allow_system_disk } => {
+            let manifest = backup::read_manifest(&mut std::io::BufReader::new(std::fs::File::open(&backup)?))?;
+            let device = device.unwrap_or_else(|| manifest.device.clone());
+
+            println!("Backup of {} taken at {}", manifest.device, manifest.created);
+            if let Some(ref serial) = manifest.serial {
+                println!("  Serial:     {}", serial);
+            }
+            println!("  Size:       {} bytes", manifest.size);
+
+            blockdev::check_writable(&device)?;
+            policy::check_device(&device)?;
+            if !allow_system_disk {
+                protect::check_not_system_disk(&device)?;
+            }
+            confirm_targets(&[&device], yes, false)?;
+            mounts::ensure_unmounted(&device, mounts::UnmountPolicy::Refuse)?;
+            backup::restore_backup(&backup, &device)?;
+
+            let restored: u64 = manifest.regions.iter().map(|r| r.length).sum();
+            println!("Restored {} bytes of {} from {}", restored, device, backup);
+        }
+    }
 
     Ok(())
 }
 
+/// Print what is about to be overwritten and have the user confirm it by
+/// typing the device names, unless this is a dry run. Returns the identity of
+/// every target as shown, so flashing can make sure it is still the same device.
+fn confirm_targets(
+    device_paths: &[&str],
+    yes: bool,
+    dry_run: bool,
+) -> Result<HashMap<String, blockdev::DeviceIdentity>, Box<dyn std::error::Error>> {
+    use std::io::{self, BufRead, IsTerminal, Write};
+
+    let mut pinned = HashMap::new();
+    let mut needs_confirmation = Vec::new();
+
+    for &path in device_paths {
+        if std::fs::metadata(path).map_or(true, |m| m.is_file()) {
+            println!("Target {} (regular file)", path);
+            continue;
+        }
+
+        let device = fs::device_info(path);
+        println!("Target {}", path);
+        println!("  Device:     {}", device.name);
+        if let Some(ref identity) = device.identity {
+            if let Some(ref serial) = identity.serial {
+                println!("  Serial:     {}", serial);
+            }
+            if let Some(ref wwn) = identity.wwn {
+                println!("  WWN:        {}", wwn);
+            }
+            if let Some(ref bus) = identity.bus {
+                println!("  Bus:        {}", bus);
+            }
+            println!("  Size:       {} ({} bytes)", device.size, identity.size);
+            pinned.insert(path.to_string(), identity.clone());
+        } else {
+            println!("  Size:       {}", device.size);
+        }
+        println!("  Removable:  {}", if device.removable { "yes" } else { "no" });
+        if device.read_only {
+            println!("  Read-only:  yes (write-protected)");
+        }
+
+        let partitions = blockdev::partitions(path);
+        if !partitions.is_empty() {
+            println!("  Partitions: {}", partitions.join(", "));
+        }
+        for mount in mounts::mounted_partitions(path) {
+            println!("  Mounted:    {} on {}", mount.source, mount.mount_point);
+        }
+        if let Some(ref reason) = device.protected {
+            println!("  System disk: {}", reason);
+        }
+        if let Some(ref reason) = device.disallowed {
+            println!("  Policy:     {}", reason);
+        }
+
+        if !dry_run && (!yes || !device.removable) {
+            needs_confirmation.push((path, device.removable));
+        }
+    }
+
+    for (path, removable) in needs_confirmation {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        if !io::stdin().is_terminal() {
+            let reason = if removable { "pass --yes to skip it" } else { "it is not removable" };
+            return Err(format!(
+                "{} needs to be confirmed interactively ({}), but stdin is not a terminal", path, reason
+            ).into());
+        }
+        if !removable {
+            println!("{} is not removable, it has to be confirmed even with --yes", path);
+        }
+
+        print!("All data on {} will be lost. Type \"{}\" to continue: ", path, name);
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        io::stdin().lock().read_line(&mut answer)?;
+
+        let answer = answer.trim();
+        if answer != name && answer != path {
+            return Err(format!("Aborted, {} was not confirmed", path).into());
+        }
+    }
+
+    Ok(pinned)
+}
+
+fn print_error_map(progress: &fs::Progress) {
+    for device in &progress.devices {
+        if let Some(ref backup) = device.backup {
+            println!("The previous partition table of {} was saved to {}, \
+                      restore it with: ferrisflash restore-header -b {}",
+                     device.path, backup.display(), backup.display());
+        }
+
+        if let Some(ref warning) = device.backup_warning {
+            eprintln!("Warning: {} was backed up, but {}", device.path, warning);
+        }
+
+        if let Some(discard) = device.discard {
+            println!("{} was {}", device.path, discard);
+        }
+
+        if let Some(zero_strategy) = device.zero_strategy {
+            println!("Zero blocks on {} were {}", device.path, zero_strategy);
+        }
+
+        if let Some(sync_policy) = device.sync_policy {
+            println!("{} was {}, {} sync(s) took {:.1}s",
+                     device.path, sync_policy, device.syncs, device.sync_time.as_secs_f32());
+        }
+
+        if let Some(truncated_at) = device.truncated_at {
+            println!("{} is smaller than the image, trailing space past the last partition \
+                      was dropped at {} bytes", device.path, truncated_at);
+        }
+
+        if device.error_map.is_empty() {
+            continue;
+        }
+
+        let ranges: Vec<String> = device.error_map
+            .iter()
+            .map(|r| format!("{}-{}", r.start, r.end - 1))
+            .collect();
+        eprintln!("Warning: {} sector(s) on {} could not be written, LBAs {}",
+                  device.failed_sectors(), device.path, ranges.join(", "));
+    }
+}
+
 fn update_progress_bar(progress: Arc<Mutex<fs::Progress>>) {
     use std::io::{self, Write};
     loop {
@@ -57,8 +548,15 @@ fn update_progress_bar(progress: Arc<Mutex<fs::Progress>>) {
         let speed = progress_guard.get_speed_bytes() / 1_048_576.0;
 
         print!("\r\x1B[2K");
+        if let Some(ref device) = progress_guard.discarding {
+            print!("Discarding {} | ", device);
+        }
         print!("Progress: {:.2}% | Speed: {:.2} MB/s | Elapsed: {}s",
                 percent, speed, progress_guard.get_elapsed_time().as_secs());
+        let in_flight: usize = progress_guard.devices.iter().map(|d| d.in_flight).sum();
+        if in_flight > 0 {
+            print!(" | In flight: {}", in_flight);
+        }
         io::stdout().flush().unwrap();
 
         drop(progress_guard);
@@ -0,0 +1,289 @@
+use std::io;
+use std::path::Path;
+
+use crate::blockdev;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum UnmountPolicy {
+    /// Refuse to flash a device that has mounted partitions
+    #[default]
+    Refuse,
+    Unmount,
+    /// Detach the filesystems even while they are still in use
+    Lazy,
+}
+
+impl UnmountPolicy {
+    /// The policy picked by `--unmount` and `--lazy-unmount`.
+    pub fn from_flags(unmount: bool, lazy: bool) -> Self {
+        if lazy {
+            UnmountPolicy::Lazy
+        } else if unmount {
+            UnmountPolicy::Unmount
+        } else {
+            UnmountPolicy::Refuse
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Mount {
+    pub source: String,
+    pub mount_point: String,
+    /// Block device backing the mount, if there is one
+    pub dev: Option<u64>,
+}
+
+/// Every mounted filesystem, in the order it was mounted.
+#[cfg(target_os = "linux")]
+pub fn mount_table() -> Vec<Mount> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let mountinfo = match std::fs::read_to_string("/proc/self/mountinfo") {
+        Ok(mountinfo) => mountinfo,
+        Err(_) => return Vec::new(),
+    };
+
+    // "id parent major:minor root mountpoint ... - fstype source options"
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(' ').collect();
+            if fields.len() < 5 {
+                return None;
+            }
+
+            let source = fields
+                .iter()
+                .position(|&f| f == "-")
+                .and_then(|i| fields.get(i + 2))
+                .map(|s| unescape(s))
+                .unwrap_or_default();
+
+            // btrfs and friends report an anonymous 0:N device, use the source instead
+            let dev = blockdev::parse_dev(fields[2])
+                .filter(|&dev| blockdev::major(dev) != 0)
+                .or_else(|| {
+                    std::fs::metadata(&source)
+                        .ok()
+                        .filter(|m| m.file_type().is_block_device())
+                        .map(|m| m.rdev())
+                });
+
+            Some(Mount {
+                source,
+                mount_point: unescape(fields[4]),
+                dev,
+            })
+        })
+        .collect()
+}
+
+#[cfg(target_os = "macos")]
+pub fn mount_table() -> Vec<Mount> {
+    let output = match std::process::Command::new("mount").output() {
+        Ok(output) if output.status.success() => output,
+        _ => return Vec::new(),
+    };
+
+    // "/dev/disk2s1 on /Volumes/NO NAME (msdos, local, nodev, nosuid, noowners)"
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let (source, rest) = line.split_once(" on ")?;
+            let mount_point = rest.rsplit_once(" (").map_or(rest, |(mp, _)| mp);
+            Some(Mount {
+                source: source.to_string(),
+                mount_point: mount_point.to_string(),
+                dev: None,
+            })
+        })
+        .collect()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn mount_table() -> Vec<Mount> {
+    Vec::new()
+}
+
+/// Filesystems mounted from any partition of the disk behind `device_path`,
+/// including ones stacked on top of it through dm or md.
+pub fn mounted_partitions<P: AsRef<Path>>(device_path: P) -> Vec<Mount> {
+    let disks = blockdev::disks_for_path(&device_path);
+    if disks.is_empty() {
+        return Vec::new();
+    }
+
+    mount_table()
+        .into_iter()
+        .filter(|mount| mount_disks(mount).iter().any(|disk| disks.contains(disk)))
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+fn mount_disks(mount: &Mount) -> Vec<String> {
+    mount
+        .dev
+        .map(|dev| blockdev::disks_backing(&blockdev::sysfs_for_dev(dev)))
+        .unwrap_or_default()
+}
+
+#[cfg(target_os = "macos")]
+fn mount_disks(mount: &Mount) -> Vec<String> {
+    match mount.source.strip_prefix("/dev/") {
+        Some(name) => vec![blockdev::whole_macos_disk(name)],
+        None => Vec::new(),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn mount_disks(_mount: &Mount) -> Vec<String> {
+    Vec::new()
+}
+
+/// Make sure nothing on the device is mounted before it gets opened for
+/// writing, unmounting according to `policy`.
+pub fn ensure_unmounted<P: AsRef<Path>>(device_path: P, policy: UnmountPolicy) -> io::Result<()> {
+    let mounts = mounted_partitions(&device_path);
+    if mounts.is_empty() {
+        return Ok(());
+    }
+
+    if policy == UnmountPolicy::Refuse {
+        return Err(io::Error::new(io::ErrorKind::ResourceBusy, format!(
+            "{} has mounted partitions ({}), unmount them first or pass --unmount",
+            device_path.as_ref().display(), describe(&mounts)
+        )));
+    }
+
+    unmount(&device_path, &mounts, policy == UnmountPolicy::Lazy)?;
+
+    let remaining = mounted_partitions(&device_path);
+    if !remaining.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::ResourceBusy, format!(
+            "{} still has mounted partitions after unmounting ({})",
+            device_path.as_ref().display(), describe(&remaining)
+        )));
+    }
+
+    Ok(())
+}
+
+pub fn describe(mounts: &[Mount]) -> String {
+    mounts
+        .iter()
+        .map(|m| format!("{} on {}", m.source, m.mount_point))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(target_os = "linux")]
+fn unmount<P: AsRef<Path>>(_device_path: P, mounts: &[Mount], lazy: bool) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::process::Command;
+
+    // newest first, so filesystems stacked on top of others go away before them
+    for mount in mounts.iter().rev() {
+        let target = CString::new(mount.mount_point.as_str())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let flags = if lazy { libc::MNT_DETACH } else { 0 };
+
+        if unsafe { libc::umount2(target.as_ptr(), flags) } == 0 {
+            continue;
+        }
+        let error = io::Error::last_os_error();
+
+        // desktop auto-mounts belong to udisks, which lets the user unmount them without root
+        if error.raw_os_error() == Some(libc::EPERM) {
+            let mut command = Command::new("udisksctl");
+            command.args(["unmount", "--block-device", &mount.source]);
+            if lazy {
+                command.arg("--force");
+            }
+            if command.output().map(|o| o.status.success()).unwrap_or(false) {
+                continue;
+            }
+        }
+
+        return Err(io::Error::new(error.kind(), format!(
+            "Could not unmount {} from {}: {}", mount.source, mount.mount_point, error
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn unmount<P: AsRef<Path>>(device_path: P, _mounts: &[Mount], lazy: bool) -> io::Result<()> {
+    let mut command = std::process::Command::new("diskutil");
+    command.arg("unmountDisk");
+    if lazy {
+        command.arg("force");
+    }
+
+    let output = command.arg(device_path.as_ref()).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "Could not unmount {}: {}",
+            device_path.as_ref().display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn unmount<P: AsRef<Path>>(device_path: P, _mounts: &[Mount], _lazy: bool) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, format!(
+        "Unmounting {} is not supported on this platform",
+        device_path.as_ref().display()
+    )))
+}
+
+/// Undo the octal escapes (`\040` for a space) used in mountinfo.
+#[cfg(target_os = "linux")]
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let is_escape = bytes[i] == b'\\'
+            && i + 4 <= bytes.len()
+            && (b'0'..=b'3').contains(&bytes[i + 1])
+            && bytes[i + 2..i + 4].iter().all(|b| (b'0'..=b'7').contains(b));
+
+        if is_escape {
+            out.push((bytes[i + 1] - b'0') * 64 + (bytes[i + 2] - b'0') * 8 + (bytes[i + 3] - b'0'));
+            i += 4;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8_lossy(&out).to_string()
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unescapes_mountinfo_fields() {
+        assert_eq!(unescape("/media/user/NO\\040NAME"), "/media/user/NO NAME");
+        assert_eq!(unescape("tab\\011and\\012newline"), "tab\tand\nnewline");
+        assert_eq!(unescape("back\\134slash"), "back\\slash");
+        assert_eq!(unescape("/mnt/caf\\303\\251"), "/mnt/caf\u{e9}");
+        assert_eq!(unescape("/dev/sdb1"), "/dev/sdb1");
+    }
+
+    #[test]
+    fn leaves_anything_that_is_not_an_escape() {
+        assert_eq!(unescape("short\\04"), "short\\04");
+        assert_eq!(unescape("not\\08octal"), "not\\08octal");
+        assert_eq!(unescape("too\\777big"), "too\\777big");
+        assert_eq!(unescape("\\"), "\\");
+    }
+}
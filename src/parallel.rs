@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::sparse::ImageRead;
+
+/// Pieces that decode to more than this are decoded by the reading thread as
+/// they are read, rather than held in memory whole
+const SEGMENT_LIMIT: u64 = 64 * 1024 * 1024;
+
+/// Threads to decode the image with: as many as asked for, or one per core.
+pub fn thread_count(requested: usize) -> usize {
+    match requested {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    }
+}
+
+/// Runs numbered jobs on a pool of threads and hands their results out in
+/// order. Workers stay no more than a job per thread ahead of the result
+/// being waited for, which bounds what is held in memory.
+pub struct OrderedPool<T> {
+    shared: Arc<Shared<T>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+struct Shared<T> {
+    schedule: Mutex<Schedule<T>>,
+    /// Signals the reading thread that a job is done
+    done: Condvar,
+    /// Signals the workers that a result was taken, or that they should stop
+    taken: Condvar,
+}
+
+struct Schedule<T> {
+    next_job: usize,
+    /// Results before this one have been taken
+    taken: usize,
+    ahead: usize,
+    /// None for a job whose worker panicked
+    results: HashMap<usize, Option<T>>,
+    cancelled: bool,
+}
+
+impl<T: Send + 'static> OrderedPool<T> {
+    pub fn new<F>(jobs: usize, threads: usize, job: F) -> Self
+    where
+        F: Fn(usize) -> T + Send + Sync + 'static,
+    {
+        let threads = threads.clamp(1, jobs.max(1));
+        let shared = Arc::new(Shared {
+            schedule: Mutex::new(Schedule {
+                next_job: 0,
+                taken: 0,
+                ahead: threads + 1,
+                results: HashMap::new(),
+                cancelled: false,
+            }),
+            done: Condvar::new(),
+            taken: Condvar::new(),
+        });
+
+        let job = Arc::new(job);
+        let workers = (0..threads)
+            .map(|_| {
+                let (shared, job) = (shared.clone(), job.clone());
+                thread::spawn(move || shared.run(jobs, &*job))
+            })
+            .collect();
+
+        OrderedPool { shared, workers }
+    }
+
+    /// Wait for the result of a job, or None if its worker panicked. Results
+    /// have to be taken in order.
+    pub fn take(&self, index: usize) -> Option<T> {
+        let mut schedule = self.shared.schedule.lock().unwrap();
+        loop {
+            if let Some(result) = schedule.results.remove(&index) {
+                schedule.taken = index + 1;
+                self.shared.taken.notify_all();
+                return result;
+            }
+            schedule = self.shared.done.wait(schedule).unwrap();
+        }
+    }
+}
+
+impl<T> Shared<T> {
+    fn run(&self, jobs: usize, job: &dyn Fn(usize) -> T) {
+        loop {
+            let index = {
+                let mut schedule = self.schedule.lock().unwrap();
+                while !schedule.cancelled
+                    && schedule.next_job < jobs
+                    && schedule.next_job >= schedule.taken + schedule.ahead
+                {
+                    schedule = self.taken.wait(schedule).unwrap();
+                }
+                if schedule.cancelled || schedule.next_job >= jobs {
+                    return;
+                }
+                schedule.next_job += 1;
+                schedule.next_job - 1
+            };
+
+            let result = panic::catch_unwind(AssertUnwindSafe(|| job(index))).ok();
+            self.schedule.lock().unwrap().results.insert(index, result);
+            self.done.notify_all();
+        }
+    }
+}
+
+impl<T> Drop for OrderedPool<T> {
+    fn drop(&mut self) {
+        self.shared.schedule.lock().unwrap().cancelled = true;
+        self.shared.taken.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// An image made of pieces that decode independently of each other, such as
+/// zstd frames or xz blocks.
+pub trait Segments: Send + Sync + 'static {
+    fn count(&self) -> usize;
+    /// Decoded size of a piece, if it is known upfront
+    fn decoded_size(&self, index: usize) -> Option<u64>;
+    /// A decoder for a single piece
+    fn open(&self, index: usize) -> io::Result<Box<dyn Read + Send>>;
+}
+
+/// Decodes the pieces of an image on a pool of threads, a whole piece at a
+/// time, and reads them back in order. A piece too big to hold in memory is
+/// decoded by the reading thread as it is read instead.
+pub struct SegmentDecoder<S> {
+    segments: Arc<S>,
+    pool: OrderedPool<Option<io::Result<Vec<u8>>>>,
+    next: usize,
+    ready: Vec<u8>,
+    ready_pos: usize,
+    current: Option<Box<dyn Read + Send>>,
+}
+
+impl<S: Segments> SegmentDecoder<S> {
+    pub fn new(segments: S, threads: usize) -> Self {
+        let segments = Arc::new(segments);
+        let shared = segments.clone();
+        let pool = OrderedPool::new(segments.count(), threads, move |index| decode_segment(&*shared, index));
+
+        SegmentDecoder {
+            segments,
+            pool,
+            next: 0,
+            ready: Vec::new(),
+            ready_pos: 0,
+            current: None,
+        }
+    }
+}
+
+impl<S: Segments> ImageRead for SegmentDecoder<S> {}
+
+impl<S: Segments> Read for SegmentDecoder<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.ready_pos < self.ready.len() {
+                let len = buf.len().min(self.ready.len() - self.ready_pos);
+                buf[..len].copy_from_slice(&self.ready[self.ready_pos..self.ready_pos + len]);
+                self.ready_pos += len;
+                return Ok(len);
+            }
+            if let Some(reader) = self.current.as_mut() {
+                match reader.read(buf)? {
+                    0 => self.current = None,
+                    n => return Ok(n),
+                }
+            }
+            if self.next == self.segments.count() {
+                return Ok(0);
+            }
+
+            match self.pool.take(self.next).flatten() {
+                Some(result) => {
+                    self.ready = result?;
+                    self.ready_pos = 0;
+                }
+                None => self.current = Some(self.segments.open(self.next)?),
+            }
+            self.next += 1;
+        }
+    }
+}
+
+/// Decode a piece whole, unless it turns out too big to hold in memory.
+fn decode_segment<S: Segments>(segments: &S, index: usize) -> Option<io::Result<Vec<u8>>> {
+    let size = segments.decoded_size(index);
+    if size.is_some_and(|size| size > SEGMENT_LIMIT) {
+        return None;
+    }
+
+    let mut data = Vec::with_capacity(size.unwrap_or(0) as usize);
+    let result = segments
+        .open(index)
+        .and_then(|reader| reader.take(SEGMENT_LIMIT + 1).read_to_end(&mut data));
+    match result {
+        Ok(_) if data.len() as u64 > SEGMENT_LIMIT => None,
+        Ok(_) => Some(Ok(data)),
+        Err(e) => Some(Err(e)),
+    }
+}
+
+/// Reads part of a file that other threads read at the same time.
+pub struct FileRange {
+    file: Arc<File>,
+    offset: u64,
+    end: u64,
+}
+
+impl FileRange {
+    pub fn new(file: Arc<File>, offset: u64, len: u64) -> Self {
+        FileRange { file, offset, end: offset + len }
+    }
+}
+
+impl Read for FileRange {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min((self.end - self.offset) as usize);
+        let n = read_at(&self.file, &mut buf[..len], self.offset)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(unix)]
+pub fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+pub fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+pub fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match read_at(file, buf, offset) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,296 @@
+use std::ops::Range;
+
+const SECTOR_SIZE: u64 = 512;
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const MBR_PROTECTIVE_TYPE: u8 = 0xEE;
+
+/// Smallest device an image fits on once everything past its last partition
+/// is dropped, keeping room for the backup GPT at the end of the device.
+/// `None` when `header` holds no partition table, or not all of it.
+pub fn required_size(header: &[u8]) -> Option<u64> {
+    if let Some(gpt) = Gpt::parse(header) {
+        let last_used = gpt.entries().filter_map(|entry| {
+            let first = read_u64(entry, 32);
+            let last = read_u64(entry, 40);
+            (first > 0 && last >= first).then_some(last)
+        }).max()?;
+
+        // the backup partition array and header follow the last partition
+        return last_used.checked_add(gpt.entry_sectors() + 2)?.checked_mul(SECTOR_SIZE);
+    }
+
+    if !has_mbr_signature(header) {
+        return None;
+    }
+
+    let mut end = None;
+    for entry in header[446..510].chunks_exact(16) {
+        let start = read_u32(entry, 8) as u64;
+        let count = read_u32(entry, 12) as u64;
+        if entry[4] == MBR_PROTECTIVE_TYPE {
+            // a GPT disk whose partition array is not in `header`
+            return None;
+        }
+        if entry[4] != 0 && start > 0 && count > 0 {
+            end = end.max(Some((start + count) * SECTOR_SIZE));
+        }
+    }
+    end
+}
+
+/// Sectors to write so that a GPT image truncated to `device_size` is valid
+/// again: the backup array and header moved to the end of the device, plus the
+/// primary header and protective MBR updated to match. Returns `(offset, data)`
+/// pairs, or `None` when the image has no GPT or it does not fit the device.
+pub fn relocate_gpt(header: &[u8], device_size: u64) -> Option<Vec<(u64, Vec<u8>)>> {
+    let gpt = Gpt::parse(header)?;
+    let last_lba = (device_size / SECTOR_SIZE).checked_sub(1)?;
+    let backup_entry_lba = last_lba.checked_sub(gpt.entry_sectors())?;
+    let last_usable_lba = backup_entry_lba.checked_sub(1)?;
+
+    let mut primary = header[SECTOR_SIZE as usize..2 * SECTOR_SIZE as usize].to_vec();
+    primary[32..40].copy_from_slice(&last_lba.to_le_bytes()); // alternate LBA
+    primary[48..56].copy_from_slice(&last_usable_lba.to_le_bytes()); // last usable LBA
+
+    let mut backup = vec![0; SECTOR_SIZE as usize];
+    backup[..gpt.header_size].copy_from_slice(&primary[..gpt.header_size]);
+    backup[24..32].copy_from_slice(&last_lba.to_le_bytes()); // my LBA
+    backup[32..40].copy_from_slice(&1u64.to_le_bytes()); // alternate LBA
+    backup[72..80].copy_from_slice(&backup_entry_lba.to_le_bytes()); // partition entry LBA
+
+    update_header_crc(&mut primary, gpt.header_size);
+    update_header_crc(&mut backup, gpt.header_size);
+
+    let mut entries = gpt.entry_array().to_vec();
+    entries.resize((gpt.entry_sectors() * SECTOR_SIZE) as usize, 0);
+
+    let mut writes = Vec::new();
+
+    // the protective entry covers the whole disk, capped at what 32 bits can hold
+    let mut mbr = header[..SECTOR_SIZE as usize].to_vec();
+    if has_mbr_signature(&mbr) {
+        for entry in mbr[446..510].chunks_exact_mut(16) {
+            if entry[4] == MBR_PROTECTIVE_TYPE {
+                let sectors = last_lba.min(u32::MAX as u64) as u32;
+                entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+            }
+        }
+        writes.push((0, mbr));
+    }
+
+    writes.push((SECTOR_SIZE, primary));
+    writes.push((backup_entry_lba * SECTOR_SIZE, entries));
+    writes.push((last_lba * SECTOR_SIZE, backup));
+    Some(writes)
+}
+
+/// Byte range of the backup GPT (partition array and header) that the primary
+/// header in `header` points to, or `None` where that makes no sense, as it
+/// may not on a corrupt header.
+pub fn backup_gpt_range(header: &[u8]) -> Option<Range<u64>> {
+    let gpt = Gpt::parse(header)?;
+    let alternate_lba = read_u64(&header[SECTOR_SIZE as usize..], 32);
+    let start = alternate_lba.checked_sub(gpt.entry_sectors())?;
+    Some(start.checked_mul(SECTOR_SIZE)?..alternate_lba.checked_add(1)?.checked_mul(SECTOR_SIZE)?)
+}
+
+/// Whether `header` starts with a GPT whose partition array it holds.
+pub fn has_gpt(header: &[u8]) -> bool {
+    Gpt::parse(header).is_some()
+}
+
+/// The primary GPT header of an image and its partition array. Everything in
+/// it comes from the image or the device, so it is only trusted once its
+/// partition array is known to lie within `header`.
+struct Gpt<'a> {
+    header: &'a [u8],
+    header_size: usize,
+    entry_lba: u64,
+    entry_count: u64,
+    entry_size: u64,
+}
+
+impl<'a> Gpt<'a> {
+    fn parse(header: &'a [u8]) -> Option<Self> {
+        let primary = header.get(SECTOR_SIZE as usize..2 * SECTOR_SIZE as usize)?;
+        if &primary[..8] != GPT_SIGNATURE {
+            return None;
+        }
+
+        let gpt = Gpt {
+            header,
+            header_size: read_u32(primary, 12) as usize,
+            entry_lba: read_u64(primary, 72),
+            entry_count: read_u32(primary, 80) as u64,
+            entry_size: read_u32(primary, 84) as u64,
+        };
+
+        let entries_end = gpt.entry_lba
+            .checked_mul(SECTOR_SIZE)?
+            .checked_add(gpt.entry_count.checked_mul(gpt.entry_size)?)?;
+        let valid = (92..=SECTOR_SIZE as usize).contains(&gpt.header_size)
+            && gpt.entry_size >= 128
+            && entries_end <= header.len() as u64;
+        valid.then_some(gpt)
+    }
+
+    fn entry_array(&self) -> &'a [u8] {
+        let start = (self.entry_lba * SECTOR_SIZE) as usize;
+        &self.header[start..start + (self.entry_count * self.entry_size) as usize]
+    }
+
+    fn entries(&self) -> impl Iterator<Item = &'a [u8]> {
+        self.entry_array().chunks_exact(self.entry_size as usize)
+    }
+
+    fn entry_sectors(&self) -> u64 {
+        (self.entry_count * self.entry_size).div_ceil(SECTOR_SIZE)
+    }
+}
+
+fn update_header_crc(sector: &mut [u8], header_size: usize) {
+    sector[16..20].fill(0);
+    let crc = crc32fast::hash(&sector[..header_size]);
+    sector[16..20].copy_from_slice(&crc.to_le_bytes());
+}
+
+fn has_mbr_signature(header: &[u8]) -> bool {
+    header.len() >= SECTOR_SIZE as usize && header[510] == 0x55 && header[511] == 0xAA
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::gpt_image;
+
+    fn header_crc_holds(sector: &[u8]) -> bool {
+        let mut copy = sector.to_vec();
+        copy[16..20].fill(0);
+        crc32fast::hash(&copy[..92]) == read_u32(sector, 16)
+    }
+
+    #[test]
+    fn gpt_images_need_room_up_to_their_last_partition() {
+        let image = gpt_image(1 << 21, &[(2048, 4095), (4096, 100_000)]);
+        // the last partition, then 32 sectors of backup array and the backup header
+        assert_eq!(required_size(&image), Some((100_001 + 32 + 1) * SECTOR_SIZE));
+
+        assert_eq!(required_size(&gpt_image(1 << 21, &[])), None);
+        // the partition array is cut off
+        assert_eq!(required_size(&image[..20 * SECTOR_SIZE as usize]), None);
+    }
+
+    #[test]
+    fn mbr_images_need_room_up_to_their_last_partition() {
+        let mut image = vec![0; SECTOR_SIZE as usize];
+        let partitions = [(0x83u8, 2048u32, 4096u32), (0x0c, 8192, 1000), (0, 1 << 20, 1)];
+        for (i, (kind, start, count)) in partitions.into_iter().enumerate() {
+            let entry = &mut image[446 + i * 16..462 + i * 16];
+            entry[4] = kind;
+            entry[8..12].copy_from_slice(&start.to_le_bytes());
+            entry[12..16].copy_from_slice(&count.to_le_bytes());
+        }
+        assert_eq!(required_size(&image), None);
+
+        image[510..512].copy_from_slice(&[0x55, 0xAA]);
+        assert_eq!(required_size(&image), Some(9192 * SECTOR_SIZE));
+
+        // a protective MBR whose GPT is not in the header
+        image[446 + 4] = MBR_PROTECTIVE_TYPE;
+        assert_eq!(required_size(&image), None);
+    }
+
+    #[test]
+    fn relocated_gpt_is_valid_at_the_new_end() {
+        let image = gpt_image(1 << 21, &[(2048, 100_000)]);
+        let device_sectors = 200_000;
+        let writes = relocate_gpt(&image, device_sectors * SECTOR_SIZE).unwrap();
+        let offsets: Vec<u64> = writes.iter().map(|(offset, _)| offset / SECTOR_SIZE).collect();
+        assert_eq!(offsets, vec![0, 1, device_sectors - 33, device_sectors - 1]);
+
+        let (mbr, primary, entries, backup) = (&writes[0].1, &writes[1].1, &writes[2].1, &writes[3].1);
+        assert_eq!(read_u32(&mbr[446..], 12) as u64, device_sectors - 1);
+
+        assert!(header_crc_holds(primary));
+        assert_eq!(read_u64(primary, 32), device_sectors - 1);
+        assert_eq!(read_u64(primary, 48), device_sectors - 34);
+        assert_eq!(read_u64(primary, 72), 2);
+
+        assert!(header_crc_holds(backup));
+        assert_eq!(read_u64(backup, 24), device_sectors - 1);
+        assert_eq!(read_u64(backup, 32), 1);
+        assert_eq!(read_u64(backup, 72), device_sectors - 33);
+        assert_eq!(backup[92..], [0; 420]);
+
+        // the array is moved unchanged, so its CRC in both headers still holds
+        assert_eq!(entries.len(), 32 * SECTOR_SIZE as usize);
+        assert_eq!(&entries[..], &image[1024..]);
+        assert_eq!(crc32fast::hash(entries), read_u32(backup, 88));
+
+        // and the relocated primary header points at the relocated backup
+        let mut relocated = image.clone();
+        relocated[512..1024].copy_from_slice(primary);
+        let backup_start = (device_sectors - 33) * SECTOR_SIZE;
+        assert_eq!(backup_gpt_range(&relocated), Some(backup_start..device_sectors * SECTOR_SIZE));
+    }
+
+    #[test]
+    fn corrupt_alternate_lbas_have_no_backup_range() {
+        let image = gpt_image(1 << 21, &[(2048, 4095)]);
+        let with_alternate = |lba: u64| {
+            let mut image = image.clone();
+            image[512 + 32..512 + 40].copy_from_slice(&lba.to_le_bytes());
+            image
+        };
+
+        assert_eq!(backup_gpt_range(&image), Some(((1 << 21) - 33) * SECTOR_SIZE..(1 << 21) * SECTOR_SIZE));
+        assert!(has_gpt(&image));
+        for lba in [u64::MAX, u64::MAX / SECTOR_SIZE, 31] {
+            assert_eq!(backup_gpt_range(&with_alternate(lba)), None);
+        }
+    }
+
+    #[test]
+    fn only_gpt_images_are_relocated() {
+        let mut image = gpt_image(1 << 21, &[(2048, 4095)]);
+        image[512] = b'X';
+        assert!(relocate_gpt(&image, 1 << 30).is_none());
+        assert!(backup_gpt_range(&image).is_none());
+        assert!(!has_gpt(&image));
+    }
+
+    #[test]
+    fn hostile_gpt_headers_are_rejected() {
+        let image = gpt_image(1 << 21, &[(2048, 4095)]);
+        let with = |offset: usize, value: u64| {
+            let mut image = image.clone();
+            image[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+            image
+        };
+
+        // partition array far past the end, entry count and size multiplying past u64
+        for hostile in [with(512 + 72, u64::MAX / 256), with(512 + 72, u64::MAX), with(512 + 80, u64::MAX)] {
+            assert_eq!(required_size(&hostile), None);
+            assert!(relocate_gpt(&hostile, 1 << 30).is_none());
+        }
+
+        // a partition that ends at the very last LBA leaves no room for the backup
+        let hostile = with(1024 + 40, u64::MAX);
+        assert_eq!(required_size(&hostile), None);
+        assert_eq!(required_size(&with(1024 + 40, u64::MAX / SECTOR_SIZE)), None);
+
+        // a device too small to hold the partition array at its end
+        assert!(relocate_gpt(&image, 0).is_none());
+        assert!(relocate_gpt(&image, 16 * SECTOR_SIZE).is_none());
+        assert!(relocate_gpt(&image, 33 * SECTOR_SIZE).is_none());
+        assert!(relocate_gpt(&image, 34 * SECTOR_SIZE).is_some());
+    }
+}
@@ -0,0 +1,263 @@
+use std::io::{self, Read};
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+
+use crate::blockdev::AlignedBuffer;
+use crate::fs::{self, DeviceWriter, FlashOptions, Progress};
+use crate::partition;
+use crate::recovery::SyncWindow;
+use crate::sparse::ImageRead;
+
+/// The image is read and handed to the devices in pieces of this size
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+/// Devices are synced at least this often
+const SYNC_BYTES: u64 = 1024 * 1024 * 32;
+
+/// A piece of the decoded image, shared by every device it is sent to. It is
+/// page aligned, so it can be written with O_DIRECT as it is.
+pub type Chunk = Arc<AlignedBuffer>;
+
+/// What the reading thread tells each device's writer thread.
+#[derive(Clone)]
+pub enum Message {
+    /// The next piece of the image
+    Data(Chunk),
+    /// A run of zeros the image does not store, such as a hole in a sparse
+    /// file, which the device gets the way its zero strategy says
+    Hole(u64),
+    /// Size of a compressed image, once its partition table gives it away
+    ImageSize(u64),
+    /// Smallest device the image fits on once the space past its last
+    /// partition is dropped
+    RequiredSize(u64),
+    /// All of the image has been sent, along with its first 64 KiB
+    Finish(Arc<Vec<u8>>),
+}
+
+/// How the devices are written.
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+pub enum IoBackend {
+    /// A writer thread for every device, writing synchronously
+    #[default]
+    Threads,
+    /// One thread keeping many writes in flight on every device (Linux only)
+    IoUring,
+}
+
+/// How far the reader may run ahead of the devices.
+#[derive(Debug, Clone, Copy)]
+pub struct PipelineLimits {
+    /// Chunks queued for each device
+    pub queue_depth: usize,
+    /// Ceiling on the image data held in memory, in bytes
+    pub memory_limit: u64,
+}
+
+impl Default for PipelineLimits {
+    fn default() -> Self {
+        PipelineLimits {
+            queue_depth: 16,
+            memory_limit: 256 * 1024 * 1024,
+        }
+    }
+}
+
+impl PipelineLimits {
+    /// Most a device is written between syncs, whatever its sync policy.
+    /// Everything since the last sync stays in memory so it can be
+    /// rewritten, so this takes up to half of the memory limit.
+    pub fn sync_bytes(&self) -> u64 {
+        SYNC_BYTES.min(self.memory_limit / 2).max(CHUNK_SIZE as u64)
+    }
+
+    /// Chunks queued for each device. Chunks are shared, so what is in memory
+    /// is at most the slowest device's sync window, its queue and the chunk
+    /// being read; the queue gets whatever the window leaves of the limit.
+    pub fn queue_depth(&self) -> usize {
+        let room = self.memory_limit.saturating_sub(self.sync_bytes()) / CHUNK_SIZE as u64;
+        (room.saturating_sub(1) as usize).clamp(1, self.queue_depth.max(1))
+    }
+}
+
+/// Read and decode the image, sending it to every device that is still
+/// taking it. A device whose writer thread has gone away is dropped; once
+/// none are left, the job fails. `image_header` is the start of a raw image,
+/// read upfront. Holes the reader knows of are passed on without reading
+/// them, in pieces no bigger than a sync so progress keeps moving.
+pub fn produce(
+    reader: &mut dyn ImageRead,
+    senders: &mut [Option<SyncSender<Message>>],
+    image_header: &[u8],
+    is_compressed: bool,
+    progress: &Arc<Mutex<Progress>>,
+) -> io::Result<()> {
+    let mut header_buffer = image_header.to_vec();
+    let mut required_size = None;
+    let mut size_determined = false;
+    let mut total_read = 0u64;
+
+    loop {
+        let mut hole = reader.skip_hole()?;
+        while hole > 0 {
+            let piece = hole.min(SYNC_BYTES);
+            broadcast(senders, Message::Hole(piece));
+            hole -= piece;
+            total_read += piece;
+        }
+
+        let len = reader.data_ahead()?.min(CHUNK_SIZE as u64) as usize;
+        let mut buffer = AlignedBuffer::new(CHUNK_SIZE, 4096);
+        let bytes_read = read_full(reader, &mut buffer[..len])?;
+        if bytes_read == 0 {
+            break;
+        }
+        buffer.truncate(bytes_read);
+
+        // Accumulate the first 64KB, which holds the partition table
+        if is_compressed && header_buffer.len() < 65536 {
+            let bytes_to_add = (65536 - header_buffer.len()).min(bytes_read);
+            header_buffer.extend_from_slice(&buffer[..bytes_to_add]);
+
+            let required = partition::required_size(&header_buffer);
+            if let (Some(required), true) = (required, required != required_size) {
+                broadcast(senders, Message::RequiredSize(required));
+            }
+            required_size = required;
+
+            // 1024 bytes should be enough to check for MBR/GPT
+            if !size_determined && header_buffer.len() >= 1024 {
+                let img_size = fs::get_img_size_from_header(&header_buffer);
+                if img_size > 0 {
+                    progress.lock().unwrap().total_bytes = img_size;
+                    size_determined = true;
+                    broadcast(senders, Message::ImageSize(img_size));
+                }
+            }
+        }
+
+        broadcast(senders, Message::Data(Arc::new(buffer)));
+        total_read += bytes_read as u64;
+
+        // If we haven't determined the size yet, use streaming-style progress
+        if is_compressed && !size_determined {
+            progress.lock().unwrap().total_bytes = total_read + (total_read / 4).max(1024 * 1024);
+        }
+
+        if senders.iter().all(Option::is_none) {
+            return Err(fs::all_devices_failed(progress));
+        }
+    }
+
+    if is_compressed && !size_determined {
+        progress.lock().unwrap().total_bytes = total_read;
+    }
+
+    broadcast(senders, Message::Finish(Arc::new(header_buffer)));
+    Ok(())
+}
+
+/// Write everything the reading thread sends to one device, syncing it
+/// regularly, until the image is complete or the device fails. When the
+/// reading thread gives up halfway, the device is left as it is.
+pub fn run_writer(
+    mut device: DeviceWriter,
+    index: usize,
+    receiver: Receiver<Message>,
+    progress: &Arc<Mutex<Progress>>,
+    options: &FlashOptions,
+) {
+    let mut window = SyncWindow::new();
+
+    while let Ok(message) = receiver.recv() {
+        match message {
+            Message::Data(chunk) => {
+                options.rate_limits.throttle(&mut device.throttle, chunk.len() as u64);
+                device.write_chunk(index, chunk, &mut window, progress, options);
+                progress.lock().unwrap().device_progressed(index, window.end());
+                device.write_back(index, &mut window, progress, options);
+            }
+            Message::Hole(len) => {
+                let traffic = device.hole_traffic(len);
+                options.rate_limits.throttle(&mut device.throttle, traffic);
+                device.write_hole(index, len, &mut window, progress, options);
+                progress.lock().unwrap().device_progressed(index, window.end());
+                device.write_back(index, &mut window, progress, options);
+            }
+            Message::ImageSize(size) => device.check_capacity(index, size, progress),
+            Message::RequiredSize(required) => {
+                device.truncate = device.capacity.is_some_and(|capacity| capacity >= required);
+            }
+            Message::Finish(header) => {
+                device.finish_truncated(index, &header, window.end(), progress);
+                device.sync(index, &mut window, progress, options);
+                return;
+            }
+        }
+
+        if device.writer.is_none() {
+            return;
+        }
+    }
+}
+
+/// Send a message to every device still taking the image, forgetting the
+/// ones whose writer thread has stopped.
+fn broadcast(senders: &mut [Option<SyncSender<Message>>], message: Message) {
+    for sender in senders.iter_mut() {
+        if let Some(channel) = sender {
+            if channel.send(message.clone()).is_err() {
+                *sender = None;
+            }
+        }
+    }
+}
+
+/// Fill `buffer` unless the image ends first, since decoders tend to return
+/// much less than asked for.
+fn read_full(reader: &mut dyn Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn limits(queue_depth: usize, memory_limit: u64) -> PipelineLimits {
+        PipelineLimits { queue_depth, memory_limit }
+    }
+
+    #[test]
+    fn the_queue_gets_what_the_sync_window_leaves() {
+        // 256 MiB holds the full queue next to a 32 MiB window
+        assert_eq!(PipelineLimits::default().sync_bytes(), 32 * MIB);
+        assert_eq!(PipelineLimits::default().queue_depth(), 16);
+        assert_eq!(limits(1000, 256 * MIB).queue_depth(), 256 - 32 - 1);
+
+        // below 64 MiB the window takes half and the queue the rest, less the
+        // chunk being read
+        assert_eq!(limits(1000, 40 * MIB).sync_bytes(), 20 * MIB);
+        assert_eq!(limits(1000, 40 * MIB).queue_depth(), 19);
+        assert_eq!(limits(8, 40 * MIB).queue_depth(), 8);
+    }
+
+    #[test]
+    fn tiny_limits_still_leave_a_chunk_for_each() {
+        for memory_limit in [0, 1, MIB, 2 * MIB] {
+            let limits = limits(16, memory_limit);
+            assert_eq!(limits.sync_bytes(), CHUNK_SIZE as u64);
+            assert_eq!(limits.queue_depth(), 1);
+        }
+        assert_eq!(limits(0, 256 * MIB).queue_depth(), 1);
+    }
+}
@@ -0,0 +1,216 @@
+use std::io;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::fs::{self, DeviceInfo};
+
+/// Administrator-controlled restrictions on which devices may be written.
+pub const POLICY_PATH: &str = "/etc/ferrisflash/policy.toml";
+
+/// A device may be written if it matches no `deny` rule, and matches an
+/// `allow` rule whenever there are any.
+///
+/// ```toml
+/// [[allow]]
+/// bus = "usb"
+/// removable = true
+/// max_size = 68719476736
+///
+/// [[deny]]
+/// vendor = "Samsung"
+/// model = "Portable SSD T7"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default)]
+    pub allow: Vec<Rule>,
+    #[serde(default)]
+    pub deny: Vec<Rule>,
+}
+
+/// Every field that is set has to match. Text compares case-insensitively,
+/// sizes are in bytes.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub vendor: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    pub bus: Option<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub removable: Option<bool>,
+}
+
+impl Policy {
+    /// The policy at `POLICY_PATH`, or `None` when there is no policy file.
+    pub fn load() -> io::Result<Option<Policy>> {
+        let contents = match std::fs::read_to_string(POLICY_PATH) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(io::Error::new(e.kind(), format!("Could not read {}: {}", POLICY_PATH, e))),
+        };
+
+        toml::from_str(&contents)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid {}: {}", POLICY_PATH, e)))
+    }
+
+    /// Why the device may not be written, if it may not.
+    pub fn check(&self, device: &DeviceInfo) -> Result<(), String> {
+        if let Some(i) = self.deny.iter().position(|rule| rule.matches(device)) {
+            return Err(format!("denied by [[deny]] rule {} in {}", i + 1, POLICY_PATH));
+        }
+
+        if !self.allow.is_empty() && !self.allow.iter().any(|rule| rule.matches(device)) {
+            return Err(format!("not allowed by {}", POLICY_PATH));
+        }
+
+        Ok(())
+    }
+}
+
+impl Rule {
+    fn matches(&self, device: &DeviceInfo) -> bool {
+        let identity = device.identity.clone().unwrap_or_default();
+        let text_matches = |expected: &Option<String>, actual: &Option<String>| match (expected, actual) {
+            (None, _) => true,
+            (Some(expected), Some(actual)) => expected.trim().eq_ignore_ascii_case(actual.trim()),
+            (Some(_), None) => false,
+        };
+
+        // a device whose size is unknown satisfies no size limit
+        let size_known = device.identity.is_some();
+
+        text_matches(&self.vendor, &identity.vendor)
+            && text_matches(&self.model, &identity.model)
+            && text_matches(&self.serial, &identity.serial)
+            && text_matches(&self.bus, &identity.bus)
+            && self.min_size.is_none_or(|min| size_known && identity.size >= min)
+            && self.max_size.is_none_or(|max| size_known && identity.size <= max)
+            && self.removable.is_none_or(|removable| device.removable == removable)
+    }
+}
+
+/// Mark every device the policy forbids, with the reason. An unreadable
+/// policy forbids everything rather than nothing.
+pub fn apply(devices: &mut [DeviceInfo]) {
+    let policy = match Policy::load() {
+        Ok(Some(policy)) => policy,
+        Ok(None) => return,
+        Err(e) => {
+            for device in devices.iter_mut() {
+                device.disallowed = Some(e.to_string());
+            }
+            return;
+        }
+    };
+
+    for device in devices.iter_mut() {
+        device.disallowed = policy.check(device).err();
+    }
+}
+
+/// Enforce the policy on a target given by path. Regular files are not
+/// devices and are left to the file checks, as are paths that do not exist
+/// yet and can only become one. A path that cannot be looked at is refused
+/// rather than let past the policy.
+pub fn check_device<P: AsRef<Path>>(device_path: P) -> io::Result<()> {
+    match std::fs::metadata(&device_path) {
+        Ok(metadata) if metadata.is_file() => return Ok(()),
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(io::Error::new(e.kind(), format!(
+            "Could not check {} against {}: {}", device_path.as_ref().display(), POLICY_PATH, e
+        ))),
+    }
+
+    let Some(policy) = Policy::load()? else {
+        return Ok(());
+    };
+
+    let path = device_path.as_ref().display().to_string();
+    policy.check(&fs::device_info(&path)).map_err(|reason| {
+        io::Error::new(io::ErrorKind::PermissionDenied, format!("{} may not be written, {}", path, reason))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdev::DeviceIdentity;
+
+    fn device(vendor: &str, model: &str, bus: &str, size: u64, removable: bool) -> DeviceInfo {
+        DeviceInfo {
+            path: "/dev/sdx".to_string(),
+            name: model.to_string(),
+            size: "Unknown".to_string(),
+            device_type: "disk".to_string(),
+            removable,
+            read_only: false,
+            protected: None,
+            disallowed: None,
+            identity: Some(DeviceIdentity {
+                vendor: Some(vendor.to_string()),
+                model: Some(model.to_string()),
+                serial: Some("4C530001".to_string()),
+                wwn: None,
+                bus: Some(bus.to_string()),
+                size,
+            }),
+        }
+    }
+
+    fn policy(toml: &str) -> Policy {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn rule_fields_all_have_to_match() {
+        let stick = device("SanDisk", "Ultra ", "usb", 16 << 30, true);
+        let rule = |toml: &str| toml::from_str::<Rule>(toml).unwrap();
+
+        assert!(rule("").matches(&stick));
+        assert!(rule("vendor = 'sandisk'\nmodel = 'ultra'").matches(&stick));
+        assert!(rule("bus = 'usb'\nremovable = true\nmax_size = 17179869184").matches(&stick));
+        assert!(!rule("bus = 'usb'\nremovable = false").matches(&stick));
+        assert!(!rule("min_size = 17179869185").matches(&stick));
+        assert!(!rule("serial = '4C530002'").matches(&stick));
+
+        // nothing is known of a device without an identity, so it meets no
+        // condition on text or size
+        let unknown = DeviceInfo { identity: None, ..stick };
+        assert!(rule("removable = true").matches(&unknown));
+        assert!(!rule("vendor = 'SanDisk'").matches(&unknown));
+        assert!(!rule("max_size = 17179869184").matches(&unknown));
+    }
+
+    #[test]
+    fn deny_rules_win_over_allow_rules() {
+        let stick = device("SanDisk", "Ultra", "usb", 16 << 30, true);
+        let ssd = device("Samsung", "Portable SSD T7", "usb", 1 << 40, true);
+        let disk = device("ATA", "WDC WD10EZEX", "ata", 1 << 40, false);
+
+        let policy = policy("
+            [[allow]]
+            bus = 'usb'
+            removable = true
+
+            [[deny]]
+            vendor = 'Samsung'
+        ");
+        assert!(policy.check(&stick).is_ok());
+        assert!(policy.check(&ssd).unwrap_err().contains("[[deny]] rule 1"));
+        assert!(policy.check(&disk).unwrap_err().contains("not allowed"));
+    }
+
+    #[test]
+    fn without_allow_rules_only_deny_rules_apply() {
+        let disk = device("ATA", "WDC WD10EZEX", "ata", 1 << 40, false);
+        assert!(policy("").check(&disk).is_ok());
+        assert!(policy("[[deny]]\nbus = 'ata'").check(&disk).is_err());
+        assert!(toml::from_str::<Policy>("[[allow]]\nsize = 1").is_err());
+    }
+}
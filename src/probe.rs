@@ -0,0 +1,214 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::blockdev::{self, AlignedBuffer};
+use crate::fs::Progress;
+
+const PROBE_BLOCK_SIZE: usize = 4096;
+const PROBE_MAGIC: &[u8; 16] = b"FERRISFLASHPROBE";
+
+// roughly how many samples to spread over the advertised capacity, then over
+// the gap between the last good and first bad sample to narrow down the real size
+const COARSE_SAMPLES: u64 = 256;
+const FINE_SAMPLES: u64 = 128;
+
+pub struct CapacityReport {
+    pub advertised_bytes: u64,
+    pub usable_bytes: u64,
+}
+
+impl CapacityReport {
+    pub fn is_genuine(&self) -> bool {
+        self.usable_bytes >= self.advertised_bytes
+    }
+}
+
+/// Detect counterfeit flash that advertises more capacity than it stores.
+///
+/// Position-tagged blocks are written at sampled offsets across the whole
+/// device, highest first, so a drive that wraps around has its high writes
+/// overwritten by the low ones and fails the read back. Samples sit on a
+/// power-of-two stride, so when the wrap is modulo a power of two (as on the
+/// fakes seen in practice) every aliased block lands on another sample. The
+/// original contents of every sampled block are restored afterwards.
+pub fn probe_capacity<P: AsRef<Path>>(
+    device_path: P,
+    progress: Arc<Mutex<Progress>>,
+) -> io::Result<CapacityReport> {
+    let mut device = blockdev::open_uncached(&device_path)?;
+    let advertised_bytes = blockdev::device_size(&device)?;
+    let total_blocks = advertised_bytes / PROBE_BLOCK_SIZE as u64;
+
+    if total_blocks == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Device is too small to probe"));
+    }
+
+    let nonce = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+
+    let stride = sample_stride(total_blocks, COARSE_SAMPLES);
+    let mut coarse = strided_samples(0, total_blocks, stride);
+    coarse.push(total_blocks - 1);
+    coarse.dedup();
+
+    {
+        let mut progress = progress.lock().unwrap();
+        *progress = Progress::new(coarse.len() as u64 * PROBE_BLOCK_SIZE as u64);
+    }
+
+    let usable_bytes = match probe_round(&mut device, &coarse, nonce, &progress)? {
+        None => advertised_bytes,
+        Some(first_bad) => {
+            let low = coarse.iter().rev().find(|&&b| b < first_bad).map_or(0, |b| b + 1);
+            let first_bad = if low < first_bad {
+                // blocks above the real size alias to within `gap` of the start
+                let gap = first_bad - low;
+                let stride = sample_stride(gap, FINE_SAMPLES);
+                let mut fine = strided_samples(0, gap, stride);
+                fine.extend(strided_samples(low, first_bad, stride));
+                fine.sort_unstable();
+                fine.dedup();
+                progress.lock().unwrap().total_bytes += fine.len() as u64 * PROBE_BLOCK_SIZE as u64;
+                probe_round(&mut device, &fine, nonce, &progress)?.unwrap_or(first_bad)
+            } else {
+                first_bad
+            };
+            first_bad * PROBE_BLOCK_SIZE as u64
+        }
+    };
+
+    {
+        let mut progress = progress.lock().unwrap();
+        progress.bytes_written = progress.total_bytes;
+    }
+
+    Ok(CapacityReport {
+        advertised_bytes,
+        usable_bytes,
+    })
+}
+
+/// Smallest power-of-two stride that yields at most `count` samples over
+/// `blocks` blocks.
+fn sample_stride(blocks: u64, count: u64) -> u64 {
+    blocks.div_ceil(count).next_power_of_two()
+}
+
+/// Every multiple of `stride` in `low..high`.
+fn strided_samples(low: u64, high: u64, stride: u64) -> Vec<u64> {
+    (low.div_ceil(stride)..high.div_ceil(stride))
+        .map(|i| i * stride)
+        .collect()
+}
+
+/// Tag, read back and restore every sample. Returns the lowest block that
+/// did not hold its own tag, if any.
+fn probe_round(
+    device: &mut File,
+    samples: &[u64],
+    nonce: u64,
+    progress: &Arc<Mutex<Progress>>,
+) -> io::Result<Option<u64>> {
+    let mut buffer = AlignedBuffer::new(PROBE_BLOCK_SIZE, PROBE_BLOCK_SIZE);
+    let mut originals = Vec::with_capacity(samples.len());
+    let mut bad = vec![false; samples.len()];
+
+    for &block in samples {
+        let original = match read_block(device, block, &mut buffer) {
+            Ok(()) => Some(buffer.to_vec()),
+            Err(_) => None,
+        };
+        originals.push(original);
+    }
+
+    // highest first so that aliased high blocks lose to the real low ones
+    for (i, &block) in samples.iter().enumerate().rev() {
+        fill_pattern(&mut buffer, block, nonce);
+        if write_block(device, block, &buffer).is_err() {
+            bad[i] = true;
+        }
+    }
+    blockdev::drop_cache(device)?;
+
+    for (i, &block) in samples.iter().enumerate() {
+        if bad[i] {
+            continue;
+        }
+        let matches = read_block(device, block, &mut buffer).is_ok() && {
+            let read_back = buffer.to_vec();
+            fill_pattern(&mut buffer, block, nonce);
+            read_back[..] == buffer[..]
+        };
+        bad[i] = !matches;
+
+        let mut progress = progress.lock().unwrap();
+        progress.bytes_written += PROBE_BLOCK_SIZE as u64;
+    }
+
+    for (i, &block) in samples.iter().enumerate().rev() {
+        if let Some(original) = &originals[i] {
+            buffer.copy_from_slice(original);
+            // best effort; a block we could not write is already reported bad
+            let _ = write_block(device, block, &buffer);
+        }
+    }
+    device.sync_all()?;
+
+    Ok(samples.iter().zip(&bad).find(|(_, &is_bad)| is_bad).map(|(&block, _)| block))
+}
+
+fn read_block(device: &mut File, block: u64, buffer: &mut [u8]) -> io::Result<()> {
+    device.seek(SeekFrom::Start(block * PROBE_BLOCK_SIZE as u64))?;
+    device.read_exact(buffer)
+}
+
+fn write_block(device: &mut File, block: u64, buffer: &[u8]) -> io::Result<()> {
+    device.seek(SeekFrom::Start(block * PROBE_BLOCK_SIZE as u64))?;
+    device.write_all(buffer)
+}
+
+/// Fill a block with a pattern unique to this run and block number.
+fn fill_pattern(buffer: &mut [u8], block: u64, nonce: u64) {
+    buffer[..16].copy_from_slice(PROBE_MAGIC);
+    buffer[16..24].copy_from_slice(&block.to_le_bytes());
+
+    // xorshift64, seeded so that no two blocks share a tail
+    let mut state = (nonce ^ block.wrapping_mul(0x9E37_79B9_7F4A_7C15)) | 1;
+    for word in buffer[24..].chunks_exact_mut(8) {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        word.copy_from_slice(&state.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Noise, TempFile};
+
+    #[test]
+    fn probing_restores_every_sampled_block() {
+        let contents = Noise(1).bytes(4 * 1024 * 1024 + 512);
+        let file = TempFile::new(&contents);
+        let progress = Arc::new(Mutex::new(Progress::new(0)));
+
+        let report = probe_capacity(&file.path, progress).unwrap();
+        assert_eq!(report.advertised_bytes, contents.len() as u64);
+        assert!(report.is_genuine());
+        assert!(std::fs::read(&file.path).unwrap() == contents);
+    }
+
+    #[test]
+    fn samples_sit_on_a_power_of_two_stride() {
+        assert_eq!(sample_stride(1000, 256), 4);
+        assert_eq!(sample_stride(256, 256), 1);
+        assert_eq!(strided_samples(5, 20, 4), vec![8, 12, 16]);
+        assert!(strided_samples(9, 12, 4).is_empty());
+    }
+}
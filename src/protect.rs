@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use crate::blockdev;
+#[cfg(target_os = "linux")]
+use crate::mounts;
+
+/// Mount points whose backing disks are never written without an override.
+#[cfg(target_os = "linux")]
+const SYSTEM_MOUNTS: &[&str] = &["/", "/boot", "/boot/efi", "/efi", "/usr"];
+
+/// Refuse to write to a disk that holds the running system, active swap, or
+/// is a member of an active LVM/mdraid/dm device.
+pub fn check_not_system_disk<P: AsRef<Path>>(device_path: P) -> io::Result<()> {
+    let protected = protected_disks();
+
+    for disk in blockdev::disks_for_path(&device_path) {
+        if let Some(reason) = protected.get(&disk) {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!(
+                "{} is a system disk ({}), refusing to overwrite it without --allow-system-disk",
+                device_path.as_ref().display(), reason
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Whole disks that must not be written, keyed by kernel name (e.g. "sda",
+/// "nvme0n1") with a human readable reason.
+#[cfg(target_os = "linux")]
+pub fn protected_disks() -> HashMap<String, String> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut protected = HashMap::new();
+    let mut protect = |dev: u64, reason: String| {
+        for disk in system_disks_backing(dev) {
+            protected.entry(disk).or_insert_with(|| reason.clone());
+        }
+    };
+
+    for mount in mounts::mount_table() {
+        if let Some(dev) = mount.dev.filter(|_| SYSTEM_MOUNTS.contains(&mount.mount_point.as_str())) {
+            protect(dev, format!("holds {}", mount.mount_point));
+        }
+    }
+
+    // /proc/swaps: "Filename Type Size Used Priority"
+    if let Ok(swaps) = std::fs::read_to_string("/proc/swaps") {
+        for line in swaps.lines().skip(1) {
+            let mut fields = line.split_whitespace();
+            let (Some(filename), Some(swap_type)) = (fields.next(), fields.next()) else {
+                continue;
+            };
+
+            if let Ok(metadata) = std::fs::metadata(filename) {
+                // a swap file lives on whatever device holds its filesystem
+                let dev = if swap_type == "partition" { metadata.rdev() } else { metadata.dev() };
+                protect(dev, "holds active swap".to_string());
+            }
+        }
+    }
+
+    // disks with a partition (or themselves) held by dm/md are array or LVM members
+    if let Ok(entries) = std::fs::read_dir("/sys/block") {
+        for entry in entries.filter_map(|e| e.ok()) {
+            let disk = entry.file_name().to_string_lossy().to_string();
+            if protected.contains_key(&disk) {
+                continue;
+            }
+
+            let disk_dir = entry.path();
+            let mut dirs = vec![disk_dir.clone()];
+            if let Ok(children) = std::fs::read_dir(&disk_dir) {
+                dirs.extend(
+                    children
+                        .filter_map(|c| c.ok())
+                        .map(|c| c.path())
+                        .filter(|p| p.join("partition").exists()),
+                );
+            }
+
+            let holder = dirs.iter().find_map(|dir| {
+                std::fs::read_dir(dir.join("holders")).ok()?.filter_map(|h| h.ok()).next()
+            });
+
+            if let Some(holder) = holder {
+                protected.insert(disk, format!("member of {}", holder_name(&holder.path())));
+            }
+        }
+    }
+
+    protected
+}
+
+#[cfg(target_os = "macos")]
+pub fn protected_disks() -> HashMap<String, String> {
+    let mut protected = HashMap::new();
+
+    let output = match std::process::Command::new("diskutil").args(["info", "/"]).output() {
+        Ok(output) if output.status.success() => output,
+        _ => return protected,
+    };
+
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let line = line.trim();
+        let value = line.split(':').nth(1).unwrap_or("").trim();
+
+        // the APFS container sits on a physical store like disk0s2
+        if line.starts_with("Part of Whole:") || line.starts_with("APFS Physical Store:") {
+            protected.insert(blockdev::whole_macos_disk(value), "holds /".to_string());
+        }
+    }
+
+    protected
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn protected_disks() -> HashMap<String, String> {
+    HashMap::new()
+}
+
+/// Whole disks behind a device the system runs from. Unlike a flash target,
+/// loop devices are followed to the disk holding their backing file, since
+/// live systems run from a loop-mounted image on the boot stick.
+#[cfg(target_os = "linux")]
+fn system_disks_backing(dev: u64) -> Vec<String> {
+    use std::os::unix::fs::MetadataExt;
+
+    let sysfs_path = blockdev::sysfs_for_dev(dev);
+    if let Ok(backing_file) = std::fs::read_to_string(sysfs_path.join("loop/backing_file")) {
+        if let Ok(metadata) = std::fs::metadata(backing_file.trim()) {
+            return system_disks_backing(metadata.dev());
+        }
+    }
+
+    blockdev::disks_backing(&sysfs_path)
+}
+
+/// Friendly name of a holder, e.g. the LVM volume name rather than "dm-0".
+#[cfg(target_os = "linux")]
+fn holder_name(holder: &Path) -> String {
+    let kernel_name = holder
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    std::fs::read_to_string(format!("/sys/block/{}/dm/name", kernel_name))
+        .map(|name| name.trim().to_string())
+        .unwrap_or(kernel_name)
+}
@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MIB: f64 = 1024.0 * 1024.0;
+/// Longest a throttled writer sleeps before looking at the rates again, so a
+/// limit changed while flashing takes hold quickly
+const RECHECK: Duration = Duration::from_millis(100);
+
+/// Turn a rate in MiB/s into bytes per second, 0 meaning no limit.
+pub fn from_mib(rate: f64) -> u64 {
+    (rate.max(0.0) * MIB) as u64
+}
+
+pub fn to_mib(rate: u64) -> f64 {
+    rate as f64 / MIB
+}
+
+/// Parse a rate in MiB/s, such as `12` or `2.5`.
+fn parse_rate(s: &str) -> Result<u64, String> {
+    s.parse::<f64>()
+        .ok()
+        .filter(|rate| rate.is_finite() && *rate >= 0.0)
+        .map(from_mib)
+        .ok_or_else(|| format!("invalid rate '{}', expected MiB/s", s))
+}
+
+/// A rate limit for a single target, given as `PATH=MiB/s`.
+#[derive(Debug, Clone)]
+pub struct DeviceRateLimit {
+    pub path: String,
+    pub rate: u64,
+}
+
+impl FromStr for DeviceRateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, rate) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("expected PATH=MiB/s, got '{}'", s))?;
+        Ok(DeviceRateLimit { path: path.to_string(), rate: parse_rate(rate)? })
+    }
+}
+
+/// Bytes a writer may send before it has to wait. It may go into debt by
+/// one write, so writes larger than a second's worth still get through.
+#[derive(Debug)]
+pub struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Default for TokenBucket {
+    fn default() -> Self {
+        TokenBucket { tokens: 0.0, last: Instant::now() }
+    }
+}
+
+impl TokenBucket {
+    /// Add what `rate` bytes per second earned since the last refill, up to
+    /// a second's worth. Without a limit there is nothing to save up.
+    fn refill(&mut self, rate: u64) {
+        let now = Instant::now();
+        self.tokens = if rate == 0 {
+            0.0
+        } else {
+            let earned = now.duration_since(self.last).as_secs_f64() * rate as f64;
+            (self.tokens + earned).min(rate as f64)
+        };
+        self.last = now;
+    }
+
+    fn take(&mut self, rate: u64, bytes: u64) {
+        self.refill(rate);
+        if rate > 0 {
+            self.tokens -= bytes as f64;
+        }
+    }
+
+    /// How long until the bucket is out of debt at `rate`.
+    fn wait(&mut self, rate: u64) -> Duration {
+        self.refill(rate);
+        if rate == 0 || self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate as f64)
+        }
+    }
+}
+
+/// A device's own bucket, along with the path its limit is looked up by.
+#[derive(Debug)]
+pub struct Throttle {
+    path: String,
+    bucket: TokenBucket,
+}
+
+impl Throttle {
+    pub fn new(path: String) -> Self {
+        Throttle { path, bucket: TokenBucket::default() }
+    }
+}
+
+/// Bandwidth limits for the devices being flashed, each a number of bytes
+/// per second with 0 for no limit. They are shared with whoever started the
+/// flash, so they can be changed while it runs.
+#[derive(Debug, Default)]
+pub struct RateLimits {
+    /// All devices together
+    total: AtomicU64,
+    /// Each device without a limit of its own
+    device: AtomicU64,
+    /// Limits of single devices, keyed by path
+    devices: Mutex<HashMap<String, u64>>,
+    /// Shared by the writers of every device
+    total_bucket: Mutex<TokenBucket>,
+}
+
+impl RateLimits {
+    pub fn new(total: u64, device: u64, devices: HashMap<String, u64>) -> Self {
+        RateLimits {
+            total: AtomicU64::new(total),
+            device: AtomicU64::new(device),
+            devices: Mutex::new(devices),
+            total_bucket: Mutex::new(TokenBucket::default()),
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    pub fn set_total(&self, rate: u64) {
+        self.total.store(rate, Ordering::Relaxed);
+    }
+
+    /// The limit of devices that have none of their own.
+    pub fn device_default(&self) -> u64 {
+        self.device.load(Ordering::Relaxed)
+    }
+
+    pub fn set_device_default(&self, rate: u64) {
+        self.device.store(rate, Ordering::Relaxed);
+    }
+
+    pub fn device(&self, path: &str) -> u64 {
+        self.devices
+            .lock()
+            .unwrap()
+            .get(path)
+            .copied()
+            .unwrap_or_else(|| self.device_default())
+    }
+
+    pub fn set_device(&self, path: &str, rate: u64) {
+        self.devices.lock().unwrap().insert(path.to_string(), rate);
+    }
+
+    /// Count `bytes` about to be written to a device against its limit and
+    /// the total one.
+    pub fn charge(&self, throttle: &mut Throttle, bytes: u64) {
+        throttle.bucket.take(self.device(&throttle.path), bytes);
+        self.total_bucket.lock().unwrap().take(self.total(), bytes);
+    }
+
+    /// How long the device has to wait before it may write again, at the
+    /// rates as they are now.
+    pub fn delay(&self, throttle: &mut Throttle) -> Duration {
+        let own = throttle.bucket.wait(self.device(&throttle.path));
+        own.max(self.total_bucket.lock().unwrap().wait(self.total()))
+    }
+
+    /// Charge `bytes` to the device, then hold up the calling writer, and
+    /// only it, until the device is back within its limits.
+    pub fn throttle(&self, throttle: &mut Throttle, bytes: u64) {
+        if bytes == 0 {
+            return;
+        }
+        self.charge(throttle, bytes);
+        loop {
+            let delay = self.delay(throttle);
+            if delay.is_zero() {
+                return;
+            }
+            thread::sleep(delay.min(RECHECK));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u64 = 8 * 1024 * 1024;
+
+    fn assert_near(actual: Duration, expected: f64) {
+        let actual = actual.as_secs_f64();
+        assert!((actual - expected).abs() < 0.05, "waited {}s, expected {}s", actual, expected);
+    }
+
+    #[test]
+    fn parses_device_rate_limits() {
+        let limit: DeviceRateLimit = "/dev/sdb=2.5".parse().unwrap();
+        assert_eq!((limit.path.as_str(), limit.rate), ("/dev/sdb", from_mib(2.5)));
+        assert_eq!("/dev/sdc=0".parse::<DeviceRateLimit>().unwrap().rate, 0);
+        assert_eq!("/dev/a=b=1".parse::<DeviceRateLimit>().unwrap().path, "/dev/a=b");
+
+        for invalid in ["/dev/sdb", "/dev/sdb=", "/dev/sdb=-1", "/dev/sdb=fast", "/dev/sdb=inf", "/dev/sdb=NaN"] {
+            assert!(invalid.parse::<DeviceRateLimit>().is_err(), "{} was accepted", invalid);
+        }
+    }
+
+    #[test]
+    fn the_bucket_waits_out_its_debt() {
+        let mut bucket = TokenBucket::default();
+        assert_eq!(bucket.wait(RATE), Duration::ZERO);
+
+        bucket.take(RATE, RATE / 2);
+        assert_near(bucket.wait(RATE), 0.5);
+
+        // a write larger than a second's worth still gets through, after
+        // which the writer waits for all of it
+        let mut bucket = TokenBucket::default();
+        bucket.take(RATE, 3 * RATE);
+        assert_near(bucket.wait(RATE), 3.0);
+        // and a higher rate pays the debt off sooner
+        assert_near(bucket.wait(3 * RATE), 1.0);
+    }
+
+    #[test]
+    fn the_bucket_saves_up_a_second_at_most() {
+        let mut bucket = TokenBucket { tokens: 0.0, last: Instant::now() - Duration::from_secs(10) };
+        bucket.take(RATE, RATE);
+        assert_eq!(bucket.wait(RATE), Duration::ZERO);
+        bucket.take(RATE, RATE / 4);
+        assert_near(bucket.wait(RATE), 0.25);
+
+        // nothing is saved up without a limit, nor owed
+        let mut bucket = TokenBucket { tokens: 0.0, last: Instant::now() - Duration::from_secs(10) };
+        bucket.take(0, 100 * RATE);
+        assert_eq!(bucket.wait(0), Duration::ZERO);
+        bucket.take(RATE, RATE / 2);
+        assert_near(bucket.wait(RATE), 0.5);
+    }
+
+    #[test]
+    fn devices_wait_for_the_stricter_limit() {
+        let limits = RateLimits::new(0, RATE, HashMap::from([("/dev/sdc".to_string(), 2 * RATE)]));
+        assert_eq!(limits.device("/dev/sdb"), RATE);
+        assert_eq!(limits.device("/dev/sdc"), 2 * RATE);
+
+        let (mut sdb, mut sdc) = (Throttle::new("/dev/sdb".to_string()), Throttle::new("/dev/sdc".to_string()));
+        limits.charge(&mut sdb, RATE);
+        limits.charge(&mut sdc, RATE);
+        assert_near(limits.delay(&mut sdb), 1.0);
+        assert_near(limits.delay(&mut sdc), 0.5);
+
+        // the total limit is shared, so each waits for what both wrote
+        limits.set_total(RATE);
+        limits.charge(&mut sdb, RATE);
+        limits.charge(&mut sdc, RATE);
+        assert_near(limits.delay(&mut sdb), 2.0);
+        assert_near(limits.delay(&mut sdc), 2.0);
+
+        // lifting the limits frees them straight away
+        limits.set_total(0);
+        limits.set_device_default(0);
+        limits.set_device("/dev/sdc", 0);
+        assert_eq!(limits.delay(&mut sdb), Duration::ZERO);
+        assert_eq!(limits.delay(&mut sdc), Duration::ZERO);
+    }
+}
@@ -0,0 +1,320 @@
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::blockdev;
+use crate::direct::AlignedWriter;
+use crate::fs::{self, DeviceWriter, Progress};
+use crate::pipeline::{Chunk, CHUNK_SIZE};
+use crate::writeback::SyncPolicy;
+use crate::zeroing::ZeroStrategy;
+
+const SECTOR_SIZE: u64 = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum BadSectorPolicy {
+    /// Drop the device from the job at the first unrecoverable sector
+    Fail,
+    /// Record unrecoverable sectors in the device's error map and keep going
+    Continue,
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled on every further attempt
+    pub backoff: Duration,
+    /// Granularity at which a failed region is rewritten
+    pub block_size: usize,
+    pub on_bad_sector: BadSectorPolicy,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            backoff: Duration::from_millis(100),
+            block_size: 4096,
+            on_bad_sector: BadSectorPolicy::Fail,
+        }
+    }
+}
+
+/// Something handed to a device: a chunk of the image, or a hole of that
+/// many zeros.
+pub enum Piece {
+    Data(Chunk),
+    Hole(u64),
+}
+
+impl Piece {
+    pub fn len(&self) -> u64 {
+        match self {
+            Piece::Data(chunk) => chunk.len() as u64,
+            Piece::Hole(len) => *len,
+        }
+    }
+}
+
+/// Data handed to a device since it was last synced successfully.
+///
+/// Buffered writes usually only report a bad sector once the page cache is
+/// flushed, so everything since the last good sync has to be kept around to
+/// be able to rewrite it. The chunks are shared with the other devices.
+pub struct SyncWindow {
+    pub start: u64,
+    pub pieces: Vec<Piece>,
+    len: u64,
+}
+
+impl SyncWindow {
+    pub fn new() -> Self {
+        SyncWindow { start: 0, pieces: Vec::new(), len: 0 }
+    }
+
+    pub fn push(&mut self, chunk: Chunk) {
+        self.len += chunk.len() as u64;
+        self.pieces.push(Piece::Data(chunk));
+    }
+
+    pub fn push_hole(&mut self, len: u64) {
+        self.len += len;
+        self.pieces.push(Piece::Hole(len));
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn end(&self) -> u64 {
+        self.start + self.len
+    }
+
+    /// Forget the data once the device has it on stable storage.
+    pub fn advance(&mut self) {
+        self.start = self.end();
+        self.pieces.clear();
+        self.len = 0;
+    }
+}
+
+/// Rewrite the sync window to a device that reported `error`, retrying at
+/// `block_size` granularity. On success the device gets a fresh writer
+/// positioned at the end of the window, and the window is dropped since the
+/// rewrite went to stable storage; otherwise the device is dropped from the
+/// job and the reason is recorded in its status.
+pub fn recover_device(
+    device: &mut DeviceWriter,
+    index: usize,
+    error: io::Error,
+    window: &mut SyncWindow,
+    retry: &RetryPolicy,
+    progress: &Arc<Mutex<Progress>>,
+) {
+    if let Some(writer) = device.writer.take() {
+        // discard whatever is still buffered, it gets rewritten from the window
+        writer.discard();
+    }
+
+    // only a device that already reads as zeros may have the window's zeros left out
+    let skip_zeros = device.zero_strategy == ZeroStrategy::Skip;
+    // a device written with O_SYNC carries on that way
+    let synchronous = device.writeback.policy == SyncPolicy::OSync;
+    let rewritten = rewrite_window(&device.path, index, window, device.capacity, skip_zeros, retry, progress)
+        .and_then(|()| reopen(&device.path, retry, synchronous))
+        .and_then(|mut file| {
+            file.seek(SeekFrom::Start(window.end()))?;
+            AlignedWriter::new(file, device.layout)
+        });
+    match rewritten {
+        Ok(writer) => {
+            device.writer = Some(writer);
+            // a later error must not rewrite it, and record its bad sectors, again
+            window.advance();
+        }
+        Err(e) => device.fail(index, format!("{} (initial error: {})", e, error), progress),
+    }
+}
+
+fn rewrite_window(
+    path: &Path,
+    index: usize,
+    window: &SyncWindow,
+    capacity: Option<u64>,
+    skip_zeros: bool,
+    retry: &RetryPolicy,
+    progress: &Arc<Mutex<Progress>>,
+) -> io::Result<()> {
+    let mut file = Some(reopen(path, retry, true)?);
+    let mut offset = window.start;
+    let zeros = vec![0; CHUNK_SIZE];
+
+    for piece in &window.pieces {
+        // a truncated device never got the part of the window past its end
+        let len = capacity.map_or(piece.len(), |capacity| capacity.saturating_sub(offset).min(piece.len()));
+        if len == 0 {
+            break;
+        }
+        // holes are rewritten a chunk of zeros at a time
+        let slices: Vec<&[u8]> = match piece {
+            Piece::Data(chunk) => vec![&chunk[..len as usize]],
+            Piece::Hole(_) if skip_zeros => {
+                offset += len;
+                continue;
+            }
+            Piece::Hole(_) => {
+                let whole = (len / CHUNK_SIZE as u64) as usize;
+                let mut slices = vec![&zeros[..]; whole];
+                let rest = (len % CHUNK_SIZE as u64) as usize;
+                if rest > 0 {
+                    slices.push(&zeros[..rest]);
+                }
+                slices
+            }
+        };
+
+        for piece in slices {
+            if !(skip_zeros && fs::is_zero_chunk(piece)) && write_at(&mut file, offset, piece).is_err() {
+                for (i, block) in piece.chunks(retry.block_size).enumerate() {
+                    let block_offset = offset + (i * retry.block_size) as u64;
+                    if skip_zeros && fs::is_zero_chunk(block) {
+                        continue;
+                    }
+
+                    if let Err(e) = write_with_retries(&mut file, path, block_offset, block, retry) {
+                        if !is_media_error(&e) {
+                            return Err(e);
+                        }
+
+                        let lba = block_offset / SECTOR_SIZE;
+                        let sectors = (block.len() as u64).div_ceil(SECTOR_SIZE);
+                        record_bad_sectors(progress, index, lba, sectors);
+
+                        if retry.on_bad_sector == BadSectorPolicy::Fail {
+                            return Err(io::Error::new(e.kind(), format!(
+                                "unrecoverable write error at LBA {}: {}", lba, e
+                            )));
+                        }
+                    }
+                }
+            }
+            offset += piece.len() as u64;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_with_retries(
+    file: &mut Option<File>,
+    path: &Path,
+    offset: u64,
+    block: &[u8],
+    retry: &RetryPolicy,
+) -> io::Result<()> {
+    let mut delay = retry.backoff;
+    let mut attempt = 0;
+
+    loop {
+        let error = match write_at(file, offset, block) {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+
+        if attempt >= retry.max_retries {
+            return Err(error);
+        }
+        attempt += 1;
+        thread::sleep(delay);
+        delay *= 2;
+
+        // the old handle may be stale if the device was reset, and has to be
+        // closed first for the exclusive open to succeed
+        *file = None;
+        *file = Some(reopen(path, retry, true)?);
+    }
+}
+
+fn write_at(file: &mut Option<File>, offset: u64, data: &[u8]) -> io::Result<()> {
+    let file = file.as_mut().ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(data)
+}
+
+/// Open an existing device for writing, retrying while it reappears after a
+/// reset. `synchronous` makes every write durable before it returns, so that
+/// errors are attributed to the block that caused them. Block devices are
+/// opened exclusively, as for the first write, so no other handle on the
+/// device may still be open.
+fn reopen(path: &Path, retry: &RetryPolicy, synchronous: bool) -> io::Result<File> {
+    let mut delay = retry.backoff;
+    let mut attempt = 0;
+
+    loop {
+        match blockdev::reopen_target(path, synchronous) {
+            Ok(file) => return Ok(file),
+            Err(e) if attempt >= retry.max_retries => return Err(e),
+            Err(_) => {
+                attempt += 1;
+                thread::sleep(delay);
+                delay *= 2;
+            }
+        }
+    }
+}
+
+/// Whether an error points at the media itself rather than at something a
+/// rewrite cannot fix, such as running out of space.
+fn is_media_error(error: &io::Error) -> bool {
+    #[cfg(unix)]
+    {
+        error.raw_os_error() == Some(libc::EIO)
+    }
+    #[cfg(not(unix))]
+    {
+        error.raw_os_error().is_some()
+    }
+}
+
+fn record_bad_sectors(progress: &Arc<Mutex<Progress>>, index: usize, lba: u64, sectors: u64) {
+    let mut progress = progress.lock().unwrap();
+    let error_map = &mut progress.devices[index].error_map;
+
+    // a range rewritten twice must not be counted twice
+    match error_map.last_mut() {
+        Some(last) if last.start <= lba && lba <= last.end => last.end = last.end.max(lba + sectors),
+        _ => error_map.push(lba..lba + sectors),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_is_dropped_once_synced() {
+        let mut window = SyncWindow::new();
+        window.push(Arc::new(blockdev::AlignedBuffer::new(100, 512)));
+        window.push_hole(50);
+        window.push(Arc::new(blockdev::AlignedBuffer::new(100, 512)));
+        assert_eq!((window.start, window.end(), window.pieces.len()), (0, 250, 3));
+
+        window.advance();
+        assert_eq!((window.start, window.len(), window.pieces.len()), (250, 0, 0));
+    }
+
+    #[test]
+    fn bad_sectors_merge_without_duplicates() {
+        let progress = Arc::new(Mutex::new(Progress::new(0)));
+        progress.lock().unwrap().devices.push(fs::DeviceStatus::new("/dev/null".to_string()));
+
+        record_bad_sectors(&progress, 0, 8, 8);
+        record_bad_sectors(&progress, 0, 16, 8);
+        record_bad_sectors(&progress, 0, 8, 8);
+        record_bad_sectors(&progress, 0, 64, 8);
+        assert_eq!(progress.lock().unwrap().devices[0].error_map, vec![8..24, 64..72]);
+    }
+}
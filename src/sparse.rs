@@ -0,0 +1,237 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+
+use crate::parallel;
+
+/// A decoded image that may know where it holds nothing but zeros, so those
+/// stretches can be passed on as holes instead of being read.
+pub trait ImageRead: Read {
+    /// Skip over the hole at the current position, if there is one, and
+    /// return its length; 0 where data follows.
+    fn skip_hole(&mut self) -> io::Result<u64> {
+        Ok(0)
+    }
+
+    /// Bytes that can be read before the next hole.
+    fn data_ahead(&mut self) -> io::Result<u64> {
+        Ok(u64::MAX)
+    }
+}
+
+impl<R: Read> ImageRead for BufReader<R> {}
+
+impl<R: ImageRead + ?Sized> ImageRead for Box<R> {
+    fn skip_hole(&mut self) -> io::Result<u64> {
+        (**self).skip_hole()
+    }
+
+    fn data_ahead(&mut self) -> io::Result<u64> {
+        (**self).data_ahead()
+    }
+}
+
+/// A raw image read extent by extent, so the holes of a sparse file are
+/// found with SEEK_DATA and SEEK_HOLE rather than read. Plain reads go
+/// through holes as the zeros they are.
+pub struct SparseFile {
+    file: File,
+    pos: u64,
+    len: u64,
+    /// End of the data or hole the position is in
+    extent_end: u64,
+    in_hole: bool,
+}
+
+impl SparseFile {
+    pub fn new(file: File) -> io::Result<Self> {
+        let len = file.metadata()?.len();
+        Ok(SparseFile { file, pos: 0, len, extent_end: 0, in_hole: false })
+    }
+
+    /// Find the extent the position is in once the last one is used up.
+    fn locate(&mut self) {
+        if self.pos < self.extent_end || self.pos >= self.len {
+            return;
+        }
+        let data = find_data(&self.file, self.pos, self.len).min(self.len);
+        if data > self.pos {
+            self.in_hole = true;
+            self.extent_end = data;
+        } else {
+            self.in_hole = false;
+            self.extent_end = find_hole(&self.file, self.pos, self.len).clamp(self.pos + 1, self.len);
+        }
+    }
+}
+
+impl Read for SparseFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.locate();
+        let len = (buf.len() as u64).min(self.extent_end.saturating_sub(self.pos)) as usize;
+        let n = parallel::read_at(&self.file, &mut buf[..len], self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl ImageRead for SparseFile {
+    fn skip_hole(&mut self) -> io::Result<u64> {
+        self.locate();
+        if !self.in_hole || self.pos >= self.extent_end {
+            return Ok(0);
+        }
+        let skipped = self.extent_end - self.pos;
+        self.pos = self.extent_end;
+        Ok(skipped)
+    }
+
+    fn data_ahead(&mut self) -> io::Result<u64> {
+        self.locate();
+        Ok(if self.in_hole { 0 } else { self.extent_end.saturating_sub(self.pos) })
+    }
+}
+
+/// Start of the first data at or after `offset`, or `len` if only a hole
+/// follows. Where holes cannot be looked for, everything is data.
+#[cfg(target_os = "linux")]
+fn find_data(file: &File, offset: u64, len: u64) -> u64 {
+    match seek(file, offset, libc::SEEK_DATA) {
+        Ok(data) => data,
+        Err(e) if e.raw_os_error() == Some(libc::ENXIO) => len,
+        Err(_) => offset,
+    }
+}
+
+/// Start of the first hole at or after `offset`; the end of the file counts
+/// as one.
+#[cfg(target_os = "linux")]
+fn find_hole(file: &File, offset: u64, len: u64) -> u64 {
+    seek(file, offset, libc::SEEK_HOLE).map_or(len, |hole| hole.min(len))
+}
+
+#[cfg(target_os = "linux")]
+fn seek(file: &File, offset: u64, whence: libc::c_int) -> io::Result<u64> {
+    use std::os::unix::io::AsRawFd;
+
+    match unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) } {
+        -1 => Err(io::Error::last_os_error()),
+        result => Ok(result as u64),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn find_data(_file: &File, offset: u64, _len: u64) -> u64 {
+    offset
+}
+
+#[cfg(not(target_os = "linux"))]
+fn find_hole(_file: &File, _offset: u64, len: u64) -> u64 {
+    len
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::testing::{Noise, TempFile};
+    use std::os::unix::fs::FileExt;
+
+    const MIB: u64 = 1024 * 1024;
+
+    /// A sparse file of `len` bytes holding `data` at each offset.
+    fn sparse_file(len: u64, data: &[(u64, &[u8])]) -> TempFile {
+        let file = TempFile::new(b"");
+        let handle = std::fs::OpenOptions::new().write(true).open(&file.path).unwrap();
+        handle.set_len(len).unwrap();
+        for &(offset, bytes) in data {
+            handle.write_all_at(bytes, offset).unwrap();
+        }
+        file
+    }
+
+    /// Read the image the way the pipeline does, noting each hole and piece
+    /// of data as `(offset, len, is_hole)`.
+    fn read_extents(reader: &mut dyn ImageRead) -> (Vec<u8>, Vec<(u64, u64, bool)>) {
+        let (mut image, mut extents) = (Vec::new(), Vec::new());
+        loop {
+            let hole = reader.skip_hole().unwrap();
+            if hole > 0 {
+                extents.push((image.len() as u64, hole, true));
+                image.resize(image.len() + hole as usize, 0);
+            }
+
+            let mut buffer = vec![0; reader.data_ahead().unwrap().min(MIB) as usize];
+            let n = reader.read(&mut buffer).unwrap();
+            if n > 0 {
+                match extents.last_mut() {
+                    Some((offset, len, false)) if *offset + *len == image.len() as u64 => *len += n as u64,
+                    _ => extents.push((image.len() as u64, n as u64, false)),
+                }
+                image.extend_from_slice(&buffer[..n]);
+            } else if hole == 0 {
+                return (image, extents);
+            }
+        }
+    }
+
+    fn reports_holes(file: &TempFile) -> bool {
+        find_data(&file.open(), 0, u64::MAX) > 0
+    }
+
+    #[test]
+    fn holes_are_skipped_and_read_as_zeros() {
+        let (first, second) = (Noise(1).bytes(64 * 1024), Noise(2).bytes(4096));
+        let file = sparse_file(8 * MIB, &[(MIB, &first), (4 * MIB, &second)]);
+
+        let mut expected = vec![0; 8 * MIB as usize];
+        expected[MIB as usize..MIB as usize + first.len()].copy_from_slice(&first);
+        expected[4 * MIB as usize..4 * MIB as usize + second.len()].copy_from_slice(&second);
+
+        let (image, extents) = read_extents(&mut SparseFile::new(file.open()).unwrap());
+        assert!(image == expected);
+
+        // filesystems that cannot tell where holes are have the file read in full
+        if reports_holes(&file) {
+            let first_end = MIB + first.len() as u64;
+            let second_end = 4 * MIB + second.len() as u64;
+            assert_eq!(extents, vec![
+                (0, MIB, true),
+                (MIB, first.len() as u64, false),
+                (first_end, 4 * MIB - first_end, true),
+                (4 * MIB, second.len() as u64, false),
+                (second_end, 8 * MIB - second_end, true),
+            ]);
+        }
+    }
+
+    #[test]
+    fn plain_reads_go_through_holes() {
+        let data = Noise(3).bytes(4096);
+        let file = sparse_file(3 * MIB, &[(2 * MIB, &data)]);
+        let mut image = Vec::new();
+        SparseFile::new(file.open()).unwrap().read_to_end(&mut image).unwrap();
+        assert_eq!(image.len() as u64, 3 * MIB);
+        assert!(image[..2 * MIB as usize].iter().all(|&b| b == 0));
+        assert!(image[2 * MIB as usize..2 * MIB as usize + 4096] == data);
+        assert!(image[2 * MIB as usize + 4096..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn files_without_holes_or_data_read_whole() {
+        let data = Noise(4).bytes(3 * MIB as usize + 100);
+        let file = TempFile::new(&data);
+        let (image, extents) = read_extents(&mut SparseFile::new(file.open()).unwrap());
+        assert!(image == data);
+        assert_eq!(extents, vec![(0, data.len() as u64, false)]);
+
+        let file = sparse_file(2 * MIB, &[]);
+        let (image, extents) = read_extents(&mut SparseFile::new(file.open()).unwrap());
+        assert_eq!(image.len() as u64, 2 * MIB);
+        assert!(image.iter().all(|&b| b == 0));
+        if reports_holes(&file) {
+            assert_eq!(extents, vec![(0, 2 * MIB, true)]);
+        }
+
+        let file = TempFile::new(b"");
+        assert!(read_extents(&mut SparseFile::new(file.open()).unwrap()).0.is_empty());
+    }
+}
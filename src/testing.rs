@@ -0,0 +1,87 @@
+//! Helpers shared by the unit tests.
+
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// A file in the temporary directory, removed again once dropped.
+pub struct TempFile {
+    pub path: PathBuf,
+}
+
+impl TempFile {
+    pub fn new(contents: &[u8]) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "ferrisflash-test-{}-{}",
+            std::process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&path, contents).unwrap();
+        TempFile { path }
+    }
+
+    pub fn open(&self) -> File {
+        File::open(&self.path).unwrap()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// A deterministic xorshift generator, so failures can be reproduced.
+pub struct Noise(pub u64);
+
+impl Noise {
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next() as u8).collect()
+    }
+}
+
+/// The first 34 sectors of a GPT disk of `sectors` sectors, with a protective
+/// MBR and partitions over the given LBA ranges.
+pub fn gpt_image(sectors: u64, partitions: &[(u64, u64)]) -> Vec<u8> {
+    let mut image = vec![0; 34 * 512];
+
+    let mbr = &mut image[446..462];
+    mbr[4] = 0xee;
+    mbr[8..12].copy_from_slice(&1u32.to_le_bytes());
+    mbr[12..16].copy_from_slice(&((sectors - 1) as u32).to_le_bytes());
+    image[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+    for (i, &(first, last)) in partitions.iter().enumerate() {
+        let entry = &mut image[1024 + i * 128..1024 + (i + 1) * 128];
+        entry[..16].fill(0xaf);
+        entry[16..32].fill(i as u8 + 1);
+        entry[32..40].copy_from_slice(&first.to_le_bytes());
+        entry[40..48].copy_from_slice(&last.to_le_bytes());
+    }
+    let entries_crc = crc32fast::hash(&image[1024..1024 + 128 * 128]);
+
+    let header = &mut image[512..1024];
+    header[..8].copy_from_slice(b"EFI PART");
+    header[8..12].copy_from_slice(&0x10000u32.to_le_bytes());
+    header[12..16].copy_from_slice(&92u32.to_le_bytes());
+    header[24..32].copy_from_slice(&1u64.to_le_bytes());
+    header[32..40].copy_from_slice(&(sectors - 1).to_le_bytes());
+    header[40..48].copy_from_slice(&34u64.to_le_bytes());
+    header[48..56].copy_from_slice(&(sectors - 34).to_le_bytes());
+    header[72..80].copy_from_slice(&2u64.to_le_bytes());
+    header[80..84].copy_from_slice(&128u32.to_le_bytes());
+    header[84..88].copy_from_slice(&128u32.to_le_bytes());
+    header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+    let header_crc = crc32fast::hash(&header[..92]);
+    header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+    image
+}
@@ -0,0 +1,345 @@
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use io_uring::{opcode, types, IoUring};
+
+use crate::fs::{DeviceWriter, FlashOptions, Progress};
+use crate::pipeline::{self, Chunk, Message};
+use crate::recovery::{self, SyncWindow};
+use crate::zeroing::{self, ZeroStrategy};
+
+/// Most entries asked of the kernel for a ring, well under its own limit and
+/// small enough not to run into the locked memory limit of older kernels
+const MAX_ENTRIES: usize = 4096;
+
+/// Whether the kernel lets us set up a ring at all; it may be too old, or
+/// io_uring may be disabled or filtered out by a sandbox.
+pub fn available() -> bool {
+    IoUring::new(1).is_ok()
+}
+
+/// A device written through the ring, along with what the writer thread of the
+/// synchronous path would otherwise keep for it.
+struct RingDevice {
+    device: DeviceWriter,
+    index: usize,
+    receiver: Receiver<Message>,
+    window: SyncWindow,
+    in_flight: usize,
+    in_flight_bytes: u64,
+    /// First error reported by a write in flight, handled once the rest are in
+    error: Option<io::Error>,
+    /// The ring has written past where the synchronous writer stands
+    moved: bool,
+    done: bool,
+}
+
+/// A write in flight. The chunk has to outlive it, since the kernel reads
+/// straight from it.
+struct Slot {
+    device: usize,
+    chunk: Chunk,
+}
+
+struct Ring<'a> {
+    // declared first so it is dropped first, before the chunks it writes from;
+    // `settle` makes sure the kernel is done with them by then
+    ring: IoUring,
+    slots: Vec<Option<Slot>>,
+    devices: Vec<RingDevice>,
+    depth: usize,
+    progress: &'a Arc<Mutex<Progress>>,
+    options: &'a FlashOptions,
+}
+
+/// Write every device from this one thread, keeping up to `depth` aligned
+/// writes in flight on each. Whatever the ring cannot take (unaligned pieces,
+/// zero blocks that are not simply written, syncs, rewriting after an error)
+/// goes through the device's own writer once its writes are in. The depth is
+/// capped so that the ring stays within `MAX_ENTRIES`, and if no ring can be
+/// set up every device gets a writer thread of the synchronous path instead.
+pub fn run_devices(
+    devices: Vec<(usize, DeviceWriter, Receiver<Message>)>,
+    depth: usize,
+    progress: &Arc<Mutex<Progress>>,
+    options: &FlashOptions,
+) {
+    let depth = depth.min(MAX_ENTRIES / devices.len().max(1)).max(1);
+    let entries = (depth * devices.len()).next_power_of_two().min(MAX_ENTRIES) as u32;
+    let ring = match IoUring::new(entries) {
+        Ok(ring) => ring,
+        Err(_) => {
+            thread::scope(|scope| {
+                for (index, device, receiver) in devices {
+                    scope.spawn(move || pipeline::run_writer(device, index, receiver, progress, options));
+                }
+            });
+            return;
+        }
+    };
+
+    let devices: Vec<RingDevice> = devices
+        .into_iter()
+        .map(|(index, device, receiver)| RingDevice {
+            device,
+            index,
+            receiver,
+            window: SyncWindow::new(),
+            in_flight: 0,
+            in_flight_bytes: 0,
+            error: None,
+            moved: false,
+            done: false,
+        })
+        .collect();
+
+    let mut ring = Ring { ring, slots: Vec::new(), devices, depth, progress, options };
+    if let Err(e) = ring.run() {
+        ring.settle();
+        for dev in ring.devices.iter_mut().filter(|d| !d.done) {
+            dev.device.fail(dev.index, format!("io_uring failed: {}", e), progress);
+        }
+    }
+}
+
+impl Ring<'_> {
+    fn run(&mut self) -> io::Result<()> {
+        while self.devices.iter().any(|d| !d.done || d.in_flight > 0) {
+            let mut received = false;
+
+            for current in 0..self.devices.len() {
+                if self.devices[current].done || self.devices[current].in_flight >= self.depth || self.throttled(current) {
+                    continue;
+                }
+                let dev = &self.devices[current];
+                match dev.receiver.try_recv() {
+                    Ok(message) => {
+                        received = true;
+                        self.handle(current, message)?;
+                    }
+                    Err(TryRecvError::Empty) => {}
+                    // the reading thread gave up halfway, leave the device as it is
+                    Err(TryRecvError::Disconnected) => self.devices[current].done = true,
+                }
+            }
+
+            if self.devices.iter().any(|d| d.in_flight > 0) {
+                // wait for a completion unless there may be more to submit right away
+                submit_and_wait(&self.ring, if received { 0 } else { 1 })?;
+                self.reap();
+            } else if !received {
+                // nothing is in flight or queued, wait for the reading thread
+                // or for a throttled device to be let through again
+                if self.devices.iter().all(|d| d.done) {
+                    break;
+                }
+                let Some(current) = (0..self.devices.len()).find(|&i| !self.devices[i].done && !self.throttled(i)) else {
+                    thread::sleep(Duration::from_millis(10));
+                    continue;
+                };
+                match self.devices[current].receiver.recv_timeout(Duration::from_millis(10)) {
+                    Ok(message) => self.handle(current, message)?,
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => self.devices[current].done = true,
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle(&mut self, current: usize, message: Message) -> io::Result<()> {
+        let (progress, options) = (self.progress, self.options);
+
+        match message {
+            Message::Data(chunk) => {
+                let dev = &mut self.devices[current];
+                options.rate_limits.charge(&mut dev.device.throttle, chunk.len() as u64);
+                if !self.submit(current, &chunk)? {
+                    self.drain(current)?;
+                    let dev = &mut self.devices[current];
+                    dev.device.write_chunk(dev.index, chunk, &mut dev.window, progress, options);
+                    progress.lock().unwrap().device_progressed(dev.index, dev.window.end());
+                }
+
+                let dev = &self.devices[current];
+                if dev.device.writeback.due(&dev.window, options.pipeline.sync_bytes()) {
+                    self.drain(current)?;
+                    let dev = &mut self.devices[current];
+                    dev.device.write_back(dev.index, &mut dev.window, progress, options);
+                }
+            }
+            Message::Hole(len) => {
+                self.drain(current)?;
+                let dev = &mut self.devices[current];
+                let traffic = dev.device.hole_traffic(len);
+                options.rate_limits.charge(&mut dev.device.throttle, traffic);
+                dev.device.write_hole(dev.index, len, &mut dev.window, progress, options);
+                progress.lock().unwrap().device_progressed(dev.index, dev.window.end());
+                dev.device.write_back(dev.index, &mut dev.window, progress, options);
+            }
+            Message::ImageSize(size) => {
+                self.drain(current)?;
+                let dev = &mut self.devices[current];
+                dev.device.check_capacity(dev.index, size, progress);
+            }
+            Message::RequiredSize(required) => {
+                let device = &mut self.devices[current].device;
+                device.truncate = device.capacity.is_some_and(|capacity| capacity >= required);
+            }
+            Message::Finish(header) => {
+                self.drain(current)?;
+                let dev = &mut self.devices[current];
+                dev.device.finish_truncated(dev.index, &header, dev.window.end(), progress);
+                dev.device.sync(dev.index, &mut dev.window, progress, options);
+                dev.done = true;
+            }
+        }
+
+        let dev = &mut self.devices[current];
+        if dev.device.writer.is_none() {
+            dev.done = true;
+        }
+        Ok(())
+    }
+
+    /// Whether the device is over its rate limit, and is left alone for now
+    /// rather than holding up the others.
+    fn throttled(&mut self, current: usize) -> bool {
+        !self.options.rate_limits.delay(&mut self.devices[current].device.throttle).is_zero()
+    }
+
+    /// Queue a chunk as a single write if the ring can take it: the device is
+    /// written with O_DIRECT, the chunk is whole blocks at a block boundary,
+    /// it fits on the device, and it is not a zero block handled some other way.
+    fn submit(&mut self, current: usize, chunk: &Chunk) -> io::Result<bool> {
+        let dev = &mut self.devices[current];
+        let offset = dev.window.end();
+        let block_size = dev.device.layout.block_size;
+        let Some(writer) = dev.device.writer.as_ref() else {
+            return Ok(false);
+        };
+
+        let fits = dev.device.capacity.is_none_or(|capacity| offset + chunk.len() as u64 <= capacity);
+        let aligned = offset.is_multiple_of(block_size as u64) && chunk.len().is_multiple_of(block_size);
+        let written_as_is = dev.device.zero_strategy == ZeroStrategy::Write
+            || !zeroing::zero_extents(chunk, offset, self.options.zero_granularity)
+                .iter()
+                .any(|e| e.zero && dev.device.offloads_zeros(e.range.len() as u64));
+        if !writer.is_direct() || !fits || !aligned || !written_as_is || dev.error.is_some() {
+            return Ok(false);
+        }
+
+        let slot = match self.slots.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None => {
+                self.slots.push(None);
+                self.slots.len() - 1
+            }
+        };
+        let entry = opcode::Write::new(types::Fd(writer.get_ref().as_raw_fd()), chunk.as_ptr(), chunk.len() as u32)
+            .offset(offset)
+            .build()
+            .user_data(slot as u64);
+
+        // there is room for every device's depth, so the queue is only full
+        // while the kernel has not picked up earlier entries yet
+        while unsafe { self.ring.submission().push(&entry) }.is_err() {
+            self.ring.submit()?;
+        }
+        self.slots[slot] = Some(Slot { device: current, chunk: chunk.clone() });
+
+        dev.window.push(chunk.clone());
+        dev.in_flight += 1;
+        dev.in_flight_bytes += chunk.len() as u64;
+        dev.moved = true;
+        self.progress.lock().unwrap().devices[dev.index].in_flight = dev.in_flight;
+        Ok(true)
+    }
+
+    /// Handle every completion that is in, rewriting the window of a device
+    /// whose write failed once none of its writes are left in flight.
+    fn reap(&mut self) {
+        for entry in self.ring.completion() {
+            let Some(slot) = self.slots[entry.user_data() as usize].take() else {
+                continue;
+            };
+            let dev = &mut self.devices[slot.device];
+            dev.in_flight -= 1;
+            dev.in_flight_bytes -= slot.chunk.len() as u64;
+
+            let result = entry.result();
+            if result < 0 {
+                dev.error.get_or_insert(io::Error::from_raw_os_error(-result));
+            } else if (result as usize) < slot.chunk.len() {
+                dev.error.get_or_insert(io::Error::new(io::ErrorKind::WriteZero, "short write"));
+            }
+
+            let mut progress = self.progress.lock().unwrap();
+            progress.devices[dev.index].in_flight = dev.in_flight;
+            progress.device_progressed(dev.index, dev.window.end() - dev.in_flight_bytes);
+        }
+
+        for dev in self.devices.iter_mut().filter(|d| d.in_flight == 0) {
+            if let Some(error) = dev.error.take() {
+                let options = self.options;
+                recovery::recover_device(&mut dev.device, dev.index, error, &mut dev.window, &options.retry, self.progress);
+                // a rewritten device gets a fresh writer at the end of the window
+                dev.moved = false;
+                if dev.device.writer.is_none() {
+                    dev.done = true;
+                }
+            }
+        }
+    }
+
+    /// Wait for every write still in flight after the ring failed, so the
+    /// chunks they write from can be freed. Should the ring not even let us
+    /// wait, the chunks are leaked instead, as the kernel may still be reading
+    /// from them.
+    fn settle(&mut self) {
+        while self.slots.iter().any(Option::is_some) {
+            if submit_and_wait(&self.ring, 1).is_err() {
+                std::mem::forget(std::mem::take(&mut self.slots));
+                return;
+            }
+            for entry in self.ring.completion() {
+                self.slots[entry.user_data() as usize] = None;
+            }
+        }
+    }
+
+    /// Wait until none of the device's writes are in flight any more, then
+    /// put its own writer where the ring left off.
+    fn drain(&mut self, current: usize) -> io::Result<()> {
+        while self.devices[current].in_flight > 0 {
+            submit_and_wait(&self.ring, 1)?;
+            self.reap();
+        }
+
+        let dev = &mut self.devices[current];
+        if !dev.moved {
+            return Ok(());
+        }
+        dev.moved = false;
+        if let Some(writer) = dev.device.writer.as_mut() {
+            if let Err(e) = writer.seek(SeekFrom::Start(dev.window.end())) {
+                dev.device.fail(dev.index, e.to_string(), self.progress);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn submit_and_wait(ring: &IoUring, want: usize) -> io::Result<()> {
+    loop {
+        match ring.submit_and_wait(want) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            result => return result.map(|_| ()),
+        }
+    }
+}
@@ -0,0 +1,356 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::blockdev;
+use crate::fs::{self, Progress};
+use crate::parallel;
+use crate::partition;
+
+pub struct VerifyReport {
+    pub bytes_compared: u64,
+    pub bytes_skipped: u64,
+    pub mismatched_chunks: u64,
+    pub first_mismatch: Option<u64>,
+    pub device_too_small: bool,
+    /// Where the image was cut off at the end of a device that only has room
+    /// up to its last partition, as a flash does
+    pub truncated_at: Option<u64>,
+}
+
+impl VerifyReport {
+    pub fn is_match(&self) -> bool {
+        self.mismatched_chunks == 0 && !self.device_too_small
+    }
+}
+
+/// Fill `buffer` as far as the reader allows, so chunk boundaries land on the
+/// same offsets regardless of how the decoder splits its output.
+fn read_chunk(reader: &mut dyn Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// The GPT sectors a flash rewrites when it cuts the image short at the end of
+/// a smaller device. What the device holds there depends on whether the image
+/// did turn out longer than the device, which is only known at the end.
+struct Relocation {
+    capacity: u64,
+    /// What the flash writes there instead of the image
+    writes: Vec<(u64, Vec<u8>)>,
+    /// The image's own bytes there
+    original: Vec<Vec<u8>>,
+}
+
+impl Relocation {
+    /// The relocation a device of `capacity` bytes gets from an image that
+    /// starts with `header`, if it would get one should the image not fit.
+    fn new(header: &[u8], capacity: u64) -> Option<Self> {
+        partition::required_size(header).filter(|&required| required <= capacity)?;
+        let writes = partition::relocate_gpt(header, capacity)?;
+        let original = writes.iter().map(|(_, data)| vec![0; data.len()]).collect();
+        Some(Relocation { capacity, writes, original })
+    }
+
+    /// Keep the image's bytes that fall into relocated sectors and put the
+    /// device's in their place, so the chunk compares equal there and the
+    /// sectors are left to `check`.
+    fn set_aside(&mut self, chunk: &mut [u8], device: &[u8], offset: u64) {
+        let end = offset + chunk.len().min(device.len()) as u64;
+        for ((start, data), original) in self.writes.iter().zip(&mut self.original) {
+            let (from, to) = ((*start).max(offset), (start + data.len() as u64).min(end));
+            if from >= to {
+                continue;
+            }
+            let (in_chunk, in_range, len) = ((from - offset) as usize, (from - start) as usize, (to - from) as usize);
+            original[in_range..in_range + len].copy_from_slice(&chunk[in_chunk..in_chunk + len]);
+            chunk[in_chunk..in_chunk + len].copy_from_slice(&device[in_chunk..in_chunk + len]);
+        }
+    }
+
+    /// Compare the relocated sectors within the first `image_end` bytes with
+    /// the relocated GPT if the image was cut short, or with the image
+    /// otherwise. Returns the first difference.
+    fn check(&self, device: &File, image_end: u64, ignore_holes: bool) -> io::Result<Option<u64>> {
+        let truncated = image_end > self.capacity;
+        let mut first_mismatch = None;
+        for ((start, data), original) in self.writes.iter().zip(&self.original) {
+            let expected = if truncated { data } else { original };
+            let len = (image_end.saturating_sub(*start) as usize).min(expected.len());
+            if len == 0 || (!truncated && ignore_holes && fs::is_zero_chunk(&expected[..len])) {
+                continue;
+            }
+
+            let mut actual = vec![0; len];
+            parallel::read_exact_at(device, &mut actual, *start)?;
+            if let Some(pos) = actual.iter().zip(expected).position(|(a, b)| a != b) {
+                first_mismatch.get_or_insert(start + pos as u64);
+            }
+        }
+        Ok(first_mismatch)
+    }
+}
+
+/// Compare an already-flashed device against an image.
+///
+/// With `ignore_holes`, all-zero chunks of the image are treated as don't-care,
+/// for a device flashed by a tool that leaves the image's zero regions
+/// untouched. The flasher itself always leaves zeros there.
+///
+/// A device that only has room for the image up to its last partition is
+/// compared the way a flash leaves it: up to its end, with the backup GPT
+/// moved there.
+pub fn verify_image<P: AsRef<Path>, Q: AsRef<Path>>(
+    image_path: P,
+    device_path: Q,
+    ignore_holes: bool,
+    threads: usize,
+    progress: Arc<Mutex<Progress>>,
+) -> io::Result<VerifyReport> {
+    let (total_size, is_compressed) = fs::get_file_info(&image_path)?;
+
+    {
+        let mut progress = progress.lock().unwrap();
+        progress.total_bytes = total_size;
+    }
+
+    let file = File::open(&image_path)?;
+    let mut reader = fs::create_reader(&image_path, file, threads)?;
+    let mut device = BufReader::with_capacity(1024 * 8192, File::open(&device_path)?);
+    let capacity = blockdev::device_size(device.get_ref())?;
+    let mut relocation = None;
+
+    let mut image_buffer = vec![0; 1024 * 1024]; // 1MB buffer
+    let mut device_buffer = vec![0; 1024 * 1024];
+    let mut offset = 0u64;
+    let mut header_checked = !is_compressed;
+    let mut size_determined = total_size > 0;
+
+    let mut report = VerifyReport {
+        bytes_compared: 0,
+        bytes_skipped: 0,
+        mismatched_chunks: 0,
+        first_mismatch: None,
+        device_too_small: false,
+        truncated_at: None,
+    };
+
+    loop {
+        let bytes_read = read_chunk(&mut reader, &mut image_buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+
+        if !header_checked {
+            let img_size = fs::get_img_size_from_header(&image_buffer[..bytes_read]);
+            if img_size > 0 {
+                progress.lock().unwrap().total_bytes = img_size;
+                size_determined = true;
+            }
+            header_checked = true;
+        }
+        if offset == 0 {
+            relocation = Relocation::new(&image_buffer[..bytes_read], capacity);
+        }
+
+        // only what fits is compared on a device that is short of nothing
+        // but the space past the image's last partition
+        let fits = relocation
+            .as_ref()
+            .map_or(bytes_read, |r| r.capacity.saturating_sub(offset).min(bytes_read as u64) as usize);
+        if fits < bytes_read {
+            report.truncated_at = relocation.as_ref().map(|r| r.capacity);
+            report.bytes_skipped += (bytes_read - fits) as u64;
+        }
+        let chunk = &mut image_buffer[..fits];
+
+        if ignore_holes && fs::is_zero_chunk(chunk) {
+            device.seek_relative(fits as i64)?;
+            report.bytes_skipped += fits as u64;
+        } else {
+            let device_read = read_chunk(&mut device, &mut device_buffer[..fits])?;
+            if device_read < fits {
+                report.device_too_small = true;
+            }
+            if let Some(relocation) = relocation.as_mut() {
+                relocation.set_aside(chunk, &device_buffer[..device_read], offset);
+            }
+
+            if let Some(pos) = chunk[..device_read]
+                .iter()
+                .zip(&device_buffer[..device_read])
+                .position(|(a, b)| a != b)
+            {
+                report.mismatched_chunks += 1;
+                report.first_mismatch.get_or_insert(offset + pos as u64);
+            }
+            report.bytes_compared += device_read as u64;
+
+            if report.device_too_small {
+                report.first_mismatch.get_or_insert(offset + device_read as u64);
+                break;
+            }
+        }
+
+        offset += bytes_read as u64;
+
+        {
+            let mut progress = progress.lock().unwrap();
+            progress.bytes_written = offset;
+
+            // If we haven't determined the size yet, use streaming-style progress
+            if !size_determined {
+                progress.total_bytes = offset + (offset / 4).max(1024 * 1024);
+            }
+        }
+    }
+
+    if let Some(relocation) = relocation.filter(|_| !report.device_too_small) {
+        if let Some(mismatch) = relocation.check(device.get_ref(), offset, ignore_holes)? {
+            report.mismatched_chunks += 1;
+            report.first_mismatch = Some(report.first_mismatch.map_or(mismatch, |first| first.min(mismatch)));
+        }
+    }
+
+    {
+        let mut progress = progress.lock().unwrap();
+        progress.total_bytes = offset;
+        progress.bytes_written = offset;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{gpt_image, Noise, TempFile};
+
+    const MIB: usize = 1024 * 1024;
+
+    fn verify(image: &[u8], device: &[u8], ignore_holes: bool) -> VerifyReport {
+        let (image, device) = (TempFile::new(image), TempFile::new(device));
+        let progress = Arc::new(Mutex::new(Progress::new(0)));
+        verify_image(&image.path, &device.path, ignore_holes, 1, progress).unwrap()
+    }
+
+    /// Hands out a byte at a time, as a decoder may.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.0.len().min(buf.len()).min(1);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn chunks_are_filled_however_the_reader_splits_them() {
+        let data = Noise(1).bytes(1000);
+        let mut reader = Trickle(&data);
+        let mut buffer = vec![0; 600];
+        assert_eq!(read_chunk(&mut reader, &mut buffer).unwrap(), 600);
+        assert!(buffer == data[..600]);
+        assert_eq!(read_chunk(&mut reader, &mut buffer).unwrap(), 400);
+        assert!(buffer[..400] == data[600..]);
+        assert_eq!(read_chunk(&mut reader, &mut buffer).unwrap(), 0);
+    }
+
+    #[test]
+    fn a_matching_device_passes() {
+        let image = Noise(2).bytes(3 * MIB + 100);
+        let report = verify(&image, &image, false);
+        assert!(report.is_match());
+        assert_eq!((report.bytes_compared, report.bytes_skipped), (image.len() as u64, 0));
+
+        // anything past the image on the device does not matter
+        let device = [&image[..], &Noise(3).bytes(MIB)].concat();
+        assert!(verify(&image, &device, false).is_match());
+    }
+
+    #[test]
+    fn mismatches_are_counted_per_chunk_from_the_first() {
+        let image = Noise(4).bytes(4 * MIB);
+        let mut device = image.clone();
+        device[2 * MIB + 5] ^= 1;
+        device[2 * MIB + 700] ^= 1;
+        device[3 * MIB + 1] ^= 1;
+
+        let report = verify(&image, &device, false);
+        assert!(!report.is_match());
+        assert_eq!(report.mismatched_chunks, 2);
+        assert_eq!(report.first_mismatch, Some(2 * MIB as u64 + 5));
+        assert!(!report.device_too_small);
+    }
+
+    #[test]
+    fn a_short_device_fails_where_it_ends() {
+        let image = Noise(5).bytes(3 * MIB);
+        let report = verify(&image, &image[..2 * MIB + 10], false);
+        assert!(report.device_too_small);
+        assert!(!report.is_match());
+        assert_eq!(report.first_mismatch, Some(2 * MIB as u64 + 10));
+        assert_eq!(report.bytes_compared, 2 * MIB as u64 + 10);
+    }
+
+    #[test]
+    fn ignored_holes_may_hold_anything() {
+        let mut image = Noise(6).bytes(4 * MIB);
+        image[MIB..3 * MIB].fill(0);
+        let mut device = image.clone();
+        device[MIB..3 * MIB].copy_from_slice(&Noise(7).bytes(2 * MIB));
+
+        let report = verify(&image, &device, false);
+        assert_eq!(report.first_mismatch, Some(MIB as u64));
+
+        let report = verify(&image, &device, true);
+        assert!(report.is_match());
+        assert_eq!((report.bytes_compared, report.bytes_skipped), (2 * MIB as u64, 2 * MIB as u64));
+
+        // a hole still has to be there, even if its contents are ignored
+        assert!(verify(&image, &device[..2 * MIB], true).device_too_small);
+    }
+
+    #[test]
+    fn a_device_cut_short_is_compared_to_its_end() {
+        // a 4 MiB image whose partition ends at 1 MiB, on a 3 MiB device
+        let (sectors, capacity) = (4 * MIB as u64 / 512, 3 * MIB);
+        let mut image = Noise(8).bytes(4 * MIB);
+        image[..34 * 512].copy_from_slice(&gpt_image(sectors, &[(34, 2047)]));
+        image[MIB..].fill(0);
+
+        let writes = partition::relocate_gpt(&image, capacity as u64).unwrap();
+        let first_change = image.iter().zip(&writes[0].1).position(|(a, b)| a != b).map(|i| i as u64);
+        let mut device = image[..capacity].to_vec();
+        for (offset, data) in writes.clone() {
+            device[offset as usize..offset as usize + data.len()].copy_from_slice(&data);
+        }
+
+        let report = verify(&image, &device, false);
+        assert!(report.is_match(), "mismatch at {:?}", report.first_mismatch);
+        assert_eq!(report.truncated_at, Some(capacity as u64));
+        assert_eq!((report.bytes_compared, report.bytes_skipped), (capacity as u64, MIB as u64));
+
+        // the image's own GPT is not what a flash leaves on that device
+        let report = verify(&image, &image[..capacity], false);
+        assert!(!report.is_match());
+        assert_eq!(report.first_mismatch, first_change);
+
+        // nor is the relocated GPT what it leaves on a device that fits the image
+        let mut device = image.clone();
+        device[..writes[0].1.len()].copy_from_slice(&writes[0].1);
+        assert!(verify(&image, &image, false).is_match());
+        assert_eq!(verify(&image, &device, false).first_mismatch, first_change);
+    }
+}
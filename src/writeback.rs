@@ -0,0 +1,258 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::recovery::SyncWindow;
+
+const MIB: u64 = 1024 * 1024;
+
+/// How a device's writes are pushed to stable storage while it is flashed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+    /// Sync after every this many bytes
+    Bytes(u64),
+    /// Sync once this long has passed since the last sync
+    Interval(Duration),
+    /// Start writeback of every this many bytes as soon as they are written,
+    /// then wait for the ones before and drop them from the page cache
+    Dirty(u64),
+    /// Open the device with O_SYNC, so every write is durable once it returns
+    OSync,
+}
+
+impl Default for SyncPolicy {
+    fn default() -> Self {
+        SyncPolicy::Bytes(32 * MIB)
+    }
+}
+
+/// Parsed from `bytes[:MiB]`, `interval[:ms]`, `dirty[:MiB]` or `o-sync`.
+impl FromStr for SyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = match s.split_once(':') {
+            Some((name, value)) => {
+                let value = value
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|&value| value > 0)
+                    .ok_or_else(|| format!("invalid sync policy amount '{}'", value))?;
+                (name, Some(value))
+            }
+            None => (s, None),
+        };
+
+        match (name, value) {
+            ("bytes", value) => Ok(SyncPolicy::Bytes(value.unwrap_or(32) * MIB)),
+            ("interval", value) => Ok(SyncPolicy::Interval(Duration::from_millis(value.unwrap_or(1000)))),
+            ("dirty", value) => Ok(SyncPolicy::Dirty(value.unwrap_or(16) * MIB)),
+            ("o-sync", None) => Ok(SyncPolicy::OSync),
+            _ => Err(format!(
+                "unknown sync policy '{}', expected bytes[:MiB], interval[:ms], dirty[:MiB] or o-sync", s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncPolicy::Bytes(bytes) => write!(f, "synced every {} MiB", bytes / MIB),
+            SyncPolicy::Interval(interval) => write!(f, "synced every {} ms", interval.as_millis()),
+            SyncPolicy::Dirty(bytes) => write!(f, "written back every {} MiB (sync_file_range)", bytes / MIB),
+            SyncPolicy::OSync => f.write_str("written with O_SYNC"),
+        }
+    }
+}
+
+/// A sync policy for a single target, given as `PATH=POLICY`.
+#[derive(Debug, Clone)]
+pub struct DeviceSyncPolicy {
+    pub path: String,
+    pub policy: SyncPolicy,
+}
+
+impl FromStr for DeviceSyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, policy) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("expected PATH=POLICY, got '{}'", s))?;
+        Ok(DeviceSyncPolicy { path: path.to_string(), policy: policy.parse()? })
+    }
+}
+
+/// Where a device stands with its sync policy.
+#[derive(Debug)]
+pub struct Writeback {
+    pub policy: SyncPolicy,
+    last_sync: Instant,
+    /// Writeback has been started on everything before this offset
+    pub started: u64,
+    /// and waited for on everything before this one
+    pub finished: u64,
+}
+
+impl Writeback {
+    pub fn new(policy: SyncPolicy) -> Self {
+        Writeback { policy, last_sync: Instant::now(), started: 0, finished: 0 }
+    }
+
+    /// Whether the device should be synced or written back now. However
+    /// lax the policy, the sync window never grows past `limit`, since all
+    /// of it is held in memory.
+    pub fn due(&self, window: &SyncWindow, limit: u64) -> bool {
+        match self.policy {
+            SyncPolicy::Bytes(bytes) => window.len() >= bytes.min(limit),
+            SyncPolicy::Interval(interval) => window.len() >= limit || self.last_sync.elapsed() >= interval,
+            SyncPolicy::Dirty(bytes) => window.len() >= limit || window.end() - self.started >= bytes.min(limit / 2),
+            SyncPolicy::OSync => window.len() >= limit,
+        }
+    }
+
+    /// Note a sync that left everything up to `end` on stable storage.
+    pub fn synced(&mut self, end: u64) {
+        self.last_sync = Instant::now();
+        self.started = end;
+        self.finished = end;
+    }
+}
+
+/// Start writing back `len` bytes at `offset` without waiting for them.
+#[cfg(target_os = "linux")]
+pub fn start(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    sync_file_range(file, offset, len, libc::SYNC_FILE_RANGE_WRITE)
+}
+
+/// Wait until `len` bytes at `offset` are written back, then drop them from
+/// the page cache.
+#[cfg(target_os = "linux")]
+pub fn finish(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    sync_file_range(
+        file,
+        offset,
+        len,
+        libc::SYNC_FILE_RANGE_WAIT_BEFORE | libc::SYNC_FILE_RANGE_WRITE | libc::SYNC_FILE_RANGE_WAIT_AFTER,
+    )?;
+    // dropping the pages is only advice, there is nothing to do if it is not taken
+    if len > 0 {
+        unsafe {
+            libc::posix_fadvise(
+                file.as_raw_fd(),
+                offset as libc::off_t,
+                len as libc::off_t,
+                libc::POSIX_FADV_DONTNEED,
+            )
+        };
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn sync_file_range(file: &File, offset: u64, len: u64, flags: libc::c_uint) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // a length of 0 would mean everything up to the end of the file
+    if len == 0 {
+        return Ok(());
+    }
+    let result = unsafe {
+        libc::sync_file_range(file.as_raw_fd(), offset as libc::off64_t, len as libc::off64_t, flags)
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Without sync_file_range, writeback only happens on a full sync.
+#[cfg(not(target_os = "linux"))]
+pub fn start(_file: &File, _offset: u64, _len: u64) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn finish(file: &File, _offset: u64, _len: u64) -> io::Result<()> {
+    file.sync_data()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(len: u64) -> SyncWindow {
+        let mut window = SyncWindow::new();
+        window.push_hole(len);
+        window
+    }
+
+    #[test]
+    fn parses_sync_policies() {
+        assert_eq!("bytes".parse(), Ok(SyncPolicy::Bytes(32 * MIB)));
+        assert_eq!("bytes:8".parse(), Ok(SyncPolicy::Bytes(8 * MIB)));
+        assert_eq!("interval".parse(), Ok(SyncPolicy::Interval(Duration::from_secs(1))));
+        assert_eq!("interval:250".parse(), Ok(SyncPolicy::Interval(Duration::from_millis(250))));
+        assert_eq!("dirty".parse(), Ok(SyncPolicy::Dirty(16 * MIB)));
+        assert_eq!("dirty:4".parse(), Ok(SyncPolicy::Dirty(4 * MIB)));
+        assert_eq!("o-sync".parse(), Ok(SyncPolicy::OSync));
+
+        for invalid in ["", "bytes:0", "bytes:-1", "bytes:1.5", "dirty:", "o-sync:1", "sometimes"] {
+            assert!(invalid.parse::<SyncPolicy>().is_err(), "{} was accepted", invalid);
+        }
+    }
+
+    #[test]
+    fn parses_device_sync_policies() {
+        let policy: DeviceSyncPolicy = "/dev/disk/by-id/usb-a=b=dirty:8".parse().unwrap();
+        assert_eq!((policy.path.as_str(), policy.policy), ("/dev/disk/by-id/usb-a=b", SyncPolicy::Dirty(8 * MIB)));
+        assert!("/dev/sdb".parse::<DeviceSyncPolicy>().is_err());
+        assert!("/dev/sdb=often".parse::<DeviceSyncPolicy>().is_err());
+    }
+
+    #[test]
+    fn the_window_never_grows_past_the_limit() {
+        let limit = 32 * MIB;
+        for policy in [
+            SyncPolicy::Bytes(64 * MIB),
+            SyncPolicy::Interval(Duration::from_secs(3600)),
+            SyncPolicy::OSync,
+        ] {
+            let writeback = Writeback::new(policy);
+            assert!(!writeback.due(&window(limit - 1), limit), "{} is due early", policy);
+            assert!(writeback.due(&window(limit), limit), "{} is not due at the limit", policy);
+        }
+
+        let writeback = Writeback::new(SyncPolicy::Bytes(8 * MIB));
+        assert!(!writeback.due(&window(8 * MIB - 1), limit));
+        assert!(writeback.due(&window(8 * MIB), limit));
+        assert!(Writeback::new(SyncPolicy::Interval(Duration::ZERO)).due(&window(0), limit));
+    }
+
+    #[test]
+    fn dirty_writeback_is_due_per_written_stretch() {
+        let limit = 32 * MIB;
+        let mut writeback = Writeback::new(SyncPolicy::Dirty(4 * MIB));
+        assert!(!writeback.due(&window(4 * MIB - 1), limit));
+        assert!(writeback.due(&window(4 * MIB), limit));
+
+        // writeback started on the first 4 MiB does not empty the window
+        writeback.finished = writeback.started;
+        writeback.started = 4 * MIB;
+        assert!(!writeback.due(&window(8 * MIB - 1), limit));
+        assert!(writeback.due(&window(8 * MIB), limit));
+
+        // a stretch is never more than half the window's limit
+        let writeback = Writeback::new(SyncPolicy::Dirty(64 * MIB));
+        assert!(writeback.due(&window(16 * MIB), limit));
+
+        let mut writeback = Writeback::new(SyncPolicy::Dirty(4 * MIB));
+        writeback.synced(8 * MIB);
+        assert_eq!((writeback.started, writeback.finished), (8 * MIB, 8 * MIB));
+    }
+}
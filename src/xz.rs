@@ -0,0 +1,277 @@
+use std::fs::File;
+use std::io::{self, Cursor, Read};
+use std::sync::Arc;
+
+use liblzma::read::XzDecoder;
+
+use crate::parallel::{self, FileRange, Segments};
+
+const HEADER_MAGIC: [u8; 6] = [0xfd, b'7', b'z', b'X', b'Z', 0];
+const FOOTER_MAGIC: [u8; 2] = *b"YZ";
+
+pub fn is_xz(header: &[u8]) -> bool {
+    header.starts_with(&HEADER_MAGIC)
+}
+
+/// A block of an xz stream, as its stream's index lists it.
+struct Block {
+    offset: u64,
+    /// Header, compressed data and check, without the padding after them
+    unpadded: u64,
+    uncompressed: u64,
+    /// Stream flags, which give the kind of check the block ends in
+    flags: [u8; 2],
+}
+
+/// The blocks of an xz file. Each one is decoded on its own by wrapping it
+/// in a stream of its own, with a header and an index that list just it.
+pub struct XzBlocks {
+    source: Arc<File>,
+    blocks: Vec<Block>,
+}
+
+impl XzBlocks {
+    /// Read the index of every stream in the file, from the last one back.
+    pub fn read(file: File) -> io::Result<Self> {
+        let source = Arc::new(file);
+        let mut end = source.metadata()?.len();
+        let mut streams = Vec::new();
+
+        while end > 0 {
+            // streams may be followed by padding, in multiples of 4 bytes
+            let mut padding = [0; 4];
+            parallel::read_exact_at(&source, &mut padding, end.checked_sub(4).ok_or_else(|| damaged("file"))?)?;
+            if padding == [0; 4] {
+                end -= 4;
+                continue;
+            }
+
+            let (stream_start, blocks) = read_stream(&source, end)?;
+            streams.push(blocks);
+            end = stream_start;
+        }
+
+        let blocks = streams.into_iter().rev().flatten().collect();
+        Ok(XzBlocks { source, blocks })
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Size of the whole image once decoded.
+    pub fn uncompressed_size(&self) -> u64 {
+        self.blocks.iter().map(|b| b.uncompressed).sum()
+    }
+}
+
+impl Segments for XzBlocks {
+    fn count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    fn decoded_size(&self, index: usize) -> Option<u64> {
+        Some(self.blocks[index].uncompressed)
+    }
+
+    fn open(&self, index: usize) -> io::Result<Box<dyn Read + Send>> {
+        let block = &self.blocks[index];
+        let mut header = HEADER_MAGIC.to_vec();
+        header.extend_from_slice(&block.flags);
+        header.extend_from_slice(&crc32fast::hash(&block.flags).to_le_bytes());
+
+        let mut index = vec![0];
+        for value in [1, block.unpadded, block.uncompressed] {
+            write_varint(&mut index, value);
+        }
+        index.resize(index.len().next_multiple_of(4), 0);
+        index.extend_from_slice(&crc32fast::hash(&index).to_le_bytes());
+
+        let mut footer = ((index.len() / 4 - 1) as u32).to_le_bytes().to_vec();
+        footer.extend_from_slice(&block.flags);
+        let mut trailer = index;
+        trailer.extend_from_slice(&crc32fast::hash(&footer).to_le_bytes());
+        trailer.extend_from_slice(&footer);
+        trailer.extend_from_slice(&FOOTER_MAGIC);
+
+        let data = FileRange::new(self.source.clone(), block.offset, block.unpadded.next_multiple_of(4));
+        Ok(Box::new(XzDecoder::new(Cursor::new(header).chain(data).chain(Cursor::new(trailer)))))
+    }
+}
+
+/// Read the stream that ends at `end` from its footer and index, returning
+/// where it starts and its blocks.
+fn read_stream(source: &File, end: u64) -> io::Result<(u64, Vec<Block>)> {
+    let mut footer = [0; 12];
+    parallel::read_exact_at(source, &mut footer, end.checked_sub(12).ok_or_else(|| damaged("stream footer"))?)?;
+    if footer[10..] != FOOTER_MAGIC || crc32fast::hash(&footer[4..10]).to_le_bytes() != footer[..4] {
+        return Err(damaged("stream footer"));
+    }
+    let flags = [footer[8], footer[9]];
+
+    let index_size = (u32::from_le_bytes(footer[4..8].try_into().unwrap()) as u64 + 1) * 4;
+    let index_start = (end - 12).checked_sub(index_size).ok_or_else(|| damaged("index"))?;
+    let mut index = vec![0; index_size as usize];
+    parallel::read_exact_at(source, &mut index, index_start)?;
+    let (body, crc) = index.split_at(index.len() - 4);
+    if body[0] != 0 || crc32fast::hash(body).to_le_bytes() != crc {
+        return Err(damaged("index"));
+    }
+
+    let mut pos = 1;
+    let records = read_varint(body, &mut pos)?;
+    let mut sizes = Vec::new();
+    for _ in 0..records {
+        sizes.push((read_varint(body, &mut pos)?, read_varint(body, &mut pos)?));
+    }
+
+    let blocks_size: u64 = sizes.iter().map(|&(unpadded, _)| unpadded.next_multiple_of(4)).sum();
+    let stream_start = index_start
+        .checked_sub(blocks_size)
+        .and_then(|start| start.checked_sub(12))
+        .ok_or_else(|| damaged("index"))?;
+    let mut header = [0; 12];
+    parallel::read_exact_at(source, &mut header, stream_start)?;
+    if !is_xz(&header) || header[6..8] != flags || crc32fast::hash(&flags).to_le_bytes() != header[8..] {
+        return Err(damaged("stream header"));
+    }
+
+    let mut offset = stream_start + 12;
+    let blocks = sizes
+        .into_iter()
+        .map(|(unpadded, uncompressed)| {
+            let block = Block { offset, unpadded, uncompressed, flags };
+            offset += unpadded.next_multiple_of(4);
+            block
+        })
+        .collect();
+    Ok((stream_start, blocks))
+}
+
+fn damaged(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("xz {} is damaged", what))
+}
+
+/// Read one of the index's variable length integers, 7 bits to a byte.
+fn read_varint(data: &[u8], pos: &mut usize) -> io::Result<u64> {
+    let mut value = 0;
+    for shift in (0..63).step_by(7) {
+        let byte = *data.get(*pos).ok_or_else(|| damaged("index"))?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(damaged("index"))
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Noise, TempFile};
+    use liblzma::stream::{Action, Check, Status, Stream};
+
+    /// An xz stream with a block for each of `pieces`, as `xz -T0` writes them.
+    fn stream(pieces: &[&[u8]], check: Check) -> Vec<u8> {
+        let mut encoder = Stream::new_easy_encoder(1, check).unwrap();
+        let mut out = Vec::new();
+        for (i, piece) in pieces.iter().enumerate() {
+            let action = if i + 1 == pieces.len() { Action::Finish } else { Action::FullFlush };
+            let start = encoder.total_in();
+            loop {
+                out.reserve(64 * 1024);
+                let consumed = (encoder.total_in() - start) as usize;
+                if encoder.process_vec(&piece[consumed..], &mut out, action).unwrap() == Status::StreamEnd {
+                    break;
+                }
+            }
+        }
+        out
+    }
+
+    fn decode_all(blocks: &XzBlocks) -> Vec<u8> {
+        let mut out = Vec::new();
+        for index in 0..blocks.count() {
+            blocks.open(index).unwrap().read_to_end(&mut out).unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn reads_the_blocks_of_a_stream() {
+        let pieces = [Noise(1).bytes(200 * 1024), vec![0; 300 * 1024], Noise(2).bytes(1)];
+        let pieces: Vec<&[u8]> = pieces.iter().map(|p| &p[..]).collect();
+
+        for check in [Check::None, Check::Crc32, Check::Crc64, Check::Sha256] {
+            let file = TempFile::new(&stream(&pieces, check));
+            let blocks = XzBlocks::read(file.open()).unwrap();
+            assert_eq!(blocks.len(), 3);
+            assert_eq!(blocks.blocks[0].offset, 12);
+            let sizes: Vec<u64> = blocks.blocks.iter().map(|b| b.uncompressed).collect();
+            assert_eq!(sizes, vec![200 * 1024, 300 * 1024, 1]);
+            assert!(decode_all(&blocks) == pieces.concat());
+        }
+    }
+
+    #[test]
+    fn reads_every_stream_past_padding() {
+        let (first, second) = (Noise(3).bytes(100 * 1024), Noise(4).bytes(50 * 1024));
+        let mut data = stream(&[&first, &first], Check::Crc64);
+        data.extend_from_slice(&[0; 8]);
+        data.extend(stream(&[&second], Check::Crc32));
+        data.extend_from_slice(&[0; 4]);
+
+        let file = TempFile::new(&data);
+        let blocks = XzBlocks::read(file.open()).unwrap();
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks.uncompressed_size(), (2 * first.len() + second.len()) as u64);
+        assert!(decode_all(&blocks) == [&first[..], &first, &second].concat());
+    }
+
+    #[test]
+    fn rejects_damaged_streams() {
+        let data = stream(&[&Noise(5).bytes(4096)], Check::Crc64);
+        let damage = |offset: usize| {
+            let mut damaged = data.clone();
+            damaged[offset] ^= 1;
+            let file = TempFile::new(&damaged);
+            read_stream(&file.open(), damaged.len() as u64).err().map(|e| e.kind())
+        };
+
+        // footer CRC, index and stream header
+        assert_eq!(damage(data.len() - 12), Some(io::ErrorKind::InvalidData));
+        assert_eq!(damage(data.len() - 20), Some(io::ErrorKind::InvalidData));
+        assert_eq!(damage(7), Some(io::ErrorKind::InvalidData));
+        assert!(XzBlocks::read(TempFile::new(&data[1..]).open()).is_err());
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        for value in [0, 1, 127, 128, 300, 16383, 16384, u32::MAX as u64, 1 << 62, (1 << 63) - 1] {
+            let mut encoded = Vec::new();
+            write_varint(&mut encoded, value);
+            assert_eq!(encoded.len(), (64 - value.leading_zeros() as usize).div_ceil(7).max(1));
+
+            let mut pos = 0;
+            assert_eq!(read_varint(&encoded, &mut pos).unwrap(), value);
+            assert_eq!(pos, encoded.len());
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_and_overlong_varints() {
+        let mut pos = 0;
+        assert!(read_varint(&[0x80, 0x80], &mut pos).is_err());
+        let mut pos = 0;
+        assert!(read_varint(&[0xff; 10], &mut pos).is_err());
+    }
+}
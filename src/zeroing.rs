@@ -0,0 +1,340 @@
+use std::fmt;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::blockdev;
+use crate::fs::{self, Progress};
+
+/// Discards are issued in pieces of this size so the discard phase can report
+/// progress
+const DISCARD_STEP: u64 = 256 * 1024 * 1024;
+
+/// Runs of zeros shorter than this are written rather than zeroed by the
+/// device, as every range it is asked to zero costs a flush of the writer and
+/// a synchronous ioctl
+pub const MIN_OFFLOAD: u64 = 1024 * 1024;
+
+/// How runs of zeros in the image reach a device.
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+pub enum ZeroStrategy {
+    /// Pick the fastest strategy that leaves real zeros behind
+    #[default]
+    Auto,
+    /// Write the zeros like any other data
+    Write,
+    /// Have the device zero the range itself (BLKZEROOUT)
+    ZeroOut,
+    /// Unmap the range so it reads back as zeros (fallocate PUNCH_HOLE), only on
+    /// devices that can do so without being sent the zeros
+    Discard,
+    /// Seek over the range, only where it is known to hold zeros already
+    Skip,
+}
+
+impl fmt::Display for ZeroStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ZeroStrategy::Auto => "auto",
+            ZeroStrategy::Write => "written as zeros",
+            ZeroStrategy::ZeroOut => "zeroed by the device (BLKZEROOUT)",
+            ZeroStrategy::Discard => "unmapped to zeros (PUNCH_HOLE)",
+            ZeroStrategy::Skip => "skipped",
+        })
+    }
+}
+
+/// Whether a target is discarded in full before the image is written.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DiscardMode {
+    #[default]
+    Off,
+    /// Unmap the device so it reads back as zeros where it can, otherwise
+    /// just discard it
+    Discard,
+    /// Also make the old data unrecoverable, where the device can
+    Secure,
+}
+
+/// What the discard pass did to a device.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiscardOutcome {
+    /// Unmapped through PUNCH_HOLE, so the whole device reads as zeros
+    Zeroed,
+    /// Discarded with BLKDISCARD, which promises nothing about what it reads as
+    Discarded,
+    SecureDiscarded,
+    /// Secure discard was refused, the device got a plain discard instead
+    DiscardedInsecurely,
+    Unsupported,
+}
+
+impl DiscardOutcome {
+    /// Whether the device is known to read as zeros everywhere afterwards.
+    pub fn zeroed(self) -> bool {
+        self == DiscardOutcome::Zeroed
+    }
+}
+
+impl fmt::Display for DiscardOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            DiscardOutcome::Zeroed => "unmapped to zeros before flashing",
+            DiscardOutcome::Discarded => "discarded before flashing",
+            DiscardOutcome::SecureDiscarded => "securely discarded before flashing",
+            DiscardOutcome::DiscardedInsecurely => {
+                "discarded before flashing, but does not support secure discard"
+            }
+            DiscardOutcome::Unsupported => "not discarded, it does not support discard",
+        })
+    }
+}
+
+/// A stretch of a piece of the image that is either all zeros or holds data.
+pub struct Extent {
+    pub range: Range<usize>,
+    pub zero: bool,
+}
+
+/// Split `data`, which goes to the device at `offset`, into runs of zero
+/// blocks and runs of data. It is looked at in blocks of `granularity` bytes
+/// aligned to the device, so a run of zeros is only split out where it covers
+/// whole blocks.
+pub fn zero_extents(data: &[u8], offset: u64, granularity: usize) -> Vec<Extent> {
+    let granularity = granularity.max(1);
+    let mut extents: Vec<Extent> = Vec::new();
+    let mut start = 0;
+
+    while start < data.len() {
+        let misalignment = ((offset + start as u64) % granularity as u64) as usize;
+        let end = (start + granularity - misalignment).min(data.len());
+        let zero = fs::is_zero_chunk(&data[start..end]);
+        match extents.last_mut() {
+            Some(last) if last.zero == zero => last.range.end = end,
+            _ => extents.push(Extent { range: start..end, zero }),
+        }
+        start = end;
+    }
+    extents
+}
+
+/// Whether the device accepts discards at all.
+pub fn supports_discard<P: AsRef<Path>>(device_path: P) -> bool {
+    blockdev::queue_attribute(device_path, "discard_max_bytes")
+        .and_then(|bytes| bytes.parse::<u64>().ok())
+        .is_some_and(|bytes| bytes > 0)
+}
+
+/// Whether the device can be unmapped with a guarantee that it reads back as
+/// zeros, through PUNCH_HOLE. That needs write-zeroes support on top of discard.
+pub fn discard_zeroes<P: AsRef<Path>>(device_path: P) -> bool {
+    supports_discard(&device_path) && supports_write_zeroes(&device_path)
+}
+
+/// Discard the whole device, reporting progress as it goes. Devices without
+/// discard support are left alone, and a refused secure discard falls back to
+/// a plain one. A plain discard unmaps through PUNCH_HOLE where the device
+/// supports it, so that it is known to read as zeros afterwards, and falls
+/// back to BLKDISCARD otherwise.
+#[cfg(target_os = "linux")]
+pub fn discard_device<P: AsRef<Path>>(
+    device_path: P,
+    mode: DiscardMode,
+    progress: &Arc<Mutex<Progress>>,
+) -> io::Result<DiscardOutcome> {
+    if mode == DiscardMode::Off || !supports_discard(&device_path) {
+        return Ok(DiscardOutcome::Unsupported);
+    }
+
+    let device = std::fs::OpenOptions::new().write(true).open(&device_path)?;
+    let size = blockdev::device_size(&device)?;
+    {
+        let mut progress = progress.lock().unwrap();
+        *progress = Progress::new(size);
+        progress.discarding = Some(device_path.as_ref().display().to_string());
+    }
+
+    let mut secure = mode == DiscardMode::Secure;
+    let mut zeroing = mode == DiscardMode::Discard && supports_write_zeroes(&device_path);
+    let mut outcome = if secure {
+        DiscardOutcome::SecureDiscarded
+    } else if zeroing {
+        DiscardOutcome::Zeroed
+    } else {
+        DiscardOutcome::Discarded
+    };
+    let mut offset = 0;
+    while offset < size {
+        let len = DISCARD_STEP.min(size - offset);
+        let result = if secure {
+            blockdev::secure_discard_range(&device, offset, len)
+        } else if zeroing {
+            blockdev::punch_hole(&device, offset, len)
+        } else {
+            blockdev::discard_range(&device, offset, len)
+        };
+
+        match result {
+            Ok(()) => {
+                offset += len;
+                progress.lock().unwrap().bytes_written = offset;
+            }
+            Err(e) if is_unsupported(&e) && zeroing => {
+                // carry on with plain discards, the device no longer reads as zeros throughout
+                zeroing = false;
+                outcome = DiscardOutcome::Discarded;
+            }
+            Err(e) if is_unsupported(&e) && secure => {
+                // start over, a secure discard has to cover everything or nothing
+                secure = false;
+                offset = 0;
+                outcome = DiscardOutcome::DiscardedInsecurely;
+            }
+            Err(e) if is_unsupported(&e) && offset == 0 => return Ok(DiscardOutcome::Unsupported),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(outcome)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn discard_device<P: AsRef<Path>>(
+    _device_path: P,
+    _mode: DiscardMode,
+    _progress: &Arc<Mutex<Progress>>,
+) -> io::Result<DiscardOutcome> {
+    Ok(DiscardOutcome::Unsupported)
+}
+
+#[cfg(target_os = "linux")]
+fn is_unsupported(error: &io::Error) -> bool {
+    matches!(error.raw_os_error(), Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY))
+}
+
+/// Settle on the strategy a device gets, making sure that whatever was on it
+/// before cannot survive in the image's zero regions.
+///
+/// `zeroed` says the device is known to read as zeros everywhere, which holds
+/// for a regular file that is being replaced and for a device that was unmapped
+/// to zeros beforehand. Only then are zeros skipped. A plain discard is not
+/// enough, discarded blocks may read back as anything.
+pub fn resolve<P: AsRef<Path>>(
+    strategy: ZeroStrategy,
+    device_path: P,
+    zeroed: bool,
+) -> io::Result<ZeroStrategy> {
+    let display = device_path.as_ref().display();
+    let is_block_device = cfg!(target_os = "linux") && std::fs::metadata(&device_path).is_ok_and(|m| !m.is_file());
+
+    match strategy {
+        ZeroStrategy::Auto if zeroed => Ok(ZeroStrategy::Skip),
+        ZeroStrategy::Auto if is_block_device && supports_write_zeroes(&device_path) => Ok(ZeroStrategy::ZeroOut),
+        ZeroStrategy::Auto => Ok(ZeroStrategy::Write),
+        ZeroStrategy::Skip if !zeroed => Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+            "Zero blocks cannot be skipped on {} unless it is known to read as zeros, its old contents would show \
+             through; --discard only guarantees that on devices that support write-zeroes",
+            display
+        ))),
+        ZeroStrategy::ZeroOut | ZeroStrategy::Discard if !is_block_device => {
+            Err(io::Error::new(io::ErrorKind::Unsupported, format!(
+                "{} is not a block device that supports {}", display, strategy
+            )))
+        }
+        ZeroStrategy::Discard if !discard_zeroes(&device_path) => {
+            Err(io::Error::new(io::ErrorKind::Unsupported, format!(
+                "{} cannot unmap blocks and guarantee that they read back as zeros", display
+            )))
+        }
+        strategy => Ok(strategy),
+    }
+}
+
+/// Whether the device can zero ranges without being sent the zeros.
+fn supports_write_zeroes<P: AsRef<Path>>(device_path: P) -> bool {
+    blockdev::queue_attribute(device_path, "write_zeroes_max_bytes")
+        .and_then(|bytes| bytes.parse::<u64>().ok())
+        .is_some_and(|bytes| bytes > 0)
+}
+
+/// Zero `len` bytes at `offset` on a device with a ZeroOut or Discard
+/// strategy, without sending it the zeros. Both leave zeros behind for certain,
+/// a plain BLKDISCARD does not.
+#[cfg(target_os = "linux")]
+pub fn clear_range(file: &std::fs::File, strategy: ZeroStrategy, offset: u64, len: u64) -> io::Result<()> {
+    match strategy {
+        ZeroStrategy::ZeroOut => blockdev::zero_range(file, offset, len),
+        ZeroStrategy::Discard => blockdev::punch_hole(file, offset, len),
+        _ => Err(io::Error::from(io::ErrorKind::Unsupported)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn clear_range(_file: &std::fs::File, _strategy: ZeroStrategy, _offset: u64, _len: u64) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extents(data: &[u8], offset: u64, granularity: usize) -> Vec<(Range<usize>, bool)> {
+        zero_extents(data, offset, granularity).into_iter().map(|e| (e.range, e.zero)).collect()
+    }
+
+    #[test]
+    fn splits_runs_of_whole_zero_blocks() {
+        let mut data = vec![0; 16 * 1024];
+        data[4096] = 1;
+        data[3 * 4096 + 100] = 1;
+        assert_eq!(extents(&data, 0, 4096), vec![
+            (0..4096, true),
+            (4096..8192, false),
+            (8192..12288, true),
+            (12288..16384, false),
+        ]);
+
+        assert_eq!(extents(&[0; 8192], 0, 4096), vec![(0..8192, true)]);
+        assert_eq!(extents(&[1; 8192], 0, 4096), vec![(0..8192, false)]);
+        assert!(extents(&[], 0, 4096).is_empty());
+    }
+
+    #[test]
+    fn blocks_are_aligned_to_the_device() {
+        // the piece starts 1 KiB into a block, so its first 3 KiB are a block
+        // of their own
+        let mut data = vec![0; 12 * 1024];
+        data[3 * 1024] = 1;
+        assert_eq!(extents(&data, 1024, 4096), vec![(0..3072, true), (3072..7168, false), (7168..12288, true)]);
+
+        // a zero run that only covers part of a block is not split out
+        let mut data = vec![1; 8192];
+        data[1024..6144].fill(0);
+        assert_eq!(extents(&data, 0, 4096), vec![(0..8192, false)]);
+    }
+
+    #[test]
+    fn a_short_tail_is_a_block_of_its_own() {
+        let mut data = vec![1; 4096 + 100];
+        data[4096..].fill(0);
+        assert_eq!(extents(&data, 0, 4096), vec![(0..4096, false), (4096..4196, true)]);
+
+        // a granularity of zero is taken as single bytes
+        assert_eq!(extents(&[0, 1, 1, 0], 0, 0), vec![(0..1, true), (1..3, false), (3..4, true)]);
+    }
+
+    #[test]
+    fn zeros_are_only_skipped_where_they_are_known() {
+        let file = crate::testing::TempFile::new(b"old contents");
+        let resolve = |strategy, zeroed| resolve(strategy, &file.path, zeroed).map_err(|e| e.kind());
+
+        assert_eq!(resolve(ZeroStrategy::Auto, true), Ok(ZeroStrategy::Skip));
+        assert_eq!(resolve(ZeroStrategy::Auto, false), Ok(ZeroStrategy::Write));
+        assert_eq!(resolve(ZeroStrategy::Skip, true), Ok(ZeroStrategy::Skip));
+        assert_eq!(resolve(ZeroStrategy::Skip, false), Err(io::ErrorKind::InvalidInput));
+        assert_eq!(resolve(ZeroStrategy::Write, true), Ok(ZeroStrategy::Write));
+        assert_eq!(resolve(ZeroStrategy::ZeroOut, false), Err(io::ErrorKind::Unsupported));
+        assert_eq!(resolve(ZeroStrategy::Discard, true), Err(io::ErrorKind::Unsupported));
+    }
+}
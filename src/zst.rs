@@ -0,0 +1,312 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::sync::Arc;
+
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+use crate::parallel::{self, FileRange, Segments};
+
+const FRAME_MAGIC: u32 = 0xfd2fb528;
+/// Skippable frames take any magic number that differs from this in its
+/// lowest 4 bits
+const SKIPPABLE_MAGIC: u32 = 0x184d2a50;
+/// The skippable frame holding the seek table of the seekable format
+const SEEK_TABLE_MAGIC: u32 = 0x184d2a5e;
+const SEEKABLE_MAGIC: u32 = 0x8f92eab1;
+/// Windows up to 2 GiB, as `zstd --long=31` writes them
+pub const WINDOW_LOG_MAX: u32 = 31;
+
+/// A zstd frame, which decodes independently of every other.
+struct Frame {
+    offset: u64,
+    len: u64,
+    content_size: Option<u64>,
+}
+
+/// The frames of a zstd file, such as pzstd and the seekable format write.
+pub struct ZstdFrames {
+    source: Arc<File>,
+    frames: Vec<Frame>,
+}
+
+impl ZstdFrames {
+    /// Find the frames of the file from its seek table if it has one, or
+    /// else by walking the block headers of each frame.
+    pub fn read(file: File) -> io::Result<Self> {
+        let source = Arc::new(file);
+        let frames = match read_seek_table(&source)? {
+            Some(frames) => frames,
+            None => scan_frames(&source)?,
+        };
+        Ok(ZstdFrames { source, frames })
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Decoded size of the frames that record theirs, a lower bound on the
+    /// size of the image.
+    pub fn content_size(&self) -> u64 {
+        self.frames.iter().filter_map(|f| f.content_size).sum()
+    }
+}
+
+impl Segments for ZstdFrames {
+    fn count(&self) -> usize {
+        self.frames.len()
+    }
+
+    fn decoded_size(&self, index: usize) -> Option<u64> {
+        self.frames[index].content_size
+    }
+
+    fn open(&self, index: usize) -> io::Result<Box<dyn Read + Send>> {
+        let frame = &self.frames[index];
+        let mut decoder = ZstdDecoder::new(FileRange::new(self.source.clone(), frame.offset, frame.len))?;
+        decoder.window_log_max(WINDOW_LOG_MAX)?;
+        Ok(Box::new(decoder.single_frame()))
+    }
+}
+
+fn read_u32(source: &File, offset: u64) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    parallel::read_exact_at(source, &mut bytes, offset)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn damaged(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("zstd {} is damaged", what))
+}
+
+/// Read the frames from the seek table at the end of a file in the seekable
+/// format, if it ends in one that adds up.
+fn read_seek_table(source: &File) -> io::Result<Option<Vec<Frame>>> {
+    let length = source.metadata()?.len();
+    if length < 17 {
+        return Ok(None);
+    }
+    let mut footer = [0; 9];
+    parallel::read_exact_at(source, &mut footer, length - 9)?;
+    if u32::from_le_bytes(footer[5..].try_into().unwrap()) != SEEKABLE_MAGIC {
+        return Ok(None);
+    }
+
+    let count = u32::from_le_bytes(footer[..4].try_into().unwrap()) as u64;
+    let entry_size = if footer[4] & 0x80 != 0 { 12 } else { 8 };
+    let table_size = count * entry_size + 9;
+    let Some(table_start) = length.checked_sub(table_size + 8) else {
+        return Ok(None);
+    };
+    if read_u32(source, table_start)? != SEEK_TABLE_MAGIC || read_u32(source, table_start + 4)? as u64 != table_size {
+        return Ok(None);
+    }
+
+    let mut table = vec![0; (count * entry_size) as usize];
+    parallel::read_exact_at(source, &mut table, table_start + 8)?;
+    let mut offset = 0;
+    let mut frames = Vec::new();
+    for entry in table.chunks_exact(entry_size as usize) {
+        let len = u32::from_le_bytes(entry[..4].try_into().unwrap()) as u64;
+        let content_size = u32::from_le_bytes(entry[4..8].try_into().unwrap()) as u64;
+        frames.push(Frame { offset, len, content_size: Some(content_size) });
+        offset += len;
+    }
+
+    // a table that does not cover the file is no use
+    Ok((offset == table_start).then_some(frames))
+}
+
+/// Find the frames by reading each frame header and skipping from block
+/// header to block header.
+fn scan_frames(source: &File) -> io::Result<Vec<Frame>> {
+    let length = source.metadata()?.len();
+    let mut frames = Vec::new();
+    let mut offset = 0;
+
+    while offset < length {
+        let magic = read_u32(source, offset)?;
+        if magic & 0xffff_fff0 == SKIPPABLE_MAGIC {
+            offset += 8 + read_u32(source, offset + 4)? as u64;
+            continue;
+        }
+        if magic != FRAME_MAGIC {
+            return Err(damaged("frame"));
+        }
+
+        let mut header = [0; 14];
+        let available = (length - offset - 4).min(header.len() as u64) as usize;
+        parallel::read_exact_at(source, &mut header[..available], offset + 4)?;
+        let descriptor = header[0];
+        let single_segment = descriptor & 0x20 != 0;
+        let dictionary_len = [0, 1, 2, 4][(descriptor & 3) as usize];
+        let size_len = match descriptor >> 6 {
+            0 if single_segment => 1,
+            0 => 0,
+            flag => 1 << flag,
+        };
+        let size_start = 1 + !single_segment as usize + dictionary_len;
+        let size_bytes = &header[size_start..size_start + size_len];
+        let content_size = match size_len {
+            0 => None,
+            2 => Some(u16::from_le_bytes(size_bytes.try_into().unwrap()) as u64 + 256),
+            _ => Some(size_bytes.iter().rev().fold(0, |size, &b| size << 8 | b as u64)),
+        };
+
+        let mut pos = offset + 4 + (size_start + size_len) as u64;
+        loop {
+            let mut block = [0; 4];
+            parallel::read_exact_at(source, &mut block[..3], pos)?;
+            let block = u32::from_le_bytes(block);
+            let len = match (block >> 1) & 3 {
+                // an RLE block is a single byte repeated
+                1 => 1,
+                3 => return Err(damaged("block")),
+                _ => (block >> 3) as u64,
+            };
+            pos += 3 + len;
+            if block & 1 != 0 {
+                break;
+            }
+        }
+        if descriptor & 4 != 0 {
+            pos += 4;
+        }
+        if pos > length {
+            return Err(damaged("frame"));
+        }
+
+        frames.push(Frame { offset, len: pos - offset, content_size });
+        offset = pos;
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Noise, TempFile};
+    use std::io::Write;
+
+    fn frame(data: &[u8], checksum: bool) -> Vec<u8> {
+        let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), 3).unwrap();
+        encoder.include_checksum(checksum).unwrap();
+        encoder.set_pledged_src_size(Some(data.len() as u64)).unwrap();
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn skippable(magic: u32, contents: &[u8]) -> Vec<u8> {
+        let mut frame = magic.to_le_bytes().to_vec();
+        frame.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+        frame.extend_from_slice(contents);
+        frame
+    }
+
+    /// A seek table as the seekable format appends it, listing `frames`.
+    fn seek_table(frames: &[(&[u8], usize)], checksums: bool) -> Vec<u8> {
+        let mut table = Vec::new();
+        for &(frame, content_size) in frames {
+            table.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            table.extend_from_slice(&(content_size as u32).to_le_bytes());
+            if checksums {
+                table.extend_from_slice(&[0; 4]);
+            }
+        }
+        table.extend_from_slice(&(frames.len() as u32).to_le_bytes());
+        table.push(if checksums { 0x80 } else { 0 });
+        table.extend_from_slice(&SEEKABLE_MAGIC.to_le_bytes());
+        skippable(SEEK_TABLE_MAGIC, &table)
+    }
+
+    fn decode_all(frames: &ZstdFrames) -> Vec<u8> {
+        let mut out = Vec::new();
+        for index in 0..frames.count() {
+            frames.open(index).unwrap().read_to_end(&mut out).unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn scans_concatenated_frames() {
+        let mut noise = Noise(1);
+        let (first, second) = (noise.bytes(300 * 1024), vec![7; 200 * 1024]);
+        let (a, b) = (frame(&first, true), frame(&second, false));
+        let file = TempFile::new(&[a.clone(), skippable(SKIPPABLE_MAGIC + 3, b"pzstd"), b.clone()].concat());
+
+        let frames = scan_frames(&file.open()).unwrap();
+        let found: Vec<_> = frames.iter().map(|f| (f.offset, f.len, f.content_size)).collect();
+        let second_offset = (a.len() + 13) as u64;
+        assert_eq!(found, vec![
+            (0, a.len() as u64, Some(first.len() as u64)),
+            (second_offset, b.len() as u64, Some(second.len() as u64)),
+        ]);
+
+        let frames = ZstdFrames::read(file.open()).unwrap();
+        assert_eq!(frames.content_size(), (first.len() + second.len()) as u64);
+        assert!(decode_all(&frames) == [first, second].concat());
+    }
+
+    #[test]
+    fn frames_without_a_content_size_have_none() {
+        let data = Noise(2).bytes(64 * 1024);
+        let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), 3).unwrap();
+        encoder.include_contentsize(false).unwrap();
+        encoder.write_all(&data).unwrap();
+        let file = TempFile::new(&encoder.finish().unwrap());
+
+        let frames = scan_frames(&file.open()).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].content_size, None);
+    }
+
+    #[test]
+    fn rejects_damaged_frames() {
+        let mut data = frame(&Noise(3).bytes(4096), false);
+        let file = TempFile::new(&[&data[..], b"garbage!"].concat());
+        assert_eq!(scan_frames(&file.open()).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+
+        // a frame that claims to go on past the end of the file
+        data.truncate(data.len() - 10);
+        let file = TempFile::new(&data);
+        assert!(scan_frames(&file.open()).is_err());
+    }
+
+    #[test]
+    fn reads_the_seek_table() {
+        let pieces = [Noise(4).bytes(100 * 1024), vec![1; 50 * 1024], Noise(5).bytes(10)];
+        let frames: Vec<Vec<u8>> = pieces.iter().map(|piece| frame(piece, false)).collect();
+
+        for checksums in [false, true] {
+            let listed: Vec<(&[u8], usize)> = frames.iter().zip(&pieces).map(|(f, p)| (&f[..], p.len())).collect();
+            let file = TempFile::new(&[frames.concat(), seek_table(&listed, checksums)].concat());
+
+            let table = read_seek_table(&file.open()).unwrap().expect("seek table not found");
+            let found: Vec<_> = table.iter().map(|f| (f.offset, f.len, f.content_size)).collect();
+            let mut offset = 0;
+            let expected: Vec<_> = frames.iter().zip(&pieces).map(|(f, p)| {
+                offset += f.len() as u64;
+                (offset - f.len() as u64, f.len() as u64, Some(p.len() as u64))
+            }).collect();
+            assert_eq!(found, expected);
+            assert!(decode_all(&ZstdFrames::read(file.open()).unwrap()) == pieces.concat());
+        }
+    }
+
+    #[test]
+    fn ignores_seek_tables_that_do_not_add_up() {
+        let data = Noise(6).bytes(4096);
+        let frame = frame(&data, false);
+
+        // no table at all
+        let file = TempFile::new(&frame);
+        assert!(read_seek_table(&file.open()).unwrap().is_none());
+
+        // a table whose frames do not cover the file
+        let short = &frame[..frame.len() - 1];
+        let file = TempFile::new(&[&frame[..], &seek_table(&[(short, data.len())], false)].concat());
+        assert!(read_seek_table(&file.open()).unwrap().is_none());
+        // which leaves the frames to be found by scanning
+        assert_eq!(ZstdFrames::read(file.open()).unwrap().len(), 1);
+    }
+}